@@ -0,0 +1,64 @@
+use rustfft::num_complex::Complex;
+
+use crate::structs::FrequencyPans;
+
+// Ambience is estimated from how correlated left and right are. Correlation is measured across neighboring
+// frequencies, because a single frequency is always perfectly correlated with itself
+const NEIGHBORING_FREQUENCIES: usize = 4;
+
+// Steers the uncorrelated (ambient) part of each frequency to the rear. The correlated (primary) part keeps
+// the matrix's steering
+//
+// amount: 0 leaves the steering as-is, 1 sends all of the ambience to the rear
+pub fn steer_ambience(
+    amount: f64,
+    left_transformed: &[Complex<f64>],
+    right_transformed: &[Complex<f64>],
+    frequency_pans: &mut [FrequencyPans],
+) {
+    // Running sums, so that each frequency's neighbors can be summed without re-adding them
+    let mut cross_sums = Vec::with_capacity(frequency_pans.len() + 1);
+    let mut left_power_sums = Vec::with_capacity(frequency_pans.len() + 1);
+    let mut right_power_sums = Vec::with_capacity(frequency_pans.len() + 1);
+
+    let mut cross_sum = Complex { re: 0.0, im: 0.0 };
+    let mut left_power_sum = 0.0;
+    let mut right_power_sum = 0.0;
+
+    cross_sums.push(cross_sum);
+    left_power_sums.push(left_power_sum);
+    right_power_sums.push(right_power_sum);
+
+    // frequency_pans starts at the first frequency above DC
+    for freq_ctr in 1..(frequency_pans.len() + 1) {
+        let left = left_transformed[freq_ctr];
+        let right = right_transformed[freq_ctr];
+
+        cross_sum += left * right.conj();
+        left_power_sum += left.norm_sqr();
+        right_power_sum += right.norm_sqr();
+
+        cross_sums.push(cross_sum);
+        left_power_sums.push(left_power_sum);
+        right_power_sums.push(right_power_sum);
+    }
+
+    for (pan_ctr, frequency_pans) in frequency_pans.iter_mut().enumerate() {
+        let start = pan_ctr.saturating_sub(NEIGHBORING_FREQUENCIES);
+        let end = (pan_ctr + NEIGHBORING_FREQUENCIES + 1).min(cross_sums.len() - 1);
+
+        let cross = cross_sums[end] - cross_sums[start];
+        let power = (left_power_sums[end] - left_power_sums[start])
+            * (right_power_sums[end] - right_power_sums[start]);
+
+        if power <= 0.0 {
+            continue;
+        }
+
+        // 1 is fully correlated (primary), 0 is uncorrelated (ambient)
+        let coherence = (cross.norm() / power.sqrt()).min(1.0);
+        let ambience = amount * (1.0 - coherence);
+
+        frequency_pans.back_to_front += (1.0 - frequency_pans.back_to_front) * ambience;
+    }
+}
@@ -0,0 +1,135 @@
+use rustfft::num_complex::Complex;
+
+use crate::{
+    structs::{FrequencyPans, TransformedWindowAndPans},
+    upmixer::Upmixer,
+};
+
+// First-order Ambisonic B-format for a single frequency, using SN3D normalization (W is at full scale)
+// See https://en.wikipedia.org/wiki/Ambisonic_data_exchange_formats
+#[derive(Debug, Clone)]
+pub struct BFormat {
+    pub w: Complex<f64>,
+    pub x: Complex<f64>,
+    pub y: Complex<f64>,
+    pub z: Complex<f64>,
+}
+
+// A window of B-format, one transform per channel
+pub struct BFormatWindow {
+    pub w: Vec<Complex<f64>>,
+    pub x: Vec<Complex<f64>>,
+    pub y: Vec<Complex<f64>>,
+    pub z: Vec<Complex<f64>>,
+}
+
+impl BFormatWindow {
+    fn new(window_size: usize) -> BFormatWindow {
+        let silence = vec![Complex { re: 0.0, im: 0.0 }; window_size];
+
+        BFormatWindow {
+            w: silence.clone(),
+            x: silence.clone(),
+            y: silence.clone(),
+            z: silence,
+        }
+    }
+
+    fn set(&mut self, upmixer: &Upmixer, freq_ctr: usize, b_format: BFormat) {
+        self.w[freq_ctr] = b_format.w;
+        self.x[freq_ctr] = b_format.x;
+        self.y[freq_ctr] = b_format.y;
+        self.z[freq_ctr] = b_format.z;
+
+        if freq_ctr < upmixer.window_midpoint {
+            let inverse_freq_ctr = upmixer.window_size - freq_ctr;
+            self.w[inverse_freq_ctr] = b_format.w.conj();
+            self.x[inverse_freq_ctr] = b_format.x.conj();
+            self.y[inverse_freq_ctr] = b_format.y.conj();
+            self.z[inverse_freq_ctr] = b_format.z.conj();
+        }
+    }
+}
+
+// Converts a window to B-format. Matrixes that encode B-format are decoded directly, otherwise each
+// frequency is encoded in the direction that it was steered to
+pub fn to_b_format(
+    upmixer: &Upmixer,
+    transformed_window_and_pans: TransformedWindowAndPans,
+) -> BFormatWindow {
+    let left_transformed = transformed_window_and_pans
+        .left_transformed
+        .expect("Transform expected, got a placeholder instead");
+    let right_transformed = transformed_window_and_pans
+        .right_transformed
+        .expect("Transform expected, got a placeholder instead");
+
+    let mut b_format_window = BFormatWindow::new(upmixer.window_size);
+
+    for freq_ctr in 1..(upmixer.window_midpoint + 1) {
+        let left = left_transformed[freq_ctr];
+        let right = right_transformed[freq_ctr];
+
+        let b_format = match upmixer.options.matrix.decode_b_format(left, right) {
+            Some(b_format) => b_format,
+            None => encode(
+                upmixer,
+                left,
+                right,
+                &transformed_window_and_pans.frequency_pans[freq_ctr - 1],
+            ),
+        };
+
+        b_format_window.set(upmixer, freq_ctr, b_format);
+    }
+
+    b_format_window
+}
+
+// Encodes a single frequency in the direction that it was steered to
+fn encode(
+    upmixer: &Upmixer,
+    left: Complex<f64>,
+    right: Complex<f64>,
+    frequency_pans: &FrequencyPans,
+) -> BFormat {
+    let (left_amplitude, mut left_front_phase) = left.to_polar();
+    let (right_amplitude, mut right_front_phase) = right.to_polar();
+
+    let back_to_front = frequency_pans.back_to_front;
+    let front_to_back = 1.0 - back_to_front;
+
+    // The rear is phase shifted the same way as when steering to speakers. This brings out-of-phase sounds
+    // back in phase, so they don't cancel out when left and right are combined
+    let mut left_rear_phase = left_front_phase;
+    let mut right_rear_phase = right_front_phase;
+    upmixer.options.matrix.phase_shift(
+        &mut left_front_phase,
+        &mut right_front_phase,
+        &mut left_rear_phase,
+        &mut right_rear_phase,
+    );
+
+    let combined = Complex::from_polar(left_amplitude * front_to_back, left_front_phase)
+        + Complex::from_polar(right_amplitude * front_to_back, right_front_phase)
+        + Complex::from_polar(left_amplitude * back_to_front, left_rear_phase)
+        + Complex::from_polar(right_amplitude * back_to_front, right_rear_phase);
+
+    // Only the phase of the combined left and right is used, the amplitude is constant-power
+    let amplitude = (left_amplitude.powi(2) + right_amplitude.powi(2)).sqrt();
+    let phase = if combined.norm() > 0.0 {
+        combined.arg()
+    } else {
+        left_front_phase
+    };
+
+    let signal = Complex::from_polar(amplitude, phase);
+    let azimuth = frequency_pans.azimuth();
+
+    BFormat {
+        w: signal,
+        x: signal * azimuth.cos(),
+        y: signal * azimuth.sin(),
+        z: Complex { re: 0.0, im: 0.0 },
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    f64::consts::{PI, TAU},
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use sofar::reader::{Filter, OpenOptions, Sofar};
+
+use crate::options::Options;
+
+// Positions of the virtual speakers, in degrees: 0 is front, positive is left
+// 5 and 5.1 follow ITU-R BS.775, quad is a square
+const QUAD_FRONT_AZIMUTH: f64 = 45.0;
+const QUAD_REAR_AZIMUTH: f64 = 135.0;
+const SURROUND_FRONT_AZIMUTH: f64 = 30.0;
+const SURROUND_REAR_AZIMUTH: f64 = 110.0;
+
+// Spherical head model used by the built-in HRTF
+// See "A Structural Model for Binaural Sound Synthesis", Brown and Duda, 1998
+const HEAD_RADIUS: f64 = 0.0875;
+const SPEED_OF_SOUND: f64 = 343.0;
+const MINIMUM_SHADOW: f64 = 0.1;
+const MINIMUM_SHADOW_ANGLE: f64 = 5.0 * PI / 6.0;
+
+pub enum HrtfSource {
+    BuiltIn,
+    Sofa(Box<Path>),
+}
+
+// How a virtual speaker is heard by each ear, as a transform the size of the window
+struct HeadRelatedTransfer {
+    left_ear: Vec<Complex<f64>>,
+    right_ear: Vec<Complex<f64>>,
+}
+
+// Renders the virtual speakers to headphones
+pub struct BinauralRenderer {
+    front_left: HeadRelatedTransfer,
+    front_right: HeadRelatedTransfer,
+    back_left: HeadRelatedTransfer,
+    back_right: HeadRelatedTransfer,
+    front_center: Option<HeadRelatedTransfer>,
+}
+
+impl BinauralRenderer {
+    pub fn new(
+        options: &Options,
+        hrtf_source: &HrtfSource,
+        window_size: usize,
+        sample_rate: usize,
+    ) -> Result<BinauralRenderer> {
+        let (front_azimuth, rear_azimuth) = if options.channels.front_center {
+            (SURROUND_FRONT_AZIMUTH, SURROUND_REAR_AZIMUTH)
+        } else {
+            (QUAD_FRONT_AZIMUTH, QUAD_REAR_AZIMUTH)
+        };
+
+        let sofa_and_fft_forward = match hrtf_source {
+            HrtfSource::BuiltIn => None,
+            HrtfSource::Sofa(path) => {
+                let sofa = match OpenOptions::new()
+                    .sample_rate(sample_rate as f32)
+                    .open(path)
+                {
+                    Ok(sofa) => sofa,
+                    Err(error) => {
+                        let error = format!("Can not open {}: {}", path.display(), error);
+                        return Err(Error::new(ErrorKind::InvalidData, error));
+                    }
+                };
+
+                let mut planner: FftPlanner<f64> = FftPlanner::new();
+                Some((sofa, planner.plan_fft_forward(window_size)))
+            }
+        };
+
+        let create = |azimuth: f64| match &sofa_and_fft_forward {
+            None => Ok(spherical_head_transfer(
+                azimuth.to_radians(),
+                window_size,
+                sample_rate,
+            )),
+            Some((sofa, fft_forward)) => sofa_transfer(
+                sofa,
+                fft_forward.as_ref(),
+                azimuth.to_radians(),
+                window_size,
+                sample_rate,
+            ),
+        };
+
+        let front_center = if options.channels.front_center {
+            Some(create(0.0)?)
+        } else {
+            None
+        };
+
+        Ok(BinauralRenderer {
+            front_left: create(front_azimuth)?,
+            front_right: create(-front_azimuth)?,
+            back_left: create(rear_azimuth)?,
+            back_right: create(-rear_azimuth)?,
+            front_center,
+        })
+    }
+
+    // Mixes the transformed virtual speakers into the left and right ears
+    // The LFE is not rendered because the other channels keep all of their bass
+    pub fn render(
+        &self,
+        left_front: &[Complex<f64>],
+        right_front: &[Complex<f64>],
+        left_rear: &[Complex<f64>],
+        right_rear: &[Complex<f64>],
+        center: &Option<Vec<Complex<f64>>>,
+    ) -> (Vec<Complex<f64>>, Vec<Complex<f64>>) {
+        let mut speakers = vec![
+            (left_front, &self.front_left),
+            (right_front, &self.front_right),
+            (left_rear, &self.back_left),
+            (right_rear, &self.back_right),
+        ];
+
+        if let (Some(center), Some(front_center)) = (center, &self.front_center) {
+            speakers.push((center, front_center));
+        }
+
+        let window_size = left_front.len();
+        let mut left_ear = vec![Complex { re: 0.0, im: 0.0 }; window_size];
+        let mut right_ear = vec![Complex { re: 0.0, im: 0.0 }; window_size];
+
+        for (speaker, head_related_transfer) in speakers {
+            for freq_ctr in 0..window_size {
+                left_ear[freq_ctr] += speaker[freq_ctr] * head_related_transfer.left_ear[freq_ctr];
+                right_ear[freq_ctr] +=
+                    speaker[freq_ctr] * head_related_transfer.right_ear[freq_ctr];
+            }
+        }
+
+        (left_ear, right_ear)
+    }
+}
+
+fn sofa_transfer(
+    sofa: &Sofar,
+    fft_forward: &dyn Fft<f64>,
+    azimuth: f64,
+    window_size: usize,
+    sample_rate: usize,
+) -> Result<HeadRelatedTransfer> {
+    let mut filter = Filter::new(sofa.filter_len());
+    sofa.filter(azimuth.cos() as f32, azimuth.sin() as f32, 0.0, &mut filter);
+
+    Ok(HeadRelatedTransfer {
+        left_ear: impulse_response_to_transfer(
+            &filter.left,
+            filter.ldelay as f64 * sample_rate as f64,
+            window_size,
+            fft_forward,
+        )?,
+        right_ear: impulse_response_to_transfer(
+            &filter.right,
+            filter.rdelay as f64 * sample_rate as f64,
+            window_size,
+            fft_forward,
+        )?,
+    })
+}
+
+// Transforms an impulse response to the size of the window
+// Only the midpoint of each window is written, so the impulse response must fit in the first half of the window
+fn impulse_response_to_transfer(
+    impulse_response: &[f32],
+    delay: f64,
+    window_size: usize,
+    fft_forward: &dyn Fft<f64>,
+) -> Result<Vec<Complex<f64>>> {
+    let window_midpoint = window_size / 2;
+    if impulse_response.len() + (delay.ceil() as usize) >= window_midpoint {
+        let error = format!(
+            "The HRTF is {} samples long, but the window is only {} samples. Consider lowering the lowest frequency via -low",
+            impulse_response.len() + (delay.ceil() as usize),
+            window_size);
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    let mut transfer = vec![Complex { re: 0.0, im: 0.0 }; window_size];
+    for (sample_ctr, sample) in impulse_response.iter().enumerate() {
+        transfer[sample_ctr].re = *sample as f64;
+    }
+
+    fft_forward.process(&mut transfer);
+
+    // The delay is fractional, so it's applied as a phase shift
+    for (freq_ctr, value) in transfer.iter_mut().enumerate() {
+        *value *= Complex::from_polar(1.0, -TAU * signed_frequency(freq_ctr, window_size) * delay);
+    }
+
+    Ok(transfer)
+}
+
+// The spherical head model: Each ear hears a delay, and is shadowed by the head at high frequencies
+fn spherical_head_transfer(
+    azimuth: f64,
+    window_size: usize,
+    sample_rate: usize,
+) -> HeadRelatedTransfer {
+    HeadRelatedTransfer {
+        left_ear: spherical_head_ear_transfer(azimuth - (PI / 2.0), window_size, sample_rate),
+        right_ear: spherical_head_ear_transfer(azimuth + (PI / 2.0), window_size, sample_rate),
+    }
+}
+
+// angle_from_ear: The angle between the source and the ear, 0 is when the sound comes from the ear's side
+fn spherical_head_ear_transfer(
+    angle_from_ear: f64,
+    window_size: usize,
+    sample_rate: usize,
+) -> Vec<Complex<f64>> {
+    let angle_from_ear = angle_from_ear.cos().acos();
+
+    // Woodworth's formula, offset so that the delay is never negative
+    let delay_seconds = if angle_from_ear < PI / 2.0 {
+        (HEAD_RADIUS / SPEED_OF_SOUND) * (1.0 - angle_from_ear.cos())
+    } else {
+        (HEAD_RADIUS / SPEED_OF_SOUND) * (1.0 + angle_from_ear - (PI / 2.0))
+    };
+
+    let shadow = (1.0 + (MINIMUM_SHADOW / 2.0))
+        + ((1.0 - (MINIMUM_SHADOW / 2.0)) * (PI * angle_from_ear / MINIMUM_SHADOW_ANGLE).cos());
+    let head_frequency = SPEED_OF_SOUND / HEAD_RADIUS;
+
+    let sample_rate_f64 = sample_rate as f64;
+
+    (0..window_size)
+        .map(|freq_ctr| {
+            let frequency = signed_frequency(freq_ctr, window_size) * sample_rate_f64;
+            let angular_frequency = TAU * frequency;
+
+            let shadow_filter = Complex {
+                re: 1.0,
+                im: shadow * angular_frequency / (2.0 * head_frequency),
+            } / Complex {
+                re: 1.0,
+                im: angular_frequency / (2.0 * head_frequency),
+            };
+
+            shadow_filter * Complex::from_polar(1.0, -angular_frequency * delay_seconds)
+        })
+        .collect()
+}
+
+// Frequencies above the midpoint of the transform are negative, in cycles per sample
+fn signed_frequency(freq_ctr: usize, window_size: usize) -> f64 {
+    let freq_ctr = if freq_ctr > window_size / 2 {
+        freq_ctr as f64 - window_size as f64
+    } else {
+        freq_ctr as f64
+    };
+
+    freq_ctr / (window_size as f64)
+}
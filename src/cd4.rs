@@ -0,0 +1,470 @@
+use std::{
+    collections::VecDeque,
+    f64::consts::{FRAC_1_SQRT_2, PI, TAU},
+    io::{stdout, Error, ErrorKind, Read, Result, Seek, Write},
+    time::{Duration, Instant},
+};
+
+use rustfft::num_complex::Complex;
+use wave_stream::{
+    open_wav::OpenWav,
+    samples_by_channel::SamplesByChannel,
+    wave_reader::{OpenWavReader, StreamOpenWavReader},
+    wave_writer::OpenWavWriter,
+};
+
+// CD-4 (Quadradisc) is demodulated in the time domain, instead of steered
+// See https://en.wikipedia.org/wiki/CD-4
+//
+// Each groove wall carries the sum of its front and rear channels, up to 15khz. The difference between
+// the front and rear channels is frequency-modulated onto a 30khz carrier, which swings between 15khz
+// and 45khz. Thus, the recording must be captured at 96khz or higher
+const MINIMUM_SAMPLE_RATE: usize = 96000;
+const BASEBAND_CUTOFF: f64 = 15000.0;
+const CARRIER_FREQUENCY: f64 = 30000.0;
+const CARRIER_DEVIATION: f64 = 15000.0;
+
+// The carrier is separated from the sum above this frequency
+const CARRIER_CUTOFF: f64 = 18000.0;
+
+// Below 800hz the difference is phase-modulated. (The modulator applies a 6db / octave rolloff.)
+const PHASE_MODULATION_CUTOFF: f64 = 800.0;
+
+// Keeps the de-emphasis from amplifying DC. Turntable speed errors shift the carrier, which is also removed
+const PHASE_MODULATION_LEAK: f64 = 5.0;
+const DC_CUTOFF: f64 = 10.0;
+
+// When the carrier is weaker than this, the difference is muted instead of demodulating noise
+const MINIMUM_CARRIER: f64 = 0.001;
+
+// ANRS (Automatic Noise Reduction System) compresses quiet high frequencies in the difference signal,
+// similar to Dolby B. The expansion is 2:1 below the threshold, and attenuates up to 10db
+const ANRS_CROSSOVER: f64 = 1500.0;
+const ANRS_THRESHOLD_DB: f64 = -20.0;
+const ANRS_MAXIMUM_ATTENUATION_DB: f64 = -10.0;
+const ANRS_ATTACK_SECONDS: f64 = 0.002;
+const ANRS_RELEASE_SECONDS: f64 = 0.06;
+
+// Q for each stage of a 4th-order Butterworth filter
+const BUTTERWORTH_Q: [f64; 2] = [0.541196100146197, 1.306562964876376];
+
+pub fn demodulate<TReader: 'static + Read + Seek>(
+    source_wav_reader: OpenWavReader<TReader>,
+    target_open_wav_writers: Vec<OpenWavWriter>,
+) -> Result<()> {
+    let sample_rate = source_wav_reader.sample_rate() as usize;
+    if sample_rate < MINIMUM_SAMPLE_RATE {
+        let error = format!(
+            "CD-4 requires a recording captured at {} samples / second or higher, the source is {} samples / second",
+            MINIMUM_SAMPLE_RATE, sample_rate
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    let total_samples_to_write = source_wav_reader.len_samples();
+    let source_wav_reader = source_wav_reader.get_stream_f32_reader()?;
+
+    let mut target_random_access_wav_writers = Vec::with_capacity(target_open_wav_writers.len());
+    for target_open_wav_writer in target_open_wav_writers {
+        target_random_access_wav_writers
+            .push(target_open_wav_writer.get_random_access_f32_writer()?);
+    }
+
+    let max_samples_in_file = (total_samples_to_write / target_random_access_wav_writers.len()) + 1;
+
+    let mut left = GrooveWall::new(sample_rate as f64);
+    let mut right = GrooveWall::new(sample_rate as f64);
+
+    let started = Instant::now();
+    let logging_frequency = Duration::from_secs_f64(1.0 / 10.0);
+    let mut next_log = started;
+    let mut stdout = stdout();
+
+    for (sample_ctr, samples_result) in source_wav_reader.into_iter().enumerate() {
+        let samples = samples_result?;
+
+        let (left_front, left_rear) = left.demodulate(
+            samples.front_left.expect("front_left missing when reading") as f64,
+            sample_ctr,
+        );
+        let (right_front, right_rear) = right.demodulate(
+            samples
+                .front_right
+                .expect("front_right missing when reading") as f64,
+            sample_ctr,
+        );
+
+        let out_file_index = sample_ctr / max_samples_in_file;
+        let sample_ctr_in_file = sample_ctr - (max_samples_in_file * out_file_index);
+
+        target_random_access_wav_writers[out_file_index].write_samples(
+            sample_ctr_in_file,
+            SamplesByChannel::new()
+                .front_left(left_front as f32)
+                .front_right(right_front as f32)
+                .back_left(left_rear as f32)
+                .back_right(right_rear as f32),
+        )?;
+
+        let now = Instant::now();
+        if now >= next_log {
+            let elapsed_seconds = (now - started).as_secs_f64();
+            let fraction_complete = sample_ctr as f64 / total_samples_to_write as f64;
+
+            stdout.write_all(
+                format!(
+                    "\rDemodulating: {:.2}% complete, {:.0} elapsed seconds, {:.2} estimated total seconds         ",
+                    100.0 * fraction_complete,
+                    elapsed_seconds,
+                    elapsed_seconds / fraction_complete,
+                )
+                .as_bytes(),
+            )?;
+            stdout.flush()?;
+
+            next_log += logging_frequency;
+        }
+    }
+
+    for target_random_access_wav_writer in target_random_access_wav_writers.iter_mut() {
+        target_random_access_wav_writer.flush()?;
+    }
+
+    stdout.write_all(
+        format!(
+            "\rTotal time to complete: {:.0} seconds                                                             ",
+            (Instant::now() - started).as_secs_f64(),
+        )
+        .as_bytes(),
+    )?;
+    stdout.flush()?;
+
+    println!();
+
+    Ok(())
+}
+
+// A groove wall carries the front and rear for one side
+struct GrooveWall {
+    sample_rate: f64,
+
+    // Sum of front and rear. It's filtered a second time so it lines up with the difference, which is
+    // filtered twice. It's also delayed by the carrier highpass, and by the half sample between the carriers
+    // that the frequency is measured from
+    baseband_filter: [Biquad; 2],
+    sum_delay_filter: [Biquad; 2],
+    sum_delay: VecDeque<f64>,
+
+    // The carrier is shifted down to 0hz, so the difference can be measured from how fast its phase rotates
+    carrier_highpass: [Biquad; 2],
+    carrier_filter_real: [Biquad; 2],
+    carrier_filter_imaginary: [Biquad; 2],
+    previous_carrier: Complex<f64>,
+    difference_filter: [Biquad; 2],
+    dc_filter: Biquad,
+    phase_modulation_integral: f64,
+
+    anrs: Anrs,
+}
+
+impl GrooveWall {
+    fn new(sample_rate: f64) -> GrooveWall {
+        let lowpass = || {
+            [
+                Biquad::lowpass(sample_rate, BASEBAND_CUTOFF, BUTTERWORTH_Q[0]),
+                Biquad::lowpass(sample_rate, BASEBAND_CUTOFF, BUTTERWORTH_Q[1]),
+            ]
+        };
+
+        let carrier_highpass = [
+            Biquad::highpass(sample_rate, CARRIER_CUTOFF, BUTTERWORTH_Q[0]),
+            Biquad::highpass(sample_rate, CARRIER_CUTOFF, BUTTERWORTH_Q[1]),
+        ];
+        let carrier_delay: f64 = carrier_highpass
+            .iter()
+            .map(|stage| stage.group_delay(sample_rate, CARRIER_FREQUENCY))
+            .sum();
+        let sum_delay = (carrier_delay + 0.5).round() as usize;
+
+        GrooveWall {
+            sample_rate,
+            baseband_filter: lowpass(),
+            sum_delay_filter: lowpass(),
+            sum_delay: VecDeque::from(vec![0.0; sum_delay]),
+            carrier_highpass,
+            carrier_filter_real: lowpass(),
+            carrier_filter_imaginary: lowpass(),
+            previous_carrier: Complex { re: 0.0, im: 0.0 },
+            difference_filter: lowpass(),
+            dc_filter: Biquad::highpass(sample_rate, DC_CUTOFF, FRAC_1_SQRT_2),
+            phase_modulation_integral: 0.0,
+            anrs: Anrs::new(sample_rate),
+        }
+    }
+
+    // Returns (front, rear)
+    fn demodulate(&mut self, sample: f64, sample_ctr: usize) -> (f64, f64) {
+        let sum = filter(&mut self.baseband_filter, sample);
+        let sum = filter(&mut self.sum_delay_filter, sum);
+        self.sum_delay.push_back(sum);
+        let sum = self.sum_delay.pop_front().unwrap_or(sum);
+
+        // Only the fraction of a cycle is kept, so the oscillator doesn't lose precision on long recordings
+        let carrier_cycles = (CARRIER_FREQUENCY * sample_ctr as f64 / self.sample_rate).fract();
+        let carrier = filter(&mut self.carrier_highpass, sample);
+        let shifted = carrier * Complex::from_polar(1.0, -TAU * carrier_cycles);
+        let carrier = Complex {
+            re: filter(&mut self.carrier_filter_real, shifted.re),
+            im: filter(&mut self.carrier_filter_imaginary, shifted.im),
+        };
+
+        let frequency =
+            if carrier.norm() < MINIMUM_CARRIER || self.previous_carrier.norm() < MINIMUM_CARRIER {
+                0.0
+            } else {
+                (carrier * self.previous_carrier.conj()).arg() * self.sample_rate / TAU
+            };
+        self.previous_carrier = carrier;
+
+        let difference = filter(&mut self.difference_filter, frequency / CARRIER_DEVIATION);
+        let difference = self.dc_filter.process(difference);
+
+        // Undo the modulator's rolloff below 800hz
+        self.phase_modulation_integral += ((TAU * PHASE_MODULATION_CUTOFF * difference)
+            - (TAU * PHASE_MODULATION_LEAK * self.phase_modulation_integral))
+            / self.sample_rate;
+        let difference = self
+            .anrs
+            .expand(difference + self.phase_modulation_integral);
+
+        ((sum + difference) / 2.0, (sum - difference) / 2.0)
+    }
+}
+
+// Expands the high frequencies in the difference signal
+struct Anrs {
+    highpass: Biquad,
+    envelope: f64,
+    attack: f64,
+    release: f64,
+}
+
+impl Anrs {
+    fn new(sample_rate: f64) -> Anrs {
+        Anrs {
+            highpass: Biquad::highpass(sample_rate, ANRS_CROSSOVER, FRAC_1_SQRT_2),
+            envelope: 0.0,
+            attack: (-1.0 / (ANRS_ATTACK_SECONDS * sample_rate)).exp(),
+            release: (-1.0 / (ANRS_RELEASE_SECONDS * sample_rate)).exp(),
+        }
+    }
+
+    fn expand(&mut self, difference: f64) -> f64 {
+        let high = self.highpass.process(difference);
+
+        let level = high.abs();
+        let coefficient = if level > self.envelope {
+            self.attack
+        } else {
+            self.release
+        };
+        self.envelope = level + (coefficient * (self.envelope - level));
+
+        let envelope_db = 20.0 * self.envelope.max(f64::MIN_POSITIVE).log10();
+        let gain_db = (envelope_db - ANRS_THRESHOLD_DB).clamp(ANRS_MAXIMUM_ATTENUATION_DB, 0.0);
+        let gain = 10.0f64.powf(gain_db / 20.0);
+
+        difference + ((gain - 1.0) * high)
+    }
+}
+
+// See https://www.w3.org/TR/audio-eq-cookbook/
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn lowpass(sample_rate: f64, frequency: f64, q: f64) -> Biquad {
+        let w0 = TAU * frequency / sample_rate;
+        let cos_w0 = w0.cos();
+        let b1 = 1.0 - cos_w0;
+
+        Biquad::new(b1 / 2.0, b1, b1 / 2.0, w0, q)
+    }
+
+    fn highpass(sample_rate: f64, frequency: f64, q: f64) -> Biquad {
+        let w0 = TAU * frequency / sample_rate;
+        let cos_w0 = w0.cos();
+        let b1 = -(1.0 + cos_w0);
+
+        Biquad::new(-b1 / 2.0, b1, -b1 / 2.0, w0, q)
+    }
+
+    fn new(b0: f64, b1: f64, b2: f64, w0: f64, q: f64) -> Biquad {
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: (-2.0 * w0.cos()) / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    // In samples
+    fn group_delay(&self, sample_rate: f64, frequency: f64) -> f64 {
+        let phase = |w: f64| {
+            let z1 = Complex::from_polar(1.0, -w);
+            let z2 = z1 * z1;
+            ((self.b0 + (self.b1 * z1) + (self.b2 * z2)) / (1.0 + (self.a1 * z1) + (self.a2 * z2)))
+                .arg()
+        };
+
+        let w = TAU * frequency / sample_rate;
+        let dw = 1e-6;
+        let mut phase_change = phase(w + dw) - phase(w - dw);
+        if phase_change > PI {
+            phase_change -= TAU;
+        } else if phase_change < -PI {
+            phase_change += TAU;
+        }
+
+        -phase_change / (2.0 * dw)
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = (self.b0 * x) + (self.b1 * self.x1) + (self.b2 * self.x2)
+            - (self.a1 * self.y1)
+            - (self.a2 * self.y2);
+
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+
+        y
+    }
+}
+
+fn filter(stages: &mut [Biquad; 2], sample: f64) -> f64 {
+    stages
+        .iter_mut()
+        .fold(sample, |sample, stage| stage.process(sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 192000.0;
+    const CARRIER_AMPLITUDE: f64 = 0.1;
+
+    const FRONT_FREQUENCY: f64 = 1000.0;
+    const REAR_FREQUENCY: f64 = 3000.0;
+    const AMPLITUDE: f64 = 0.3;
+
+    // Cuts a groove wall: The sum is recorded directly, and the difference is frequency-modulated onto the carrier.
+    // The modulator's rolloff below 800hz is the inverse of what GrooveWall undoes
+    fn modulate(fronts: &[f64], rears: &[f64]) -> Vec<f64> {
+        let mut phase_modulation_integral = 0.0;
+        let mut carrier_cycles = 0.0;
+        let mut previous_frequency = CARRIER_FREQUENCY;
+
+        fronts
+            .iter()
+            .zip(rears)
+            .map(|(front, rear)| {
+                let difference = front - rear;
+
+                let modulation = (difference
+                    - (phase_modulation_integral
+                        * (1.0 - (TAU * PHASE_MODULATION_LEAK / SAMPLE_RATE))))
+                    / (1.0 + (TAU * PHASE_MODULATION_CUTOFF / SAMPLE_RATE));
+                phase_modulation_integral += ((TAU * PHASE_MODULATION_CUTOFF * modulation)
+                    - (TAU * PHASE_MODULATION_LEAK * phase_modulation_integral))
+                    / SAMPLE_RATE;
+
+                // Like a cutter, the carrier's phase is continuous between samples
+                let frequency = CARRIER_FREQUENCY + (CARRIER_DEVIATION * modulation);
+                carrier_cycles += (previous_frequency + frequency) / (2.0 * SAMPLE_RATE);
+                previous_frequency = frequency;
+
+                front + rear + (CARRIER_AMPLITUDE * (TAU * carrier_cycles).cos())
+            })
+            .collect()
+    }
+
+    fn tone(frequency: f64, len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|sample_ctr| AMPLITUDE * (TAU * frequency * sample_ctr as f64 / SAMPLE_RATE).sin())
+            .collect()
+    }
+
+    // The amplitude of a frequency, which must complete whole cycles in the samples
+    fn amplitude(samples: &[f64], frequency: f64) -> f64 {
+        let sum: Complex<f64> = samples
+            .iter()
+            .enumerate()
+            .map(|(sample_ctr, sample)| {
+                Complex::from_polar(*sample, -TAU * frequency * sample_ctr as f64 / SAMPLE_RATE)
+            })
+            .sum();
+
+        2.0 * sum.norm() / samples.len() as f64
+    }
+
+    #[test]
+    fn groove_wall_separates_front_and_rear() {
+        let len = (SAMPLE_RATE / 2.0) as usize;
+        let groove = modulate(&tone(FRONT_FREQUENCY, len), &tone(REAR_FREQUENCY, len));
+
+        let mut groove_wall = GrooveWall::new(SAMPLE_RATE);
+        let (fronts, rears): (Vec<f64>, Vec<f64>) = groove
+            .iter()
+            .enumerate()
+            .map(|(sample_ctr, sample)| groove_wall.demodulate(*sample, sample_ctr))
+            .unzip();
+
+        // The filters settle, then 100ms is measured
+        let measured = (0.3 * SAMPLE_RATE) as usize..(0.4 * SAMPLE_RATE) as usize;
+        let fronts = &fronts[measured.clone()];
+        let rears = &rears[measured];
+
+        for (name, samples, wanted, unwanted) in [
+            ("front", fronts, FRONT_FREQUENCY, REAR_FREQUENCY),
+            ("rear", rears, REAR_FREQUENCY, FRONT_FREQUENCY),
+        ] {
+            let wanted_amplitude = amplitude(samples, wanted);
+            let unwanted_amplitude = amplitude(samples, unwanted);
+            let separation_db = 20.0 * (wanted_amplitude / unwanted_amplitude).log10();
+
+            assert!(
+                (wanted_amplitude / AMPLITUDE - 1.0).abs() < 0.1,
+                "{}: amplitude is {}, expected {}",
+                name,
+                wanted_amplitude,
+                AMPLITUDE
+            );
+            assert!(
+                separation_db > 25.0,
+                "{}: separation is {}db",
+                name,
+                separation_db
+            );
+        }
+    }
+}
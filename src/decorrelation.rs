@@ -0,0 +1,97 @@
+use std::f64::consts::{PI, TAU};
+
+use rustfft::num_complex::Complex;
+
+use crate::options::Options;
+
+// The random phases are fixed, so that upmixing the same file twice gives the same result
+const LEFT_REAR_SEED: u64 = 0x5eed_0001;
+const RIGHT_REAR_SEED: u64 = 0x5eed_0002;
+
+// The random phase is smoothed across this bandwidth, so that it doesn't smear transients
+const PHASE_BANDWIDTH: f64 = 50.0;
+
+// All-pass filters for the rear channels: Each frequency is delayed and has its phase randomized, so the rear
+// channels aren't correlated with the front channels
+pub struct RearFilters {
+    left_rear: Vec<Complex<f64>>,
+    right_rear: Vec<Complex<f64>>,
+}
+
+impl RearFilters {
+    pub fn new(options: &Options, window_size: usize, sample_rate: usize) -> Option<RearFilters> {
+        if options.decorrelation == 0.0 && options.rear_delay == 0.0 {
+            return None;
+        }
+
+        let delay = rear_delay_samples(options, sample_rate);
+
+        let smoothing =
+            ((PHASE_BANDWIDTH * window_size as f64 / sample_rate as f64) as usize).max(1);
+
+        let create = |seed: u64| {
+            let phases = random_phases(seed, window_size / 2, smoothing);
+
+            let mut filter = vec![Complex { re: 1.0, im: 0.0 }; window_size];
+            for freq_ctr in 1..(window_size / 2 + 1) {
+                let delay_phase = -TAU * freq_ctr as f64 * delay / window_size as f64;
+                let phase = delay_phase + (options.decorrelation * phases[freq_ctr - 1]);
+                filter[freq_ctr] = Complex::from_polar(1.0, phase);
+
+                if freq_ctr < window_size / 2 {
+                    filter[window_size - freq_ctr] = filter[freq_ctr].conj();
+                }
+            }
+
+            filter
+        };
+
+        Some(RearFilters {
+            left_rear: create(LEFT_REAR_SEED),
+            right_rear: create(RIGHT_REAR_SEED),
+        })
+    }
+
+    pub fn apply(&self, left_rear: &mut [Complex<f64>], right_rear: &mut [Complex<f64>]) {
+        for (value, filter) in left_rear.iter_mut().zip(self.left_rear.iter()) {
+            *value *= filter;
+        }
+
+        for (value, filter) in right_rear.iter_mut().zip(self.right_rear.iter()) {
+            *value *= filter;
+        }
+    }
+}
+
+pub fn rear_delay_samples(options: &Options, sample_rate: usize) -> f64 {
+    options.rear_delay * sample_rate as f64 / 1000.0
+}
+
+// Random phases between -pi and pi, smoothed with a moving average
+fn random_phases(seed: u64, len: usize, smoothing: usize) -> Vec<f64> {
+    let mut state = seed;
+    let random: Vec<f64> = (0..(len + smoothing))
+        .map(|_| (split_mix_64(&mut state) as f64 / u64::MAX as f64) * 2.0 - 1.0)
+        .collect();
+
+    // Averaging lowers the range, so the result is scaled back up
+    let scale = (smoothing as f64).sqrt();
+
+    random
+        .windows(smoothing)
+        .take(len)
+        .map(|window| {
+            let average = window.iter().sum::<f64>() / smoothing as f64;
+            (average * scale).clamp(-1.0, 1.0) * PI
+        })
+        .collect()
+}
+
+// See https://prng.di.unimi.it/splitmix64.c
+fn split_mix_64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,110 @@
+use std::f64::consts::PI;
+
+use rustfft::num_complex::Complex;
+
+// The speech band, with smooth edges: Fades in between LOW_START and LOW_FULL, fades out between HIGH_FULL and HIGH_END
+const LOW_START: f64 = 100.0;
+const LOW_FULL: f64 = 200.0;
+const HIGH_FULL: f64 = 4000.0;
+const HIGH_END: f64 = 8000.0;
+
+// Harmonics are compared to the average of the surrounding frequencies in this bandwidth
+const NEIGHBORHOOD_BANDWIDTH: f64 = 200.0;
+
+// Finds how much of each center-panned frequency is dialog: Voices are in the speech band, and are harmonic, so their
+// spectrum is peaky instead of flat. Music beds and noise stay in the front left and right
+pub struct DialogDetector {
+    // The speech band, indexed by freq_ctr
+    band_levels: Vec<f64>,
+    // How many frequencies on each side are used to find harmonic peaks
+    neighborhood: usize,
+}
+
+impl DialogDetector {
+    pub fn new(window_size: usize, sample_rate: usize) -> DialogDetector {
+        let window_midpoint = window_size / 2;
+
+        let band_levels = (0..(window_midpoint + 1))
+            .map(|freq_ctr| band_level(freq_ctr as f64 * sample_rate as f64 / window_size as f64))
+            .collect();
+
+        let neighborhood =
+            ((NEIGHBORHOOD_BANDWIDTH * window_size as f64 / sample_rate as f64) as usize / 2)
+                .max(1);
+
+        DialogDetector {
+            band_levels,
+            neighborhood,
+        }
+    }
+
+    // Returns how much of each frequency to keep in the center, between 0 and 1, indexed by freq_ctr
+    pub fn center_levels(&self, mono_transformed: &[Complex<f64>]) -> Vec<f64> {
+        let powers: Vec<f64> = (0..self.band_levels.len())
+            .map(|freq_ctr| mono_transformed[freq_ctr].norm_sqr() + f64::MIN_POSITIVE)
+            .collect();
+
+        // Spectral flatness in the speech band: 1 is noise-like, 0 is a few pure tones
+        let mut band_weight = 0.0;
+        let mut log_sum = 0.0;
+        let mut sum = 0.0;
+        for (power, band_level) in powers.iter().zip(self.band_levels.iter()) {
+            if *band_level > 0.0 {
+                band_weight += band_level;
+                log_sum += band_level * power.ln();
+                sum += band_level * power;
+            }
+        }
+
+        if band_weight == 0.0 {
+            return vec![0.0; self.band_levels.len()];
+        }
+
+        let geometric_mean = (log_sum / band_weight).exp();
+        let arithmetic_mean = sum / band_weight;
+        let tonality = (1.0 - (geometric_mean / arithmetic_mean)).clamp(0.0, 1.0);
+
+        // Prefix sums to find the average power around each frequency
+        let mut prefix_sums = Vec::with_capacity(powers.len() + 1);
+        prefix_sums.push(0.0);
+        for power in powers.iter() {
+            prefix_sums.push(prefix_sums[prefix_sums.len() - 1] + power);
+        }
+
+        (0..self.band_levels.len())
+            .map(|freq_ctr| {
+                let band_level = self.band_levels[freq_ctr];
+                if band_level == 0.0 {
+                    return 0.0;
+                }
+
+                let start = freq_ctr.saturating_sub(self.neighborhood);
+                let end = (freq_ctr + self.neighborhood + 1).min(powers.len());
+                let neighborhood_average =
+                    (prefix_sums[end] - prefix_sums[start]) / (end - start) as f64;
+
+                // 0.5 for an average frequency, approaches 1 at a harmonic peak
+                let prominence = powers[freq_ctr] / (powers[freq_ctr] + neighborhood_average);
+
+                band_level * (tonality * prominence * 2.0).min(1.0)
+            })
+            .collect()
+    }
+}
+
+fn band_level(frequency: f64) -> f64 {
+    if frequency <= LOW_START || frequency >= HIGH_END {
+        0.0
+    } else if frequency < LOW_FULL {
+        raised_cosine((frequency - LOW_START) / (LOW_FULL - LOW_START))
+    } else if frequency <= HIGH_FULL {
+        1.0
+    } else {
+        raised_cosine((HIGH_END - frequency) / (HIGH_END - HIGH_FULL))
+    }
+}
+
+// 0 at 0, 1 at 1, with a smooth transition
+fn raised_cosine(fraction: f64) -> f64 {
+    (1.0 - (fraction * PI).cos()) / 2.0
+}
@@ -0,0 +1,552 @@
+use std::{
+    collections::VecDeque,
+    f64::consts::{FRAC_1_SQRT_2, SQRT_2, TAU},
+    io::Result,
+    path::PathBuf,
+};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use wave_stream::{
+    open_wav::OpenWav, read_wav_from_file_path, samples_by_channel::SamplesByChannel,
+    wave_reader::StreamOpenWavReader,
+};
+
+use crate::{
+    input_conditioning::InputConditioner,
+    matrix::Matrix,
+    options::{db_to_amplitude, ChannelAdjustment, DownmixMethod, InputConditioning, Options},
+};
+
+// Each block is transformed to compare the downmix with the source per frequency band
+const BLOCK_SIZE: usize = 8192;
+
+// The upmix is written in line with the source, so the alignment should be 0. It's still checked, so that an offset is
+// reported instead of showing up as a residual. Only the first part of the file is used to find the alignment
+const MAXIMUM_ALIGNMENT: usize = 16;
+const ALIGNMENT_SECONDS: usize = 10;
+
+// Center frequencies of the octave bands that are reported
+const BANDS: [f64; 10] = [
+    31.5, 63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+// Bands that are this much quieter than the whole source are too quiet to compare
+const SILENT_BAND_DB: f64 = -60.0;
+
+// Front left, front right, center, LFE, rear left, rear right. (The same order as channel_adjustments)
+const NUM_CHANNELS: usize = 6;
+
+// Folds the upmix back down to stereo and compares it with the source
+pub struct DownmixVerifier {
+    source_wav_path: PathBuf,
+    input_conditioning: InputConditioning,
+    method: DownmixMethod,
+    threshold: f64,
+
+    // How much of each channel goes into the left and right of the downmix
+    left_coefficients: [Complex<f64>; NUM_CHANNELS],
+    right_coefficients: [Complex<f64>; NUM_CHANNELS],
+
+    // The trims, inverts, and delays that were applied to each channel of the upmix, so they can be undone
+    channel_adjustments: [ChannelAdjustment; NUM_CHANNELS],
+}
+
+impl DownmixVerifier {
+    pub fn new(options: &Options) -> Option<DownmixVerifier> {
+        let method = options.verify_downmix?;
+
+        let (left_coefficients, right_coefficients) = fold_coefficients(
+            method,
+            options.matrix.as_ref(),
+            options.loud,
+            options.bass_management,
+        );
+
+        Some(DownmixVerifier {
+            source_wav_path: options.source_wav_path.to_path_buf(),
+            input_conditioning: options.input_conditioning,
+            method,
+            threshold: options.downmix_threshold,
+            left_coefficients,
+            right_coefficients,
+            channel_adjustments: options.channel_adjustments,
+        })
+    }
+
+    // Prints the residual per band. Returns false if the residual is above the threshold. The gain is undone, so
+    // normalizing the upmix doesn't count as a difference
+    pub fn verify(&self, target_wav_paths: &[PathBuf], gain: f64) -> Result<bool> {
+        println!();
+        println!(
+            "Verifying the downmix ({}) against {}",
+            match self.method {
+                DownmixMethod::Itu => "itu",
+                DownmixMethod::Matrix => "matrix",
+            },
+            self.source_wav_path.display()
+        );
+
+        let sample_rate = read_wav_from_file_path(&self.source_wav_path)?.sample_rate() as usize;
+
+        let alignment = self.find_alignment(target_wav_paths, sample_rate)?;
+        if alignment > 0 {
+            println!("\tThe upmix is {} sample(s) late, aligning", alignment);
+        } else if alignment < 0 {
+            println!("\tThe upmix is {} sample(s) early, aligning", -alignment);
+        }
+
+        let mut planner: FftPlanner<f64> = FftPlanner::new();
+        let fft_forward = planner.plan_fft_forward(BLOCK_SIZE);
+        let mut scratch = vec![zero(); fft_forward.get_inplace_scratch_len()];
+
+        // Hann window, so the edges of each block don't leak into other bands
+        let window: Vec<f64> = (0..BLOCK_SIZE)
+            .map(|sample_ctr| 0.5 - (0.5 * (TAU * sample_ctr as f64 / BLOCK_SIZE as f64).cos()))
+            .collect();
+
+        let band_per_frequency: Vec<Option<usize>> = (0..(BLOCK_SIZE / 2 + 1))
+            .map(|freq_ctr| band(freq_ctr as f64 * sample_rate as f64 / BLOCK_SIZE as f64))
+            .collect();
+
+        let mut source_powers = [0.0f64; BANDS.len()];
+        let mut residual_powers = [0.0f64; BANDS.len()];
+
+        let mut source_samples = self.open_source(sample_rate)?;
+        let mut target_samples = self.open_targets(target_wav_paths, sample_rate)?;
+
+        // A positive alignment means that the upmix is late
+        for _ in 0..alignment.max(0) {
+            target_samples.next();
+        }
+        for _ in 0..(-alignment).max(0) {
+            source_samples.next();
+        }
+
+        let mut source_blocks = [vec![zero(); BLOCK_SIZE], vec![zero(); BLOCK_SIZE]];
+        let mut target_blocks: Vec<Vec<Complex<f64>>> = (0..NUM_CHANNELS)
+            .map(|_| vec![zero(); BLOCK_SIZE])
+            .collect();
+
+        'each_block: loop {
+            for sample_ctr in 0..BLOCK_SIZE {
+                let (source, target) = match (source_samples.next(), target_samples.next()) {
+                    (Some(source), Some(target)) => (source?, target?),
+                    _ => break 'each_block,
+                };
+
+                for channel_ctr in 0..2 {
+                    source_blocks[channel_ctr][sample_ctr] =
+                        real(source[channel_ctr] * window[sample_ctr]);
+                }
+
+                for (target_block, target) in target_blocks.iter_mut().zip(target) {
+                    target_block[sample_ctr] = real(target * window[sample_ctr] / gain);
+                }
+            }
+
+            for block in source_blocks.iter_mut().chain(target_blocks.iter_mut()) {
+                fft_forward.process_with_scratch(block, &mut scratch);
+            }
+
+            // Only positive frequencies are compared. The negative frequencies are a mirror image
+            for freq_ctr in 1..(BLOCK_SIZE / 2 + 1) {
+                let band_ctr = match band_per_frequency[freq_ctr] {
+                    Some(band_ctr) => band_ctr,
+                    None => continue,
+                };
+
+                let mut left = zero();
+                let mut right = zero();
+                for (channel_ctr, target_block) in target_blocks.iter().enumerate() {
+                    left += target_block[freq_ctr] * self.left_coefficients[channel_ctr];
+                    right += target_block[freq_ctr] * self.right_coefficients[channel_ctr];
+                }
+
+                source_powers[band_ctr] +=
+                    source_blocks[0][freq_ctr].norm_sqr() + source_blocks[1][freq_ctr].norm_sqr();
+                residual_powers[band_ctr] += (left - source_blocks[0][freq_ctr]).norm_sqr()
+                    + (right - source_blocks[1][freq_ctr]).norm_sqr();
+            }
+        }
+
+        let total_source_power: f64 = source_powers.iter().sum();
+        let total_residual_power: f64 = residual_powers.iter().sum();
+
+        if total_source_power == 0.0 {
+            println!("\tThe source is silent, there is nothing to compare");
+            return Ok(true);
+        }
+
+        let mut worst_residual = f64::NEG_INFINITY;
+        for band_ctr in 0..BANDS.len() {
+            let source_db = power_to_db(source_powers[band_ctr] / total_source_power);
+            if source_db < SILENT_BAND_DB {
+                continue;
+            }
+
+            let residual = power_to_db(residual_powers[band_ctr] / source_powers[band_ctr]);
+            worst_residual = worst_residual.max(residual);
+
+            println!("\t{:>7}hz: {:>7.1}db", BANDS[band_ctr], residual);
+        }
+
+        println!(
+            "\t  Overall: {:>7.1}db",
+            power_to_db(total_residual_power / total_source_power)
+        );
+
+        if worst_residual > self.threshold {
+            println!(
+                "The downmix residual, {:.1}db, is above the threshold of {}db",
+                worst_residual, self.threshold
+            );
+            return Ok(false);
+        }
+
+        println!(
+            "The downmix residual is below the threshold of {}db",
+            self.threshold
+        );
+        Ok(true)
+    }
+
+    // Reads the source, conditioned the same way as when it was upmixed
+    fn open_source(&self, sample_rate: usize) -> Result<Samples> {
+        let reader = read_wav_from_file_path(&self.source_wav_path)?.get_stream_f32_reader()?;
+        let mut input_conditioner = InputConditioner::new(&self.input_conditioning, sample_rate);
+        Ok(Box::new(reader.into_iter().map(move |samples| {
+            let samples = samples?;
+            let (left, right) = input_conditioner.condition(
+                samples.front_left.unwrap_or(0.0) as f64,
+                samples.front_right.unwrap_or(0.0) as f64,
+            );
+            Ok([left, right, 0.0, 0.0, 0.0, 0.0])
+        })))
+    }
+
+    // Reads the upmix, with each channel's trim, invert, and delay undone
+    fn open_targets(&self, target_wav_paths: &[PathBuf], sample_rate: usize) -> Result<Samples> {
+        let mut samples = open_targets(target_wav_paths)?;
+
+        let gains = self.channel_adjustments.map(|channel_adjustment| {
+            let gain = db_to_amplitude(channel_adjustment.trim as f32) as f64;
+            if channel_adjustment.invert {
+                -gain
+            } else {
+                gain
+            }
+        });
+        let delays = self.channel_adjustments.map(|channel_adjustment| {
+            (channel_adjustment.delay * sample_rate as f64 / 1000.0).round() as usize
+        });
+        let maximum_delay = delays.iter().copied().max().unwrap_or(0);
+
+        // A delayed channel is read ahead of the other channels. The file ends when the most delayed channel ends
+        let mut frames = VecDeque::with_capacity(maximum_delay + 1);
+        Ok(Box::new(std::iter::from_fn(move || {
+            while frames.len() <= maximum_delay {
+                match samples.next()? {
+                    Ok(frame) => frames.push_back(frame),
+                    Err(err) => return Some(Err(err)),
+                }
+            }
+
+            let mut frame = [0.0; NUM_CHANNELS];
+            for channel_ctr in 0..NUM_CHANNELS {
+                frame[channel_ctr] = frames[delays[channel_ctr]][channel_ctr] / gains[channel_ctr];
+            }
+
+            frames.pop_front();
+            Some(Ok(frame))
+        })))
+    }
+
+    // Finds how many samples the upmix is offset from the source by correlating the downmix with the source
+    fn find_alignment(&self, target_wav_paths: &[PathBuf], sample_rate: usize) -> Result<isize> {
+        let len = ALIGNMENT_SECONDS * sample_rate;
+
+        let mut sources = Vec::with_capacity(len);
+        for source in self.open_source(sample_rate)?.take(len) {
+            let source = source?;
+            sources.push(source[0] + source[1]);
+        }
+
+        // Phase shifts can't be undone in the time domain, so only the channels that aren't shifted are used
+        let mut targets = Vec::with_capacity(len);
+        for target in self.open_targets(target_wav_paths, sample_rate)?.take(len) {
+            let target = target?;
+            let mono: f64 = target
+                .iter()
+                .enumerate()
+                .map(|(channel_ctr, sample)| {
+                    sample
+                        * (self.left_coefficients[channel_ctr].re.max(0.0)
+                            + self.right_coefficients[channel_ctr].re.max(0.0))
+                })
+                .sum();
+            targets.push(mono);
+        }
+
+        let len = sources.len().min(targets.len());
+        if len <= 2 * MAXIMUM_ALIGNMENT {
+            return Ok(0);
+        }
+
+        let mut best_alignment = 0;
+        let mut best_correlation = f64::NEG_INFINITY;
+        for alignment in -(MAXIMUM_ALIGNMENT as isize)..(MAXIMUM_ALIGNMENT as isize + 1) {
+            let correlation: f64 = (MAXIMUM_ALIGNMENT..(len - MAXIMUM_ALIGNMENT))
+                .map(|sample_ctr| {
+                    sources[sample_ctr] * targets[(sample_ctr as isize + alignment) as usize]
+                })
+                .sum();
+
+            if correlation > best_correlation {
+                best_correlation = correlation;
+                best_alignment = alignment;
+            }
+        }
+
+        Ok(best_alignment)
+    }
+}
+
+// How much of each channel goes into the left and right of the downmix
+fn fold_coefficients(
+    method: DownmixMethod,
+    matrix: &dyn Matrix,
+    loud: bool,
+    bass_management: bool,
+) -> ([Complex<f64>; NUM_CHANNELS], [Complex<f64>; NUM_CHANNELS]) {
+    // With bass management, the bass below the crossover is moved from the other channels to the LFE, so the LFE is
+    // added back. (The LFE is (left + right) / 2, so stereo bass comes back as mono.) Otherwise the LFE is a copy of
+    // the bass, and is dropped
+    let lfe = if bass_management { one() } else { zero() };
+
+    match method {
+        // ITU-R BS.775: The center and rears are lowered by 3db
+        DownmixMethod::Itu => (
+            [
+                one(),
+                zero(),
+                real(FRAC_1_SQRT_2),
+                lfe,
+                real(FRAC_1_SQRT_2),
+                zero(),
+            ],
+            [
+                zero(),
+                one(),
+                real(FRAC_1_SQRT_2),
+                lfe,
+                zero(),
+                real(FRAC_1_SQRT_2),
+            ],
+        ),
+        // Encodes the upmix with the selected matrix
+        DownmixMethod::Matrix => {
+            // Undoes the amplitude adjustment that the panner applies
+            let amplitude_adjustment = matrix.amplitude_adjustment();
+            let gain = if matrix.steer_right_left() {
+                if loud {
+                    1.0
+                } else {
+                    1.0 / amplitude_adjustment
+                }
+            } else if loud {
+                amplitude_adjustment
+            } else {
+                1.0
+            };
+
+            let [left_front, right_front, left_rear, right_rear] = matrix.encoding();
+            (
+                [
+                    left_front.0 * gain,
+                    right_front.0 * gain,
+                    real(FRAC_1_SQRT_2),
+                    lfe,
+                    left_rear.0 * gain,
+                    right_rear.0 * gain,
+                ],
+                [
+                    left_front.1 * gain,
+                    right_front.1 * gain,
+                    real(FRAC_1_SQRT_2),
+                    lfe,
+                    left_rear.1 * gain,
+                    right_rear.1 * gain,
+                ],
+            )
+        }
+    }
+}
+
+type Samples = Box<dyn Iterator<Item = Result<[f64; NUM_CHANNELS]>>>;
+
+// Reads all of the target files, in order, as one stream
+fn open_targets(paths: &[PathBuf]) -> Result<Samples> {
+    let mut samples: Samples = Box::new(std::iter::empty());
+    for path in paths {
+        let reader = read_wav_from_file_path(path)?.get_stream_f32_reader()?;
+        let next: Samples = Box::new(reader.into_iter().map(|samples| Ok(channels(&samples?))));
+        samples = Box::new(samples.chain(next));
+    }
+
+    Ok(samples)
+}
+
+fn channels(samples: &SamplesByChannel<f32>) -> [f64; NUM_CHANNELS] {
+    [
+        samples.front_left.unwrap_or(0.0) as f64,
+        samples.front_right.unwrap_or(0.0) as f64,
+        samples.front_center.unwrap_or(0.0) as f64,
+        samples.low_frequency.unwrap_or(0.0) as f64,
+        samples.back_left.unwrap_or(0.0) as f64,
+        samples.back_right.unwrap_or(0.0) as f64,
+    ]
+}
+
+// The octave band that contains the frequency
+fn band(frequency: f64) -> Option<usize> {
+    BANDS
+        .iter()
+        .position(|center| frequency >= center * FRAC_1_SQRT_2 && frequency < center * SQRT_2)
+}
+
+fn power_to_db(power: f64) -> f64 {
+    if power > 0.0 {
+        10.0 * power.log10()
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+fn zero() -> Complex<f64> {
+    Complex { re: 0.0, im: 0.0 }
+}
+
+fn one() -> Complex<f64> {
+    Complex { re: 1.0, im: 0.0 }
+}
+
+fn real(re: f64) -> Complex<f64> {
+    Complex { re, im: 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matrix::{CoefficientMatrix, DefaultMatrix};
+
+    use super::*;
+
+    const TOLERANCE: f64 = 0.01;
+
+    fn fold(
+        (left_coefficients, right_coefficients): &(
+            [Complex<f64>; NUM_CHANNELS],
+            [Complex<f64>; NUM_CHANNELS],
+        ),
+        frame: [f64; NUM_CHANNELS],
+    ) -> (Complex<f64>, Complex<f64>) {
+        let mut left = zero();
+        let mut right = zero();
+        for channel_ctr in 0..NUM_CHANNELS {
+            left += left_coefficients[channel_ctr] * frame[channel_ctr];
+            right += right_coefficients[channel_ctr] * frame[channel_ctr];
+        }
+
+        (left, right)
+    }
+
+    fn assert_near(name: &str, actual: Complex<f64>, expected: f64) {
+        assert!(
+            (actual - real(expected)).norm() < 1e-12,
+            "{} is {}, expected {}",
+            name,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn itu_folds_center_and_rears_at_minus_3_db() {
+        // Front left, front right, center, LFE, rear left, rear right
+        let frame = [0.5, -0.25, 0.4, 0.9, 0.2, -0.1];
+
+        let (left, right) = fold(
+            &fold_coefficients(DownmixMethod::Itu, &DefaultMatrix::new(), false, false),
+            frame,
+        );
+        assert_near("left", left, 0.5 + (0.4 + 0.2) * FRAC_1_SQRT_2);
+        assert_near("right", right, -0.25 + (0.4 - 0.1) * FRAC_1_SQRT_2);
+
+        // With bass management, the LFE has bass that was removed from the other channels
+        let (left, right) = fold(
+            &fold_coefficients(DownmixMethod::Itu, &DefaultMatrix::new(), false, true),
+            frame,
+        );
+        assert_near("left", left, 0.5 + 0.9 + (0.4 + 0.2) * FRAC_1_SQRT_2);
+        assert_near("right", right, -0.25 + 0.9 + (0.4 - 0.1) * FRAC_1_SQRT_2);
+    }
+
+    // Folds a sound that is only in one speaker, and checks that the matrix steers it back to that speaker
+    fn assert_folds_back_to_speakers(name: &str, matrix: &dyn Matrix) {
+        let coefficients = fold_coefficients(DownmixMethod::Matrix, matrix, false, false);
+
+        // (channel_ctr, left_to_right, back_to_front)
+        for (channel_ctr, left_to_right, back_to_front) in
+            [(0, -1.0, 0.0), (1, 1.0, 0.0), (4, -1.0, 1.0), (5, 1.0, 1.0)]
+        {
+            let mut frame = [0.0; NUM_CHANNELS];
+            frame[channel_ctr] = 1.0;
+
+            let (left, right) = fold(&coefficients, frame);
+            let (left_amplitude, left_phase) = left.to_polar();
+            let (right_amplitude, right_phase) = right.to_polar();
+            let frequency_pans =
+                matrix.steer(left_amplitude, left_phase, right_amplitude, right_phase);
+
+            assert!(
+                (frequency_pans.left_to_right - left_to_right).abs() < TOLERANCE,
+                "{} channel {}: left_to_right is {}, expected {}",
+                name,
+                channel_ctr,
+                frequency_pans.left_to_right,
+                left_to_right
+            );
+            assert!(
+                (frequency_pans.back_to_front - back_to_front).abs() < TOLERANCE,
+                "{} channel {}: back_to_front is {}, expected {}",
+                name,
+                channel_ctr,
+                frequency_pans.back_to_front,
+                back_to_front
+            );
+        }
+
+        // The center is folded equally into left and right, in phase
+        let mut frame = [0.0; NUM_CHANNELS];
+        frame[2] = 1.0;
+        let (left, right) = fold(&coefficients, frame);
+        assert_near("center in left", left, FRAC_1_SQRT_2);
+        assert_near("center in right", right, FRAC_1_SQRT_2);
+
+        // The LFE is dropped without bass management
+        let mut frame = [0.0; NUM_CHANNELS];
+        frame[3] = 1.0;
+        let (left, right) = fold(&coefficients, frame);
+        assert_near("LFE in left", left, 0.0);
+        assert_near("LFE in right", right, 0.0);
+    }
+
+    #[test]
+    fn ev4_fold_steers_back_to_each_speaker() {
+        assert_folds_back_to_speakers("ev4", &CoefficientMatrix::ev4());
+    }
+
+    #[test]
+    fn dynaquad_fold_steers_back_to_each_speaker() {
+        assert_folds_back_to_speakers("dynaquad", &CoefficientMatrix::dynaquad());
+    }
+}
@@ -0,0 +1,619 @@
+use std::{
+    collections::VecDeque,
+    f64::consts::PI,
+    fs::remove_file,
+    io::Result,
+    path::{Path, PathBuf},
+};
+
+use wave_stream::{
+    open_wav::OpenWav, read_wav_from_file_path, samples_by_channel::SamplesByChannel,
+    wave_header::WavHeader, wave_reader::StreamOpenWavReader, write_wav_to_file_path,
+};
+
+use crate::{
+    loudness::{Loudness, LoudnessMeter},
+    options::{amplitude_to_db, db_to_amplitude, Options},
+};
+
+// Peaks between samples are found by oversampling 4x, see ITU-R BS.1770
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+// The limiter looks ahead so it can lower the gain before a peak, and then slowly releases
+const LOOK_AHEAD_SECONDS: f64 = 0.0015;
+const RELEASE_SECONDS: f64 = 0.05;
+
+// Once the release is this close to no gain reduction, (about 0.01db,) it stops
+const RELEASED: f64 = 0.999;
+
+// The most channels that are written: Front left, front right, center, LFE, rear left, rear right
+pub const MAX_CHANNELS: usize = 6;
+
+// Where the upmix is written before it's normalized and limited
+pub fn unfinished_path(target_path: &Path) -> PathBuf {
+    target_path.with_extension("unfinished.wav")
+}
+
+// When the upmix is normalized or limited, it's first written to an unfinished file
+pub fn rewrites_upmix(options: &Options) -> bool {
+    options.headroom_auto || options.limit || options.normalize_lufs.is_some()
+}
+
+// Normalizes, limits, and measures the loudness of the upmix after it's written
+pub struct Finisher {
+    source_wav_path: PathBuf,
+    rewrites_upmix: bool,
+    headroom_auto: bool,
+    normalize_lufs: Option<f64>,
+    limit: bool,
+    true_peak: f64,
+    measure_loudness: bool,
+}
+
+impl Finisher {
+    pub fn new(options: &Options) -> Option<Finisher> {
+        let measure_loudness = options.measure_loudness || options.normalize_lufs.is_some();
+        if !(rewrites_upmix(options) || measure_loudness) {
+            return None;
+        }
+
+        Some(Finisher {
+            source_wav_path: options.source_wav_path.to_path_buf(),
+            rewrites_upmix: rewrites_upmix(options),
+            headroom_auto: options.headroom_auto,
+            normalize_lufs: options.normalize_lufs,
+            limit: options.limit,
+            true_peak: options.true_peak,
+            measure_loudness,
+        })
+    }
+
+    // Finishes the unfinished upmix into the targets, then reports the loudness. Returns the gain that was applied
+    pub fn finish(
+        &self,
+        header: WavHeader,
+        unfinished_paths: &[PathBuf],
+        target_paths: &[PathBuf],
+    ) -> Result<f64> {
+        println!();
+
+        let gain = if self.rewrites_upmix {
+            self.rewrite(header, unfinished_paths, target_paths)?
+        } else {
+            1.0
+        };
+
+        if self.measure_loudness {
+            println!("Measuring loudness...");
+
+            let sample_rate = header.sample_rate as usize;
+            let source =
+                measure_loudness(std::slice::from_ref(&self.source_wav_path), sample_rate)?;
+            let upmix = measure_loudness(target_paths, sample_rate)?;
+
+            println!("\t        Integrated   Short-term max   Range");
+            for (name, loudness) in [("Source", source), ("Upmix", upmix)] {
+                println!(
+                    "\t{:>6}: {:>6.1}LUFS {:>10.1}LUFS {:>7.1}LU",
+                    name, loudness.integrated, loudness.short_term_max, loudness.range
+                );
+            }
+        }
+
+        Ok(gain)
+    }
+
+    // Normalizes and limits the unfinished upmix into the targets, then removes the unfinished files
+    fn rewrite(
+        &self,
+        header: WavHeader,
+        unfinished_paths: &[PathBuf],
+        target_paths: &[PathBuf],
+    ) -> Result<f64> {
+        let channels = &header.channels;
+        let layout = SamplesByChannel::<f32> {
+            front_left: channels.front_left.then_some(0.0),
+            front_right: channels.front_right.then_some(0.0),
+            front_center: channels.front_center.then_some(0.0),
+            low_frequency: channels.low_frequency.then_some(0.0),
+            back_left: channels.back_left.then_some(0.0),
+            back_right: channels.back_right.then_some(0.0),
+            ..SamplesByChannel::new()
+        };
+
+        let gain = if self.headroom_auto || self.normalize_lufs.is_some() {
+            println!("Measuring the upmix...");
+
+            let mut true_peak_meter = TruePeakMeter::new();
+            let mut loudness_meter = LoudnessMeter::new(header.sample_rate as usize);
+            let mut peaks = [0.0f64; MAX_CHANNELS];
+            for samples in open_wavs(unfinished_paths)? {
+                let frame = to_frame(&samples?);
+
+                if self.headroom_auto {
+                    let (true_peaks, _) = true_peak_meter.measure(&frame);
+                    for (peak, true_peak) in peaks.iter_mut().zip(true_peaks) {
+                        *peak = peak.max(true_peak);
+                    }
+                } else {
+                    loudness_meter.add(&frame);
+                }
+            }
+
+            match self.normalize_lufs {
+                Some(normalize_lufs) => {
+                    let integrated = loudness_meter.loudness().integrated;
+                    if integrated.is_finite() {
+                        let gain = db_to_amplitude((normalize_lufs - integrated) as f32) as f64;
+                        println!(
+                            "Normalizing from {:.1}LUFS to {}LUFS, gain: {:.1}db",
+                            integrated,
+                            normalize_lufs,
+                            amplitude_to_db(gain as f32)
+                        );
+                        gain
+                    } else {
+                        println!("The upmix is silent, it will not be normalized");
+                        1.0
+                    }
+                }
+                None => self.peak_gain(&header, &peaks),
+            }
+        } else {
+            1.0
+        };
+
+        let mut limiter = if self.limit {
+            println!("Limiting to {}dbTP...", self.true_peak);
+            Some(Limiter::new(
+                db_to_amplitude(self.true_peak as f32) as f64,
+                header.sample_rate as usize,
+            ))
+        } else {
+            None
+        };
+
+        // Each target has as many samples as its unfinished file
+        let mut file_lengths = Vec::with_capacity(unfinished_paths.len());
+        let mut writers = Vec::with_capacity(target_paths.len());
+        for (unfinished_path, target_path) in unfinished_paths.iter().zip(target_paths) {
+            file_lengths.push(read_wav_from_file_path(unfinished_path)?.len_samples());
+            writers
+                .push(write_wav_to_file_path(target_path, header)?.get_random_access_f32_writer()?);
+        }
+
+        let total_samples: usize = file_lengths.iter().sum();
+        let mut samples_written = 0;
+        let mut write = |frame: &[f64; MAX_CHANNELS]| -> Result<()> {
+            let mut sample_ctr_in_file = samples_written;
+            for (file_length, writer) in file_lengths.iter().zip(writers.iter_mut()) {
+                if sample_ctr_in_file < *file_length {
+                    writer.write_samples(sample_ctr_in_file, from_frame(&layout, frame))?;
+                    break;
+                }
+
+                sample_ctr_in_file -= file_length;
+            }
+
+            samples_written += 1;
+            Ok(())
+        };
+
+        for samples in open_wavs(unfinished_paths)? {
+            let mut frame = to_frame(&samples?);
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+
+            match &mut limiter {
+                Some(limiter) => {
+                    if let Some(limited_frame) = limiter.process(frame) {
+                        write(&limited_frame)?;
+                    }
+                }
+                None => write(&frame)?,
+            }
+        }
+
+        // The limiter holds back samples while it looks ahead
+        if let Some(limiter) = &mut limiter {
+            let mut samples_remaining = total_samples - limiter.samples_processed;
+            while samples_remaining > 0 {
+                if let Some(limited_frame) = limiter.process([0.0; MAX_CHANNELS]) {
+                    write(&limited_frame)?;
+                    samples_remaining -= 1;
+                }
+            }
+
+            println!(
+                "Limited {} samples, maximum gain reduction: {:.1}db",
+                limiter.samples_limited,
+                amplitude_to_db(limiter.lowest_gain as f32)
+            );
+        }
+
+        for mut writer in writers {
+            writer.flush()?;
+        }
+
+        for unfinished_path in unfinished_paths {
+            remove_file(unfinished_path)?;
+        }
+
+        Ok(gain)
+    }
+
+    // Reports the true peak of each channel, and returns the gain that normalizes the loudest peak
+    fn peak_gain(&self, header: &WavHeader, peaks: &[f64; MAX_CHANNELS]) -> f64 {
+        let channels = &header.channels;
+        let channel_names = [
+            "Front left",
+            "Front right",
+            "Center",
+            "LFE",
+            "Rear left",
+            "Rear right",
+        ];
+        let present = [
+            channels.front_left,
+            channels.front_right,
+            channels.front_center,
+            channels.low_frequency,
+            channels.back_left,
+            channels.back_right,
+        ];
+        for channel_ctr in 0..MAX_CHANNELS {
+            if present[channel_ctr] {
+                println!(
+                    "\t{:>11}: {:>6.1}dbTP",
+                    channel_names[channel_ctr],
+                    amplitude_to_db(peaks[channel_ctr] as f32)
+                );
+            }
+        }
+
+        let peak = peaks.iter().cloned().fold(0.0, f64::max);
+        if peak > 0.0 {
+            let gain = db_to_amplitude(self.true_peak as f32) as f64 / peak;
+            println!(
+                "Normalizing to {}dbTP, gain: {:.1}db",
+                self.true_peak,
+                amplitude_to_db(gain as f32)
+            );
+            gain
+        } else {
+            println!("The upmix is silent, it will not be normalized");
+            1.0
+        }
+    }
+}
+
+// Reads all of the files, in order, as one stream
+fn open_wavs(paths: &[PathBuf]) -> Result<Box<dyn Iterator<Item = Result<SamplesByChannel<f32>>>>> {
+    let mut samples: Box<dyn Iterator<Item = Result<SamplesByChannel<f32>>>> =
+        Box::new(std::iter::empty());
+    for path in paths {
+        let reader = read_wav_from_file_path(path)?.get_stream_f32_reader()?;
+        samples = Box::new(samples.chain(reader));
+    }
+
+    Ok(samples)
+}
+
+fn measure_loudness(paths: &[PathBuf], sample_rate: usize) -> Result<Loudness> {
+    let mut loudness_meter = LoudnessMeter::new(sample_rate);
+    for samples in open_wavs(paths)? {
+        loudness_meter.add(&to_frame(&samples?));
+    }
+
+    Ok(loudness_meter.loudness())
+}
+
+// Finds the peaks between samples with a windowed-sinc interpolator
+struct TruePeakMeter {
+    coefficients: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    history: VecDeque<[f64; MAX_CHANNELS]>,
+}
+
+impl TruePeakMeter {
+    fn new() -> TruePeakMeter {
+        let len = OVERSAMPLING * TAPS_PER_PHASE;
+        let center = (len - 1) as f64 / 2.0;
+
+        let mut coefficients = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for (phase_ctr, phase) in coefficients.iter_mut().enumerate() {
+            for (tap_ctr, coefficient) in phase.iter_mut().enumerate() {
+                let n = (tap_ctr * OVERSAMPLING) + phase_ctr;
+                let x = (n as f64 - center) / OVERSAMPLING as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                let window = 0.5 - (0.5 * (2.0 * PI * (n as f64 + 0.5) / len as f64).cos());
+                *coefficient = sinc * window;
+            }
+
+            // Each phase passes DC unchanged
+            let sum: f64 = phase.iter().sum();
+            for coefficient in phase.iter_mut() {
+                *coefficient /= sum;
+            }
+        }
+
+        TruePeakMeter {
+            coefficients,
+            history: VecDeque::from(vec![[0.0; MAX_CHANNELS]; TAPS_PER_PHASE]),
+        }
+    }
+
+    // Returns the true peak of each channel, and the frame that it is centered on, (which is delayed by half of the
+    // interpolator)
+    fn measure(
+        &mut self,
+        frame: &[f64; MAX_CHANNELS],
+    ) -> ([f64; MAX_CHANNELS], [f64; MAX_CHANNELS]) {
+        self.history.pop_back();
+        self.history.push_front(*frame);
+
+        let centered = self.history[TAPS_PER_PHASE / 2];
+
+        let mut peaks = centered.map(f64::abs);
+        for phase in self.coefficients.iter() {
+            for (channel_ctr, peak) in peaks.iter_mut().enumerate() {
+                let interpolated: f64 = phase
+                    .iter()
+                    .zip(self.history.iter())
+                    .map(|(coefficient, frame)| coefficient * frame[channel_ctr])
+                    .sum();
+                *peak = peak.max(interpolated.abs());
+            }
+        }
+
+        (peaks, centered)
+    }
+}
+
+// A look-ahead limiter: The gain is the smallest gain needed in the next look-ahead samples, then smoothed over the
+// look-ahead. Because every sample is within the look-ahead of all of the gains that are smoothed, the gain is
+// always low enough
+struct Limiter {
+    ceiling: f64,
+    look_ahead: usize,
+    release: f64,
+
+    true_peak_meter: TruePeakMeter,
+    // Frames waiting to be written, and the gain they need
+    frames: VecDeque<([f64; MAX_CHANNELS], f64)>,
+    // The smallest needed gain in the look-ahead, after release
+    released_gain: f64,
+    // The released gains, to smooth over the look-ahead. Seeded with the first released gain, because the frames
+    // before the first frame aren't in the look-ahead of the first peaks
+    released_gains: VecDeque<f64>,
+    released_gains_sum: f64,
+
+    // The true peak meter is delayed by half of its interpolator
+    samples_to_skip: usize,
+
+    samples_processed: usize,
+    samples_limited: usize,
+    lowest_gain: f64,
+}
+
+impl Limiter {
+    fn new(ceiling: f64, sample_rate: usize) -> Limiter {
+        let look_ahead = ((LOOK_AHEAD_SECONDS * sample_rate as f64) as usize).max(1);
+
+        Limiter {
+            ceiling,
+            look_ahead,
+            release: 1.0 - (-1.0 / (RELEASE_SECONDS * sample_rate as f64)).exp(),
+            true_peak_meter: TruePeakMeter::new(),
+            frames: VecDeque::with_capacity(look_ahead),
+            released_gain: 1.0,
+            released_gains: VecDeque::with_capacity(look_ahead + 1),
+            released_gains_sum: 0.0,
+            samples_to_skip: TAPS_PER_PHASE / 2,
+            samples_processed: 0,
+            samples_limited: 0,
+            lowest_gain: 1.0,
+        }
+    }
+
+    // Returns a limited frame once enough frames are read to look ahead. At the end of the file, silence is passed in
+    // until every frame is returned
+    fn process(&mut self, frame: [f64; MAX_CHANNELS]) -> Option<[f64; MAX_CHANNELS]> {
+        let (peaks, centered) = self.true_peak_meter.measure(&frame);
+
+        // The true peak meter starts with silence
+        if self.samples_to_skip > 0 {
+            self.samples_to_skip -= 1;
+            return None;
+        }
+
+        let peak = peaks.iter().cloned().fold(0.0, f64::max);
+        let needed_gain = if peak > self.ceiling {
+            self.ceiling / peak
+        } else {
+            1.0
+        };
+
+        self.frames.push_back((centered, needed_gain));
+        if self.frames.len() < self.look_ahead {
+            return None;
+        }
+
+        Some(self.next_frame())
+    }
+
+    fn next_frame(&mut self) -> [f64; MAX_CHANNELS] {
+        // The smallest gain needed in the look-ahead
+        let needed_gain = self
+            .frames
+            .iter()
+            .take(self.look_ahead)
+            .map(|(_, needed_gain)| *needed_gain)
+            .fold(1.0, f64::min);
+
+        self.released_gain =
+            needed_gain.min(self.released_gain + ((1.0 - self.released_gain) * self.release));
+        if self.released_gain > RELEASED {
+            self.released_gain = 1.0;
+        }
+
+        if self.released_gains.is_empty() {
+            self.released_gains = VecDeque::from(vec![self.released_gain; self.look_ahead]);
+            self.released_gains_sum = self.released_gain * self.look_ahead as f64;
+        }
+
+        self.released_gains_sum += self.released_gain;
+        self.released_gains.push_back(self.released_gain);
+        self.released_gains_sum -= self.released_gains.pop_front().unwrap_or(1.0);
+
+        let gain = (self.released_gains_sum / self.look_ahead as f64).min(1.0);
+
+        let (mut frame, _) = self.frames.pop_front().expect("Frames expected");
+        self.samples_processed += 1;
+        if gain < 1.0 {
+            self.samples_limited += 1;
+            self.lowest_gain = self.lowest_gain.min(gain);
+
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+        }
+
+        frame
+    }
+}
+
+fn to_frame(samples: &SamplesByChannel<f32>) -> [f64; MAX_CHANNELS] {
+    [
+        samples.front_left,
+        samples.front_right,
+        samples.front_center,
+        samples.low_frequency,
+        samples.back_left,
+        samples.back_right,
+    ]
+    .map(|sample| sample.unwrap_or(0.0) as f64)
+}
+
+// Fills in the channels that are in the layout
+fn from_frame(
+    layout: &SamplesByChannel<f32>,
+    frame: &[f64; MAX_CHANNELS],
+) -> SamplesByChannel<f32> {
+    SamplesByChannel {
+        front_left: layout.front_left.map(|_| frame[0] as f32),
+        front_right: layout.front_right.map(|_| frame[1] as f32),
+        front_center: layout.front_center.map(|_| frame[2] as f32),
+        low_frequency: layout.low_frequency.map(|_| frame[3] as f32),
+        back_left: layout.back_left.map(|_| frame[4] as f32),
+        back_right: layout.back_right.map(|_| frame[5] as f32),
+        ..*layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // A sine at a quarter of the sample rate, with the samples halfway between the peaks. The samples are at
+    // full scale, and the peaks between them are 3db higher
+    fn inter_sample_peaks(len: usize) -> Vec<f64> {
+        (0..len)
+            .map(|sample_ctr| {
+                std::f64::consts::SQRT_2 * ((PI / 2.0 * sample_ctr as f64) + (PI / 4.0)).sin()
+            })
+            .collect()
+    }
+
+    // Limits the samples in the front left, the same way as Finisher
+    fn limit(samples: &[f64], ceiling: f64) -> Vec<f64> {
+        let mut limiter = Limiter::new(ceiling, SAMPLE_RATE);
+        let mut limited = Vec::with_capacity(samples.len());
+
+        for sample in samples {
+            let mut frame = [0.0; MAX_CHANNELS];
+            frame[0] = *sample;
+            if let Some(limited_frame) = limiter.process(frame) {
+                limited.push(limited_frame[0]);
+            }
+        }
+
+        while limited.len() < samples.len() {
+            if let Some(limited_frame) = limiter.process([0.0; MAX_CHANNELS]) {
+                limited.push(limited_frame[0]);
+            }
+        }
+
+        limited
+    }
+
+    fn true_peak(samples: &[f64]) -> f64 {
+        let mut true_peak_meter = TruePeakMeter::new();
+        let mut true_peak = 0.0f64;
+
+        // Silence is added at the end so the interpolator reaches the last samples
+        for sample in samples.iter().chain([0.0; TAPS_PER_PHASE].iter()) {
+            let mut frame = [0.0; MAX_CHANNELS];
+            frame[0] = *sample;
+            let (peaks, _) = true_peak_meter.measure(&frame);
+            true_peak = true_peak.max(peaks[0]);
+        }
+
+        true_peak
+    }
+
+    #[test]
+    fn true_peak_meter_finds_peaks_between_samples() {
+        let samples = inter_sample_peaks(SAMPLE_RATE / 10);
+        let sample_peak = samples
+            .iter()
+            .cloned()
+            .fold(0.0, |peak, sample| sample.abs().max(peak));
+
+        assert!((sample_peak - 1.0).abs() < 1e-9);
+
+        let true_peak_db = amplitude_to_db(true_peak(&samples) as f32);
+        assert!(
+            (true_peak_db - 3.0).abs() < 0.1,
+            "true peak is {}db, expected 3db",
+            true_peak_db
+        );
+    }
+
+    #[test]
+    fn limiter_keeps_inter_sample_peaks_below_the_ceiling() {
+        let ceiling = db_to_amplitude(-1.0) as f64;
+
+        // The peaks start at the first sample, and after silence
+        let mut samples = inter_sample_peaks(SAMPLE_RATE / 10);
+        samples.extend(vec![0.0; SAMPLE_RATE / 10]);
+        samples.extend(inter_sample_peaks(SAMPLE_RATE / 10));
+
+        let limited = limit(&samples, ceiling);
+        assert_eq!(limited.len(), samples.len());
+
+        let true_peak = true_peak(&limited);
+        assert!(
+            true_peak <= ceiling * 1.0001,
+            "true peak is {}db, the ceiling is -1db",
+            amplitude_to_db(true_peak as f32)
+        );
+    }
+
+    #[test]
+    fn limiter_passes_quiet_samples_unchanged() {
+        let samples: Vec<f64> = inter_sample_peaks(SAMPLE_RATE / 10)
+            .iter()
+            .map(|sample| sample * 0.5)
+            .collect();
+
+        assert_eq!(limit(&samples, 1.0), samples);
+    }
+}
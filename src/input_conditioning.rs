@@ -0,0 +1,114 @@
+use std::f64::consts::TAU;
+
+use crate::options::{db_to_amplitude, InputConditioning};
+
+// When left and right are this anti-correlated, one channel is probably inverted. (Stereo recordings are almost always
+// positively correlated)
+const POLARITY_WARNING_CORRELATION: f64 = -0.3;
+
+// Fixes mis-wired captures before they are steered: Swaps, inverts, and balances the channels, and removes DC
+pub struct InputConditioner {
+    swap: bool,
+    left_gain: f64,
+    right_gain: f64,
+    dc_blockers: Option<[DcBlocker; 2]>,
+
+    // Sums for the correlation between left and right
+    left_right: f64,
+    left_squared: f64,
+    right_squared: f64,
+}
+
+impl InputConditioner {
+    pub fn new(input_conditioning: &InputConditioning, sample_rate: usize) -> InputConditioner {
+        // Positive balance moves the image to the right; each channel gets half of the change
+        let balance = db_to_amplitude((input_conditioning.balance / 2.0) as f32) as f64;
+        let left_gain = if input_conditioning.invert_left {
+            -1.0 / balance
+        } else {
+            1.0 / balance
+        };
+        let right_gain = if input_conditioning.invert_right {
+            -balance
+        } else {
+            balance
+        };
+
+        InputConditioner {
+            swap: input_conditioning.swap,
+            left_gain,
+            right_gain,
+            dc_blockers: input_conditioning.dc_block.map(|frequency| {
+                [
+                    DcBlocker::new(frequency, sample_rate),
+                    DcBlocker::new(frequency, sample_rate),
+                ]
+            }),
+            left_right: 0.0,
+            left_squared: 0.0,
+            right_squared: 0.0,
+        }
+    }
+
+    pub fn condition(&mut self, left: f64, right: f64) -> (f64, f64) {
+        let (left, right) = if self.swap {
+            (right, left)
+        } else {
+            (left, right)
+        };
+
+        let mut left = left * self.left_gain;
+        let mut right = right * self.right_gain;
+
+        if let Some([left_dc_blocker, right_dc_blocker]) = &mut self.dc_blockers {
+            left = left_dc_blocker.process(left);
+            right = right_dc_blocker.process(right);
+        }
+
+        self.left_right += left * right;
+        self.left_squared += left * left;
+        self.right_squared += right * right;
+
+        (left, right)
+    }
+
+    // Warns if the conditioned left and right channels look like one of them is inverted
+    pub fn warn_about_polarity(&self) {
+        let energy = (self.left_squared * self.right_squared).sqrt();
+        if energy == 0.0 {
+            return;
+        }
+
+        let correlation = self.left_right / energy;
+        if correlation < POLARITY_WARNING_CORRELATION {
+            println!(
+                "Warning: The left and right channels are out of phase, (correlation {:.2},) so most of the sound was steered to the rear. One channel may be inverted, consider -invert-left or -invert-right",
+                correlation
+            );
+        }
+    }
+}
+
+// A one-pole highpass
+struct DcBlocker {
+    pole: f64,
+    last_input: f64,
+    last_output: f64,
+}
+
+impl DcBlocker {
+    fn new(frequency: f64, sample_rate: usize) -> DcBlocker {
+        DcBlocker {
+            pole: (-TAU * frequency / sample_rate as f64).exp(),
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = input - self.last_input + (self.pole * self.last_output);
+        self.last_input = input;
+        self.last_output = output;
+        output
+    }
+}
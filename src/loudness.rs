@@ -0,0 +1,285 @@
+use std::f64::consts::PI;
+
+use crate::headroom::MAX_CHANNELS;
+
+// See ITU-R BS.1770-4 and EBU Tech 3342
+// Loudness is measured in 400ms blocks, that overlap by 75%; short-term loudness is measured over 3 seconds
+const SUB_BLOCK_SECONDS: f64 = 0.1;
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const SUB_BLOCKS_PER_SHORT_TERM: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+const RANGE_LOW_PERCENTILE: f64 = 0.1;
+const RANGE_HIGH_PERCENTILE: f64 = 0.95;
+
+// Front left, front right, center, LFE, rear left, rear right. The LFE isn't measured, and surrounds are 1.5db louder
+const CHANNEL_WEIGHTS: [f64; MAX_CHANNELS] = [1.0, 1.0, 1.0, 0.0, 1.41, 1.41];
+
+pub struct Loudness {
+    // In LUFS
+    pub integrated: f64,
+    pub short_term_max: f64,
+    // In LU
+    pub range: f64,
+}
+
+// Measures loudness, one frame at a time
+pub struct LoudnessMeter {
+    k_weighting: Vec<[Biquad; 2]>,
+    sub_block_len: usize,
+
+    // The weighted power of the current sub-block
+    sub_block_sum: f64,
+    sub_block_samples: usize,
+
+    // The mean weighted power of each 100ms sub-block
+    sub_blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: usize) -> LoudnessMeter {
+        let sample_rate = sample_rate as f64;
+
+        LoudnessMeter {
+            k_weighting: (0..MAX_CHANNELS)
+                .map(|_| {
+                    [
+                        Biquad::pre_filter(sample_rate),
+                        Biquad::rlb_filter(sample_rate),
+                    ]
+                })
+                .collect(),
+            sub_block_len: (SUB_BLOCK_SECONDS * sample_rate).round() as usize,
+            sub_block_sum: 0.0,
+            sub_block_samples: 0,
+            sub_blocks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, frame: &[f64; MAX_CHANNELS]) {
+        for channel_ctr in 0..MAX_CHANNELS {
+            if CHANNEL_WEIGHTS[channel_ctr] == 0.0 {
+                continue;
+            }
+
+            let [pre_filter, rlb_filter] = &mut self.k_weighting[channel_ctr];
+            let weighted = rlb_filter.process(pre_filter.process(frame[channel_ctr]));
+            self.sub_block_sum += CHANNEL_WEIGHTS[channel_ctr] * weighted * weighted;
+        }
+
+        self.sub_block_samples += 1;
+        if self.sub_block_samples == self.sub_block_len {
+            self.sub_blocks
+                .push(self.sub_block_sum / self.sub_block_len as f64);
+            self.sub_block_sum = 0.0;
+            self.sub_block_samples = 0;
+        }
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        let blocks = mean_powers(&self.sub_blocks, SUB_BLOCKS_PER_BLOCK);
+        let short_terms = mean_powers(&self.sub_blocks, SUB_BLOCKS_PER_SHORT_TERM);
+
+        Loudness {
+            integrated: gated_loudness(&blocks, RELATIVE_GATE),
+            short_term_max: short_terms
+                .iter()
+                .map(|power| power_to_loudness(*power))
+                .fold(f64::NEG_INFINITY, f64::max),
+            range: loudness_range(&short_terms),
+        }
+    }
+}
+
+// The mean power of each window of sub-blocks
+fn mean_powers(sub_blocks: &[f64], sub_blocks_per_window: usize) -> Vec<f64> {
+    sub_blocks
+        .windows(sub_blocks_per_window)
+        .map(|window| window.iter().sum::<f64>() / sub_blocks_per_window as f64)
+        .collect()
+}
+
+fn power_to_loudness(power: f64) -> f64 {
+    -0.691 + (10.0 * power.log10())
+}
+
+// Blocks below the absolute gate are ignored. Then blocks quieter than the relative gate, (relative to the remaining
+// blocks,) are also ignored
+fn relative_gate(powers: &[f64], relative_gate: f64) -> Vec<f64> {
+    let above_absolute_gate: Vec<f64> = powers
+        .iter()
+        .cloned()
+        .filter(|power| power_to_loudness(*power) > ABSOLUTE_GATE)
+        .collect();
+
+    if above_absolute_gate.is_empty() {
+        return above_absolute_gate;
+    }
+
+    let mean = above_absolute_gate.iter().sum::<f64>() / above_absolute_gate.len() as f64;
+    let gate = power_to_loudness(mean) + relative_gate;
+
+    above_absolute_gate
+        .into_iter()
+        .filter(|power| power_to_loudness(*power) > gate)
+        .collect()
+}
+
+fn gated_loudness(powers: &[f64], relative: f64) -> f64 {
+    let gated = relative_gate(powers, relative);
+    if gated.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+
+    power_to_loudness(gated.iter().sum::<f64>() / gated.len() as f64)
+}
+
+fn loudness_range(short_terms: &[f64]) -> f64 {
+    let mut loudnesses: Vec<f64> = relative_gate(short_terms, RANGE_RELATIVE_GATE)
+        .into_iter()
+        .map(power_to_loudness)
+        .collect();
+
+    if loudnesses.is_empty() {
+        return 0.0;
+    }
+
+    loudnesses.sort_by(f64::total_cmp);
+
+    let percentile = |fraction: f64| {
+        let index = ((loudnesses.len() - 1) as f64 * fraction).round() as usize;
+        loudnesses[index]
+    };
+
+    percentile(RANGE_HIGH_PERCENTILE) - percentile(RANGE_LOW_PERCENTILE)
+}
+
+// The K-weighting filters, calculated for any sample rate
+// See https://github.com/jiixyj/libebur128
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    // A high shelf, +4db, that models the acoustic effect of the head
+    fn pre_filter(sample_rate: f64) -> Biquad {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+
+        let a0 = 1.0 + (k / q) + (k * k);
+        Biquad::new(
+            [
+                (vh + (vb * k / q) + (k * k)) / a0,
+                2.0 * ((k * k) - vh) / a0,
+                (vh - (vb * k / q) + (k * k)) / a0,
+            ],
+            [2.0 * ((k * k) - 1.0) / a0, (1.0 - (k / q) + (k * k)) / a0],
+        )
+    }
+
+    // The revised low-frequency B-curve: A highpass at about 38hz
+    fn rlb_filter(sample_rate: f64) -> Biquad {
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+
+        let k = (PI * f0 / sample_rate).tan();
+
+        let a0 = 1.0 + (k / q) + (k * k);
+        Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * ((k * k) - 1.0) / a0, (1.0 - (k / q) + (k * k)) / a0],
+        )
+    }
+
+    fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        let output = (self.b[0] * sample) + (self.b[1] * self.x[0]) + (self.b[2] * self.x[1])
+            - (self.a[0] * self.y[0])
+            - (self.a[1] * self.y[1]);
+
+        self.x = [sample, self.x[0]];
+        self.y = [output, self.y[0]];
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // Measures a stereo 1khz sine, in segments of (level in dbfs, seconds). A level of None is silence
+    fn measure(segments: &[(Option<f64>, usize)]) -> Loudness {
+        let mut loudness_meter = LoudnessMeter::new(SAMPLE_RATE);
+
+        let mut sample_ctr = 0;
+        for (level, seconds) in segments {
+            let amplitude = match level {
+                Some(level) => 10f64.powf(level / 20.0),
+                None => 0.0,
+            };
+
+            for _ in 0..(seconds * SAMPLE_RATE) {
+                let sample =
+                    amplitude * (2.0 * PI * 1000.0 * sample_ctr as f64 / SAMPLE_RATE as f64).sin();
+                loudness_meter.add(&[sample, sample, 0.0, 0.0, 0.0, 0.0]);
+                sample_ctr += 1;
+            }
+        }
+
+        loudness_meter.loudness()
+    }
+
+    fn assert_integrated(loudness: &Loudness, expected: f64) {
+        assert!(
+            (loudness.integrated - expected).abs() <= 0.1,
+            "integrated loudness is {}LUFS, expected {}LUFS",
+            loudness.integrated,
+            expected
+        );
+    }
+
+    // EBU Tech 3341, case 1
+    #[test]
+    fn sine_at_minus_23_dbfs_is_minus_23_lufs() {
+        let loudness = measure(&[(Some(-23.0), 20)]);
+        assert_integrated(&loudness, -23.0);
+        assert!((loudness.short_term_max - -23.0).abs() <= 0.1);
+    }
+
+    // Silence is below the absolute gate
+    #[test]
+    fn silence_is_gated() {
+        assert_integrated(&measure(&[(Some(-23.0), 20), (None, 20)]), -23.0);
+        assert_eq!(measure(&[(None, 5)]).integrated, f64::NEG_INFINITY);
+    }
+
+    // EBU Tech 3341, case 3: The quiet parts are below the relative gate
+    #[test]
+    fn quiet_parts_are_gated() {
+        assert_integrated(
+            &measure(&[(Some(-36.0), 10), (Some(-23.0), 60), (Some(-36.0), 10)]),
+            -23.0,
+        );
+    }
+}
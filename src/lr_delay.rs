@@ -0,0 +1,423 @@
+use std::{
+    f64::consts::{PI, TAU},
+    io::Result,
+};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use wave_stream::{open_wav::OpenWav, read_wav_from_file_path, wave_reader::StreamOpenWavReader};
+
+use crate::{
+    input_conditioning::InputConditioner,
+    options::{LrDelay, Options},
+};
+
+// The delay is found with the generalized cross-correlation with phase transform, (GCC-PHAT,) of overlapping blocks
+const BLOCK_SIZE: usize = 4096;
+
+// Head azimuth error is usually a few samples; anything further apart is a wide stereo image, not an error
+const MAXIMUM_DELAY: usize = 32;
+
+// The delay is measured in segments, so it can drift over time
+const SEGMENT_SECONDS: f64 = 10.0;
+
+// A segment whose cross-correlation peak is below this has no consistent delay, (silence, or uncorrelated sound)
+const MINIMUM_PEAK: f64 = 0.1;
+
+// Corrects a time offset between the left and right channels, (such as from tape head azimuth error,) before steering
+//
+// The delay doesn't depend on the window size, so it is measured once and shared by each band of a multiband upmix
+#[derive(Clone)]
+pub struct DelayCorrection {
+    segment_samples: usize,
+
+    // How late the right channel is, in samples, in each segment
+    delays: Vec<f64>,
+}
+
+impl DelayCorrection {
+    pub fn new(options: &Options) -> Result<Option<DelayCorrection>> {
+        let (delays, segment_samples) = match options.lr_delay {
+            None => return Ok(None),
+            Some(LrDelay::Samples(delay)) => {
+                print_delay(delay);
+                (vec![delay], usize::MAX)
+            }
+            Some(LrDelay::Auto) => match measure_delays(options)? {
+                Some(delays_and_segment_samples) => delays_and_segment_samples,
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(DelayCorrection {
+            segment_samples,
+            delays,
+        }))
+    }
+
+    // Advances the right channel by the delay, for the window that ends with last_sample_ctr
+    pub fn correct(&self, last_sample_ctr: usize, right_transformed: &mut [Complex<f64>]) {
+        let window_size = right_transformed.len();
+        let delay = self.delay(last_sample_ctr.saturating_sub(window_size / 2));
+
+        // A delay is a phase shift that increases with frequency. Negative frequencies are shifted the opposite way
+        let step = Complex::from_polar(1.0, TAU * delay / window_size as f64);
+        let mut shift = Complex::new(1.0, 0.0);
+        for freq_ctr in 1..window_size.div_ceil(2) {
+            shift *= step;
+            right_transformed[freq_ctr] *= shift;
+            right_transformed[window_size - freq_ctr] *= shift.conj();
+        }
+
+        // The middle frequency of an even window is real, so only the real part of its shift is used
+        if window_size.is_multiple_of(2) {
+            right_transformed[window_size / 2] *= (PI * delay).cos();
+        }
+    }
+
+    // Interpolates between the centers of the segments
+    fn delay(&self, sample_ctr: usize) -> f64 {
+        if self.delays.len() == 1 {
+            return self.delays[0];
+        }
+
+        let position = (sample_ctr as f64 / self.segment_samples as f64) - 0.5;
+        if position <= 0.0 {
+            return self.delays[0];
+        }
+
+        let segment_ctr = position.floor() as usize;
+        if segment_ctr + 1 >= self.delays.len() {
+            return self.delays[self.delays.len() - 1];
+        }
+
+        let fraction = position - segment_ctr as f64;
+        (self.delays[segment_ctr] * (1.0 - fraction)) + (self.delays[segment_ctr + 1] * fraction)
+    }
+}
+
+// Reads the source and measures the delay of each segment. Returns None if there is no consistent delay
+fn measure_delays(options: &Options) -> Result<Option<(Vec<f64>, usize)>> {
+    println!("Measuring the delay between left and right...");
+
+    let source_wav = read_wav_from_file_path(&options.source_wav_path)?;
+    let sample_rate = source_wav.sample_rate() as usize;
+
+    let hop = BLOCK_SIZE / 2;
+    let blocks_per_segment = ((SEGMENT_SECONDS * sample_rate as f64) / hop as f64).round() as usize;
+
+    let mut planner = FftPlanner::new();
+    let fft_forward = planner.plan_fft_forward(BLOCK_SIZE);
+    let fft_inverse = planner.plan_fft_inverse(BLOCK_SIZE);
+
+    let window: Vec<f64> = (0..BLOCK_SIZE)
+        .map(|sample_ctr| 0.5 - (0.5 * (TAU * sample_ctr as f64 / BLOCK_SIZE as f64).cos()))
+        .collect();
+
+    let mut left_samples = Vec::with_capacity(BLOCK_SIZE);
+    let mut right_samples = Vec::with_capacity(BLOCK_SIZE);
+
+    // The cross-spectrum of each segment, and of the whole source
+    let mut segment_cross_spectrum = vec![Complex::new(0.0, 0.0); BLOCK_SIZE];
+    let mut total_cross_spectrum = vec![Complex::new(0.0, 0.0); BLOCK_SIZE];
+    let mut segment_delays = Vec::new();
+    let mut block_ctr = 0;
+
+    let mut input_conditioner = InputConditioner::new(&options.input_conditioning, sample_rate);
+    for samples in source_wav.get_stream_f32_reader()? {
+        let samples = samples?;
+        let (left, right) = input_conditioner.condition(
+            samples.front_left.expect("front_left missing when reading") as f64,
+            samples
+                .front_right
+                .expect("front_right missing when reading") as f64,
+        );
+        left_samples.push(left);
+        right_samples.push(right);
+
+        if left_samples.len() < BLOCK_SIZE {
+            continue;
+        }
+
+        let cross_spectrum =
+            cross_spectrum(&left_samples, &right_samples, &window, fft_forward.as_ref());
+        for freq_ctr in 0..BLOCK_SIZE {
+            segment_cross_spectrum[freq_ctr] += cross_spectrum[freq_ctr];
+            total_cross_spectrum[freq_ctr] += cross_spectrum[freq_ctr];
+        }
+
+        block_ctr += 1;
+        if block_ctr % blocks_per_segment == 0 {
+            segment_delays.push(gcc_phat(&segment_cross_spectrum, fft_inverse.as_ref()));
+            segment_cross_spectrum = vec![Complex::new(0.0, 0.0); BLOCK_SIZE];
+        }
+
+        left_samples.drain(..hop);
+        right_samples.drain(..hop);
+    }
+
+    if block_ctr % blocks_per_segment > 0 {
+        segment_delays.push(gcc_phat(&segment_cross_spectrum, fft_inverse.as_ref()));
+    }
+
+    let delay = match gcc_phat(&total_cross_spectrum, fft_inverse.as_ref()) {
+        Some(delay) => delay,
+        None => {
+            println!("\tNo consistent delay was found, the delay will not be corrected");
+            return Ok(None);
+        }
+    };
+
+    print_delay(delay);
+
+    // Segments without a consistent delay use the delay of the whole source
+    let delays: Vec<f64> = segment_delays
+        .iter()
+        .map(|segment_delay| segment_delay.unwrap_or(delay))
+        .collect();
+
+    let earliest = delays.iter().cloned().fold(f64::INFINITY, f64::min);
+    let latest = delays.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if latest - earliest >= 0.1 {
+        println!(
+            "\tThe delay drifts from {:.2} to {:.2} samples",
+            earliest, latest
+        );
+    }
+
+    Ok(Some((delays, blocks_per_segment * hop)))
+}
+
+// The cross-spectrum of a block of left and right samples
+fn cross_spectrum(
+    left_samples: &[f64],
+    right_samples: &[f64],
+    window: &[f64],
+    fft_forward: &dyn Fft<f64>,
+) -> Vec<Complex<f64>> {
+    let mut left_block: Vec<Complex<f64>> = left_samples
+        .iter()
+        .zip(window)
+        .map(|(sample, window)| Complex::new(sample * window, 0.0))
+        .collect();
+    let mut right_block: Vec<Complex<f64>> = right_samples
+        .iter()
+        .zip(window)
+        .map(|(sample, window)| Complex::new(sample * window, 0.0))
+        .collect();
+    fft_forward.process(&mut left_block);
+    fft_forward.process(&mut right_block);
+
+    left_block
+        .iter()
+        .zip(&right_block)
+        .map(|(left, right)| left * right.conj())
+        .collect()
+}
+
+// Finds how late the right channel is, in samples, from a cross-spectrum. Returns None if there is no clear peak
+fn gcc_phat(cross_spectrum: &[Complex<f64>], fft_inverse: &dyn Fft<f64>) -> Option<f64> {
+    // Only the phase is used, so that loud frequencies don't dominate
+    let phases: Vec<Complex<f64>> = cross_spectrum
+        .iter()
+        .map(|cross| {
+            let magnitude = cross.norm();
+            if magnitude > 0.0 {
+                cross / magnitude
+            } else {
+                Complex::new(0.0, 0.0)
+            }
+        })
+        .collect();
+    let mut correlation = phases.clone();
+    fft_inverse.process(&mut correlation);
+
+    // When the right channel is late, the peak is at a negative lag
+    let at_lag = |lag: isize| {
+        correlation[lag.rem_euclid(BLOCK_SIZE as isize) as usize].re / BLOCK_SIZE as f64
+    };
+
+    let maximum_delay = MAXIMUM_DELAY as isize;
+    let peak_lag =
+        (-maximum_delay..=maximum_delay).max_by(|a, b| at_lag(*a).total_cmp(&at_lag(*b)))?;
+    if at_lag(peak_lag) < MINIMUM_PEAK {
+        return None;
+    }
+
+    // The peak is between samples. The correlation is calculated between samples directly from the phases, (the same
+    // as the inverse transform, but at a fractional lag,) and the peak is found with a golden-section search
+    let at_fractional_lag = |lag: f64| {
+        phases
+            .iter()
+            .enumerate()
+            .map(|(freq_ctr, phase)| {
+                // Negative frequencies turn the other way
+                let frequency = if freq_ctr <= BLOCK_SIZE / 2 {
+                    freq_ctr as f64
+                } else {
+                    freq_ctr as f64 - BLOCK_SIZE as f64
+                };
+                (phase * Complex::from_polar(1.0, TAU * frequency * lag / BLOCK_SIZE as f64)).re
+            })
+            .sum::<f64>()
+    };
+
+    let golden_ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let mut lower = peak_lag as f64 - 1.0;
+    let mut upper = peak_lag as f64 + 1.0;
+    while upper - lower > 0.001 {
+        let lower_inner = upper - (golden_ratio * (upper - lower));
+        let upper_inner = lower + (golden_ratio * (upper - lower));
+        if at_fractional_lag(lower_inner) > at_fractional_lag(upper_inner) {
+            upper = upper_inner;
+        } else {
+            lower = lower_inner;
+        }
+    }
+
+    Some(-(lower + upper) / 2.0)
+}
+
+fn print_delay(delay: f64) {
+    let channel = if delay >= 0.0 { "right" } else { "left" };
+    println!(
+        "\tLeft-right delay: {:.2} samples, the {} channel is late",
+        delay.abs(),
+        channel
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small linear congruential generator, so the noise is the same every time
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 11) as f64 / (1u64 << 53) as f64) - 0.5
+        }
+    }
+
+    // Noise, and the same noise delayed by a (possibly fractional) number of samples. The delay is a phase shift of
+    // the whole signal, so the delayed noise wraps around
+    fn noise_and_delayed(len: usize, delay: f64) -> (Vec<f64>, Vec<f64>) {
+        let mut random = Random(1);
+        let noise: Vec<f64> = (0..len).map(|_| random.next()).collect();
+
+        let mut planner = FftPlanner::new();
+        let mut transformed: Vec<Complex<f64>> = noise
+            .iter()
+            .map(|sample| Complex::new(*sample, 0.0))
+            .collect();
+        planner.plan_fft_forward(len).process(&mut transformed);
+
+        for freq_ctr in 1..len.div_ceil(2) {
+            let shift = Complex::from_polar(1.0, -TAU * delay * freq_ctr as f64 / len as f64);
+            transformed[freq_ctr] *= shift;
+            transformed[len - freq_ctr] *= shift.conj();
+        }
+        transformed[len / 2] *= (PI * delay).cos();
+
+        planner.plan_fft_inverse(len).process(&mut transformed);
+        let delayed = transformed
+            .iter()
+            .map(|sample| sample.re / len as f64)
+            .collect();
+
+        (noise, delayed)
+    }
+
+    // Measures the delay over overlapping blocks, the same way as measure_delays
+    fn measure(left: &[f64], right: &[f64]) -> Option<f64> {
+        let mut planner = FftPlanner::new();
+        let fft_forward = planner.plan_fft_forward(BLOCK_SIZE);
+        let fft_inverse = planner.plan_fft_inverse(BLOCK_SIZE);
+
+        let window: Vec<f64> = (0..BLOCK_SIZE)
+            .map(|sample_ctr| 0.5 - (0.5 * (TAU * sample_ctr as f64 / BLOCK_SIZE as f64).cos()))
+            .collect();
+
+        let mut total_cross_spectrum = vec![Complex::new(0.0, 0.0); BLOCK_SIZE];
+        let mut start = 0;
+        while start + BLOCK_SIZE <= left.len() {
+            let cross_spectrum = cross_spectrum(
+                &left[start..(start + BLOCK_SIZE)],
+                &right[start..(start + BLOCK_SIZE)],
+                &window,
+                fft_forward.as_ref(),
+            );
+            for (total, cross) in total_cross_spectrum.iter_mut().zip(cross_spectrum) {
+                *total += cross;
+            }
+
+            start += BLOCK_SIZE / 2;
+        }
+
+        gcc_phat(&total_cross_spectrum, fft_inverse.as_ref())
+    }
+
+    #[test]
+    fn gcc_phat_measures_fractional_delays() {
+        for delay in [2.3, -1.6, 0.5, 7.75] {
+            let (left, right) = noise_and_delayed(16 * BLOCK_SIZE, delay);
+
+            let measured = measure(&left, &right).expect("No delay found");
+            assert!(
+                (measured - delay).abs() < 0.1,
+                "measured {} samples, expected {}",
+                measured,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn uncorrelated_channels_have_no_delay() {
+        let (left, _) = noise_and_delayed(16 * BLOCK_SIZE, 0.0);
+        let mut random = Random(2);
+        let right: Vec<f64> = (0..left.len()).map(|_| random.next()).collect();
+
+        assert_eq!(measure(&left, &right), None);
+    }
+
+    #[test]
+    fn correct_undoes_an_integer_delay() {
+        let window_size = 1024;
+        for delay in [3, -2] {
+            let (left, right) = noise_and_delayed(window_size, delay as f64);
+
+            let mut right_transformed: Vec<Complex<f64>> = right
+                .iter()
+                .map(|sample| Complex::new(*sample, 0.0))
+                .collect();
+            let mut planner = FftPlanner::new();
+            planner
+                .plan_fft_forward(window_size)
+                .process(&mut right_transformed);
+
+            let delay_correction = DelayCorrection {
+                segment_samples: usize::MAX,
+                delays: vec![delay as f64],
+            };
+            delay_correction.correct(window_size - 1, &mut right_transformed);
+
+            planner
+                .plan_fft_inverse(window_size)
+                .process(&mut right_transformed);
+            for (left, corrected) in left.iter().zip(&right_transformed) {
+                assert!(
+                    (corrected.re / window_size as f64 - left).abs() < 1e-9,
+                    "delay {}: corrected sample is {}, expected {}",
+                    delay,
+                    corrected.re / window_size as f64,
+                    left
+                );
+            }
+        }
+    }
+}
@@ -1,23 +1,40 @@
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use wave_stream::open_wav::OpenWav;
 use wave_stream::wave_header::{Channels, SampleFormat, WavHeader};
 use wave_stream::{read_wav_from_file_path, write_wav_to_file_path};
 
+mod ambience;
+mod ambisonics;
+mod binaural;
+mod cd4;
+mod decorrelation;
+mod dialog;
+mod downmix;
+mod headroom;
+mod input_conditioning;
 mod logger;
+mod loudness;
+mod lr_delay;
 mod matrix;
+mod multiband;
+mod noise_floor;
 mod options;
 mod panner_and_writer;
 mod panning_averager;
 mod reader;
 mod structs;
+mod transients;
 mod upmixer;
+mod vbap;
 mod vecdeque_ext;
 mod window_sizes;
 
 use upmixer::upmix;
 
+use crate::downmix::DownmixVerifier;
+use crate::headroom::Finisher;
 use crate::options::Options;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -66,7 +83,7 @@ fn main() {
 
     let header = WavHeader {
         sample_format: SampleFormat::Float,
-        channels: options.channels.clone(),
+        channels: options.target_channels(),
         sample_rate: source_wav.sample_rate(),
     };
 
@@ -110,37 +127,30 @@ fn main() {
             );
 
             let target_wav_path = folder.join(target_wav_filename_string);
+            target_paths.push(target_wav_path);
+        }
+    } else {
+        target_paths.push(options.target_wav_path.to_path_buf());
+    }
 
-            let open_target_wav_result = write_wav_to_file_path(&target_wav_path, header);
+    let written_paths: Vec<PathBuf> = target_paths
+        .iter()
+        .map(|target_path| written_path(&options, target_path))
+        .collect();
 
-            let target_wav = match open_target_wav_result {
+    // When upmixing in multiple bands, each band is written separately and then added together
+    if options.multiband.is_none() {
+        for (target_path, written_path) in target_paths.iter().zip(&written_paths) {
+            let target_wav = match write_wav_to_file_path(written_path, header) {
                 Err(error) => {
-                    println!("Can not open {}: {:?}", &target_wav_path.display(), error);
+                    println!("Can not open {}: {:?}", target_path.display(), error);
                     return;
                 }
                 Ok(target_wav) => target_wav,
             };
 
             target_open_wav_writers.push(target_wav);
-            target_paths.push(target_wav_path);
         }
-    } else {
-        let open_target_wav_result = write_wav_to_file_path(&options.target_wav_path, header);
-
-        let target_wav = match open_target_wav_result {
-            Err(error) => {
-                println!(
-                    "Can not open {}: {:?}",
-                    &options.target_wav_path.display(),
-                    error
-                );
-                return;
-            }
-            Ok(target_wav) => target_wav,
-        };
-
-        target_open_wav_writers.push(target_wav);
-        target_paths.push(options.target_wav_path.to_path_buf());
     }
 
     let length_seconds = (source_wav.len_samples() as f64) / (source_wav.sample_rate() as f64);
@@ -154,7 +164,7 @@ fn main() {
         println!("\tTarget: {}", target_paths[0].display());
     } else {
         println!("\tTargets:");
-        for target_path in target_paths {
+        for target_path in &target_paths {
             println!("\t\t{}", target_path.display());
         }
     }
@@ -187,14 +197,60 @@ fn main() {
         None
     };
 
-    match upmix(options, source_wav, target_open_wav_writers) {
+    let downmix_verifier = DownmixVerifier::new(&options);
+
+    let finisher = Finisher::new(&options);
+
+    let upmix_result = if options.multiband.is_some() {
+        multiband::upmix(options, source_wav, header, &written_paths)
+    } else {
+        upmix(options, source_wav, target_open_wav_writers)
+    };
+
+    match upmix_result {
         Err(error) => {
             println!("Error upmixing: {:?}", error);
+            return;
         }
         _ => {
             println!("Upmixing completed successfully");
         }
     }
 
+    let mut gain = 1.0;
+    if let Some(finisher) = finisher {
+        let unfinished_paths: Vec<PathBuf> = target_paths
+            .iter()
+            .map(|target_path| headroom::unfinished_path(target_path))
+            .collect();
+
+        match finisher.finish(header, &unfinished_paths, &target_paths) {
+            Ok(finished_gain) => gain = finished_gain,
+            Err(error) => {
+                println!("Error finishing the upmix: {:?}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
     _keepawake = None;
+
+    if let Some(downmix_verifier) = downmix_verifier {
+        match downmix_verifier.verify(&target_paths, gain) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(error) => {
+                println!("Error verifying the downmix: {:?}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn written_path(options: &Options, target_path: &Path) -> PathBuf {
+    if headroom::rewrites_upmix(options) {
+        headroom::unfinished_path(target_path)
+    } else {
+        target_path.to_path_buf()
+    }
 }
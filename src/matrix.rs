@@ -1,13 +1,17 @@
 use std::{
     cell::Cell,
     f64::consts::{PI, TAU},
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
 };
 
 const HALF_PI: f64 = PI / 2.0;
 
 use rustfft::num_complex::Complex;
+use serde::Deserialize;
 
-use crate::structs::FrequencyPans;
+use crate::{ambisonics::BFormat, structs::FrequencyPans};
 
 // When derriving a center channel:
 // An amplitude of 1 in the center is equivalent to 0.707 (square root of 0.5) in both speakers
@@ -38,6 +42,34 @@ pub trait Matrix {
     fn amplitude_adjustment(&self) -> f64;
 
     fn steer_right_left(&self) -> bool;
+
+    // Matrixes that encode Ambisonic B-format, instead of discrete speakers, return the decoded B-format
+    fn decode_b_format(&self, _left: Complex<f64>, _right: Complex<f64>) -> Option<BFormat> {
+        None
+    }
+
+    // How the left front, right front, left rear, and right rear are encoded into (left total, right total)
+    // Used to fold an upmix back to stereo. By default, each side's phase shifts are undone
+    fn encoding(&self) -> [(Complex<f64>, Complex<f64>); 4] {
+        let mut left_front_phase = 0.0;
+        let mut right_front_phase = 0.0;
+        let mut left_rear_phase = 0.0;
+        let mut right_rear_phase = 0.0;
+        self.phase_shift(
+            &mut left_front_phase,
+            &mut right_front_phase,
+            &mut left_rear_phase,
+            &mut right_rear_phase,
+        );
+
+        let zero = Complex { re: 0.0, im: 0.0 };
+        [
+            (Complex::from_polar(1.0, -left_front_phase), zero),
+            (zero, Complex::from_polar(1.0, -right_front_phase)),
+            (Complex::from_polar(1.0, -left_rear_phase), zero),
+            (zero, Complex::from_polar(1.0, -right_rear_phase)),
+        ]
+    }
 }
 
 pub struct DefaultMatrix {
@@ -45,6 +77,36 @@ pub struct DefaultMatrix {
     left_rear_shift: f64,
     right_rear_shift: f64,
     rear_adjustment: f64,
+    rear_amount: f64,
+    rear_curve: RearCurve,
+}
+
+// Maps the phase difference (0 is in phase, 1 is out of phase) to how much is steered to the rear
+#[derive(Clone, Copy)]
+pub enum RearCurve {
+    Linear,
+    // Higher exponents keep more of the nearly in-phase sounds in front
+    Power(f64),
+    // Sounds below the threshold stay in front, sounds above it go to the rear
+    Sigmoid(f64),
+}
+
+// How sharply the sigmoid curve transitions at its threshold
+const SIGMOID_STEEPNESS: f64 = 12.0;
+
+impl RearCurve {
+    fn apply(&self, phase_ratio: f64) -> f64 {
+        match self {
+            RearCurve::Linear => phase_ratio,
+            RearCurve::Power(exponent) => phase_ratio.powf(*exponent),
+            RearCurve::Sigmoid(threshold) => {
+                let sigmoid = |x: f64| 1.0 / (1.0 + (-SIGMOID_STEEPNESS * (x - threshold)).exp());
+
+                // Scaled so that in phase is still fully front, and out of phase is still fully rear
+                (sigmoid(phase_ratio) - sigmoid(0.0)) / (sigmoid(1.0) - sigmoid(0.0))
+            }
+        }
+    }
 }
 
 // Note that it is intended that DefaultMatrix can be configured to support the old quad matrixes
@@ -55,6 +117,8 @@ impl DefaultMatrix {
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            rear_amount: 1.0,
+            rear_curve: RearCurve::Linear,
         }
     }
 
@@ -67,6 +131,8 @@ impl DefaultMatrix {
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            rear_amount: 1.0,
+            rear_curve: RearCurve::Linear,
         }
     }
 
@@ -76,6 +142,8 @@ impl DefaultMatrix {
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            rear_amount: 1.0,
+            rear_curve: RearCurve::Linear,
         }
     }
 
@@ -85,8 +153,17 @@ impl DefaultMatrix {
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 2.0f64.sqrt(),
+            rear_amount: 1.0,
+            rear_curve: RearCurve::Linear,
         }
     }
+
+    // Changes how strongly out-of-phase sounds are steered to the rear
+    pub fn rear_steering(mut self, rear_amount: f64, rear_curve: RearCurve) -> DefaultMatrix {
+        self.rear_amount = rear_amount;
+        self.rear_curve = rear_curve;
+        self
+    }
 }
 
 impl Matrix for DefaultMatrix {
@@ -109,7 +186,8 @@ impl Matrix for DefaultMatrix {
         };
 
         // phase ratio: 0 is in phase, 1 is out of phase
-        let back_to_front_from_phase = phase_difference_pi / PI;
+        let phase_ratio = phase_difference_pi / PI;
+        let back_to_front_from_phase = self.rear_amount * self.rear_curve.apply(phase_ratio);
 
         let amplitude_sum = left_amplitude + right_amplitude;
 
@@ -180,6 +258,34 @@ const SQ_RAISE: f64 = 1.0 / 0.7;
 const SQ_LEFT_REAR_SHIFT: f64 = PI / 2.0;
 const SQ_RIGHT_REAR_SHIFT: f64 = SQ_LEFT_REAR_SHIFT * -1.0;
 
+// The SQ encoder: Each rear channel goes into both sides, shifted by 90 degrees on its own side
+fn sq_encoding() -> [(Complex<f64>, Complex<f64>); 4] {
+    [
+        (Complex { re: 1.0, im: 0.0 }, Complex { re: 0.0, im: 0.0 }),
+        (Complex { re: 0.0, im: 0.0 }, Complex { re: 1.0, im: 0.0 }),
+        (
+            Complex {
+                re: 0.0,
+                im: -CENTER_AMPLITUDE_ADJUSTMENT,
+            },
+            Complex {
+                re: -CENTER_AMPLITUDE_ADJUSTMENT,
+                im: 0.0,
+            },
+        ),
+        (
+            Complex {
+                re: CENTER_AMPLITUDE_ADJUSTMENT,
+                im: 0.0,
+            },
+            Complex {
+                re: 0.0,
+                im: CENTER_AMPLITUDE_ADJUSTMENT,
+            },
+        ),
+    ]
+}
+
 // Uses the Soft Matrix approach of closely inspecting phase and amplitude, but it doesn't work very well
 pub struct SQMatrix {}
 
@@ -356,6 +462,10 @@ impl Matrix for SQMatrix {
         shift_in_place(right_rear_phase, SQ_RIGHT_REAR_SHIFT);
     }
 
+    fn encoding(&self) -> [(Complex<f64>, Complex<f64>); 4] {
+        sq_encoding()
+    }
+
     fn print_debugging_information(&self) {}
 
     fn amplitude_adjustment(&self) -> f64 {
@@ -516,6 +626,10 @@ impl Matrix for SQMatrixExperimental {
         shift_in_place(right_rear_phase, SQ_RIGHT_REAR_SHIFT);
     }
 
+    fn encoding(&self) -> [(Complex<f64>, Complex<f64>); 4] {
+        sq_encoding()
+    }
+
     fn print_debugging_information(&self) {
         /*
         println!();
@@ -538,6 +652,556 @@ impl Matrix for SQMatrixExperimental {
     }
 }
 
+// https://en.wikipedia.org/wiki/Ambisonic_UHJ_format
+// Two-channel UHJ is decoded to horizontal B-format (W, X, Y.) Steering comes from the direction of the
+// decoded sound, based on the intensity of X and Y relative to W
+const UHJ_W_FROM_S: f64 = 0.982;
+const UHJ_W_FROM_D: f64 = 0.164;
+const UHJ_X_FROM_S: f64 = 0.419;
+const UHJ_X_FROM_D: f64 = -0.828;
+const UHJ_Y_FROM_D: f64 = 0.763;
+const UHJ_Y_FROM_S: f64 = 0.385;
+
+// The UHJ decoding equations produce W at -3db (FuMa)
+const UHJ_W_TO_PRESSURE: f64 = std::f64::consts::SQRT_2;
+
+pub struct UHJMatrix {}
+
+impl UHJMatrix {
+    pub fn uhj() -> UHJMatrix {
+        UHJMatrix {}
+    }
+
+    // Decodes to FuMa W, X, and Y. j is a 90 degree phase shift
+    fn decode(
+        &self,
+        left: Complex<f64>,
+        right: Complex<f64>,
+    ) -> (Complex<f64>, Complex<f64>, Complex<f64>) {
+        let j = Complex { re: 0.0, im: 1.0 };
+
+        let s = (left + right) / 2.0;
+        let d = (left - right) / 2.0;
+
+        let w = s * UHJ_W_FROM_S + j * d * UHJ_W_FROM_D;
+        let x = s * UHJ_X_FROM_S + j * d * UHJ_X_FROM_D;
+        let y = d * UHJ_Y_FROM_D + j * s * UHJ_Y_FROM_S;
+
+        (w, x, y)
+    }
+}
+
+impl Matrix for UHJMatrix {
+    fn steer(
+        &self,
+        left_amplitude: f64,
+        left_phase: f64,
+        right_amplitude: f64,
+        right_phase: f64,
+    ) -> FrequencyPans {
+        let left = Complex::from_polar(left_amplitude, left_phase);
+        let right = Complex::from_polar(right_amplitude, right_phase);
+
+        let (w, x, y) = self.decode(left, right);
+
+        // Active intensity: The direction that the sound comes from
+        let intensity_front = (w * x.conj()).re;
+        let intensity_left = (w * y.conj()).re;
+
+        let amplitude = w.norm() * UHJ_W_TO_PRESSURE;
+
+        if intensity_front == 0.0 && intensity_left == 0.0 {
+            return FrequencyPans {
+                amplitude,
+                left_to_right: 0.0,
+                back_to_front: 0.0,
+            };
+        }
+
+        FrequencyPans::from_azimuth(amplitude, intensity_left.atan2(intensity_front))
+    }
+
+    fn phase_shift(
+        &self,
+        _left_front_phase: &mut f64,
+        _right_front_phase: &mut f64,
+        _left_rear_phase: &mut f64,
+        _right_rear_phase: &mut f64,
+    ) {
+    }
+
+    fn print_debugging_information(&self) {}
+
+    fn amplitude_adjustment(&self) -> f64 {
+        CENTER_AMPLITUDE_ADJUSTMENT
+    }
+
+    // UHJ's left-right panning is partially phase-based, so left and right come from the decoded direction
+    fn steer_right_left(&self) -> bool {
+        true
+    }
+
+    fn decode_b_format(&self, left: Complex<f64>, right: Complex<f64>) -> Option<BFormat> {
+        let (w, x, y) = self.decode(left, right);
+
+        Some(BFormat {
+            w: w * UHJ_W_TO_PRESSURE,
+            x,
+            y,
+            z: Complex { re: 0.0, im: 0.0 },
+        })
+    }
+}
+
+// Matrixes that are defined by their encoding coefficients: Each position around the listener is encoded
+// into left and right total with a complex (amplitude and phase) coefficient
+// See https://en.wikipedia.org/wiki/Matrix_decoder
+//
+// Decoding finds the direction whose encoding most resembles each frequency. Left and right total are
+// compared as a point on the Scheiber sphere, where each encoded position is also a point, and sounds
+// panned between two positions lie on the arc between them
+pub struct EncodedPosition {
+    // 0 is front, positive is left, in radians
+    pub azimuth: f64,
+    pub left: Complex<f64>,
+    pub right: Complex<f64>,
+}
+
+// A matrix file: Each position is in degrees (0 is front, positive is left), and is encoded into left and
+// right total with an amplitude and a phase in degrees
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatrixFile {
+    position: Vec<MatrixFilePosition>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatrixFilePosition {
+    azimuth: f64,
+    left_amplitude: f64,
+    #[serde(default)]
+    left_phase: f64,
+    right_amplitude: f64,
+    #[serde(default)]
+    right_phase: f64,
+}
+
+// Each pan between two encoded positions is split into this many arcs, so that positions on opposite sides
+// of the Scheiber sphere (such as left front and right front) can still be decoded
+const ARCS_PER_PAN: usize = 2;
+
+// Arcs that are this close to a point are equally close
+const OVERLAPPING_ARC_TOLERANCE: f64 = 1e-9;
+
+// Part of a pan between two encoded positions
+struct Arc {
+    start: [f64; 3],
+    end: [f64; 3],
+    // The plane of the arc
+    normal: [f64; 3],
+    length: f64,
+    // Index of the position that the pan starts at
+    position_ctr: usize,
+    start_fraction: f64,
+}
+
+pub struct CoefficientMatrix {
+    // Sorted by azimuth
+    positions: Vec<EncodedPosition>,
+    arcs: Vec<Arc>,
+    left_front_shift: f64,
+    right_front_shift: f64,
+    left_rear_shift: f64,
+    right_rear_shift: f64,
+}
+
+impl CoefficientMatrix {
+    pub fn new(mut positions: Vec<EncodedPosition>) -> CoefficientMatrix {
+        for position in positions.iter_mut() {
+            bring_phase_in_range(&mut position.azimuth);
+        }
+
+        positions.sort_by(|a, b| a.azimuth.total_cmp(&b.azimuth));
+
+        let mut arcs = Vec::with_capacity(positions.len() * ARCS_PER_PAN);
+        for position_ctr in 0..positions.len() {
+            for arc_ctr in 0..ARCS_PER_PAN {
+                let start_fraction = arc_ctr as f64 / ARCS_PER_PAN as f64;
+                let end_fraction = (arc_ctr + 1) as f64 / ARCS_PER_PAN as f64;
+
+                let (start_left, start_right) = pan(&positions, position_ctr, start_fraction);
+                let (end_left, end_right) = pan(&positions, position_ctr, end_fraction);
+
+                let start = scheiber_point(start_left, start_right);
+                let end = scheiber_point(end_left, end_right);
+                let normal = normalize(cross(start, end));
+
+                arcs.push(Arc {
+                    start,
+                    end,
+                    normal,
+                    length: dot(start, end).clamp(-1.0, 1.0).acos(),
+                    position_ctr,
+                    start_fraction,
+                });
+            }
+        }
+
+        // Phase shifts bring each speaker back in phase with the sound that was encoded
+        let left_front = nearest_position(&positions, PI / 4.0);
+        let right_front = nearest_position(&positions, PI / -4.0);
+        let left_rear = nearest_position(&positions, 3.0 * PI / 4.0);
+        let right_rear = nearest_position(&positions, 3.0 * PI / -4.0);
+
+        CoefficientMatrix {
+            left_front_shift: -left_front.left.arg(),
+            right_front_shift: -right_front.right.arg(),
+            left_rear_shift: -left_rear.left.arg(),
+            right_rear_shift: -right_rear.right.arg(),
+            positions,
+            arcs,
+        }
+    }
+
+    pub fn open(path: &Path) -> Result<CoefficientMatrix> {
+        let matrix_file: MatrixFile = match toml::from_str(&fs::read_to_string(path)?) {
+            Ok(matrix_file) => matrix_file,
+            Err(error) => return Err(Error::new(ErrorKind::InvalidData, error.to_string())),
+        };
+
+        if matrix_file.position.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "A matrix needs at least two positions",
+            ));
+        }
+
+        let mut positions = Vec::with_capacity(matrix_file.position.len());
+        for position in matrix_file.position {
+            if position.left_amplitude == 0.0 && position.right_amplitude == 0.0 {
+                let error = format!(
+                    "The position at {} degrees is not encoded into left or right",
+                    position.azimuth
+                );
+                return Err(Error::new(ErrorKind::InvalidData, error));
+            }
+
+            positions.push(EncodedPosition {
+                azimuth: position.azimuth.to_radians(),
+                left: Complex::from_polar(
+                    position.left_amplitude,
+                    position.left_phase.to_radians(),
+                ),
+                right: Complex::from_polar(
+                    position.right_amplitude,
+                    position.right_phase.to_radians(),
+                ),
+            });
+        }
+
+        Ok(CoefficientMatrix::new(positions))
+    }
+
+    // Electro-Voice Stereo-4
+    pub fn ev4() -> CoefficientMatrix {
+        CoefficientMatrix::quad((1.0, 0.3), (0.3, 1.0), (1.0, -0.5), (-0.5, 1.0))
+    }
+
+    // Dynaco Dynaquad
+    pub fn dynaquad() -> CoefficientMatrix {
+        CoefficientMatrix::quad((1.0, 0.25), (0.25, 1.0), (1.0, -0.8), (-0.8, 1.0))
+    }
+
+    // Passive Hafler: The rear speakers both play the difference between left and right, so the rear
+    // is a single position
+    pub fn hafler() -> CoefficientMatrix {
+        CoefficientMatrix::new(vec![
+            EncodedPosition {
+                azimuth: PI / 4.0,
+                left: Complex { re: 1.0, im: 0.0 },
+                right: Complex { re: 0.0, im: 0.0 },
+            },
+            EncodedPosition {
+                azimuth: PI / -4.0,
+                left: Complex { re: 0.0, im: 0.0 },
+                right: Complex { re: 1.0, im: 0.0 },
+            },
+            EncodedPosition {
+                azimuth: PI,
+                left: Complex {
+                    re: CENTER_AMPLITUDE_ADJUSTMENT,
+                    im: 0.0,
+                },
+                right: Complex {
+                    re: -CENTER_AMPLITUDE_ADJUSTMENT,
+                    im: 0.0,
+                },
+            },
+        ])
+    }
+
+    // Encodes a direction by panning between the two positions around it
+    fn encode(&self, azimuth: f64) -> (Complex<f64>, Complex<f64>) {
+        for position_ctr in 0..self.positions.len() {
+            let start_azimuth = self.positions[position_ctr].azimuth;
+            let end_azimuth = self.positions[(position_ctr + 1) % self.positions.len()].azimuth;
+
+            let mut pan_width = end_azimuth - start_azimuth;
+            if pan_width <= 0.0 {
+                pan_width += TAU;
+            }
+
+            let mut offset = azimuth - start_azimuth;
+            if offset < 0.0 {
+                offset += TAU;
+            }
+
+            if offset <= pan_width {
+                return pan(&self.positions, position_ctr, offset / pan_width);
+            }
+        }
+
+        let position = nearest_position(&self.positions, azimuth);
+        (position.left, position.right)
+    }
+
+    // Dolby Stereo, decoded by direction instead of by phase: Sounds between the front and the surround
+    // are steered to the sides
+    pub fn dolby_wide() -> CoefficientMatrix {
+        CoefficientMatrix::new(vec![
+            EncodedPosition {
+                azimuth: PI / 4.0,
+                left: Complex { re: 1.0, im: 0.0 },
+                right: Complex { re: 0.0, im: 0.0 },
+            },
+            EncodedPosition {
+                azimuth: PI / -4.0,
+                left: Complex { re: 0.0, im: 0.0 },
+                right: Complex { re: 1.0, im: 0.0 },
+            },
+            EncodedPosition {
+                azimuth: PI,
+                left: Complex {
+                    re: 0.0,
+                    im: CENTER_AMPLITUDE_ADJUSTMENT,
+                },
+                right: Complex {
+                    re: 0.0,
+                    im: -CENTER_AMPLITUDE_ADJUSTMENT,
+                },
+            },
+        ])
+    }
+
+    // Four corners, each is (left total, right total)
+    fn quad(
+        left_front: (f64, f64),
+        right_front: (f64, f64),
+        left_rear: (f64, f64),
+        right_rear: (f64, f64),
+    ) -> CoefficientMatrix {
+        let corners = [
+            (PI / 4.0, left_front),
+            (PI / -4.0, right_front),
+            (3.0 * PI / 4.0, left_rear),
+            (3.0 * PI / -4.0, right_rear),
+        ];
+
+        CoefficientMatrix::new(
+            corners
+                .iter()
+                .map(|(azimuth, (left, right))| EncodedPosition {
+                    azimuth: *azimuth,
+                    left: Complex { re: *left, im: 0.0 },
+                    right: Complex {
+                        re: *right,
+                        im: 0.0,
+                    },
+                })
+                .collect(),
+        )
+    }
+
+    // Finds the closest point on an arc: Returns how close it is (1 is on the arc), and how far along the arc it is
+    fn closest_on_arc(arc: &Arc, point: [f64; 3]) -> (f64, f64) {
+        let distance_from_plane = dot(point, arc.normal);
+        let in_plane = [
+            point[0] - (distance_from_plane * arc.normal[0]),
+            point[1] - (distance_from_plane * arc.normal[1]),
+            point[2] - (distance_from_plane * arc.normal[2]),
+        ];
+
+        let angle = dot(cross(arc.start, in_plane), arc.normal).atan2(dot(arc.start, in_plane));
+
+        if angle <= 0.0 || arc.length == 0.0 {
+            (dot(point, arc.start), 0.0)
+        } else if angle >= arc.length {
+            (dot(point, arc.end), 1.0)
+        } else {
+            let closeness = (1.0 - distance_from_plane.powi(2)).max(0.0).sqrt();
+            (closeness, angle / arc.length)
+        }
+    }
+}
+
+impl Matrix for CoefficientMatrix {
+    fn steer(
+        &self,
+        left_amplitude: f64,
+        left_phase: f64,
+        right_amplitude: f64,
+        right_phase: f64,
+    ) -> FrequencyPans {
+        let power = left_amplitude.powi(2) + right_amplitude.powi(2);
+
+        if power == 0.0 {
+            return FrequencyPans {
+                amplitude: 0.0,
+                left_to_right: 0.0,
+                back_to_front: 0.0,
+            };
+        }
+
+        let point = scheiber_point(
+            Complex::from_polar(left_amplitude, left_phase),
+            Complex::from_polar(right_amplitude, right_phase),
+        );
+
+        let mut closest_arc = &self.arcs[0];
+        let mut closest = f64::NEG_INFINITY;
+        let mut fraction_in_arc = 0.0;
+        for arc in self.arcs.iter() {
+            let (closeness, fraction) = CoefficientMatrix::closest_on_arc(arc, point);
+
+            // Pans can overlap on the Scheiber sphere: (With real coefficients, a pan between the rear
+            // corners can pass through a front corner.) The shorter arc is the better guess
+            let closer = closeness > closest + OVERLAPPING_ARC_TOLERANCE
+                || (closeness > closest - OVERLAPPING_ARC_TOLERANCE
+                    && arc.length < closest_arc.length);
+
+            if closer {
+                closest = closeness;
+                closest_arc = arc;
+                fraction_in_arc = fraction;
+            }
+        }
+
+        let fraction = closest_arc.start_fraction + (fraction_in_arc / ARCS_PER_PAN as f64);
+        let position_ctr = closest_arc.position_ctr;
+        let start_azimuth = self.positions[position_ctr].azimuth;
+        let end_azimuth = self.positions[(position_ctr + 1) % self.positions.len()].azimuth;
+        let mut pan_width = end_azimuth - start_azimuth;
+        if pan_width <= 0.0 {
+            pan_width += TAU;
+        }
+
+        // The amplitude is constant-power, relative to how loud the direction is encoded
+        let (encoded_left, encoded_right) = pan(&self.positions, position_ctr, fraction);
+        let encoded_power = encoded_left.norm_sqr() + encoded_right.norm_sqr();
+        let amplitude = (power / encoded_power).sqrt();
+
+        FrequencyPans::from_azimuth(amplitude, start_azimuth + (fraction * pan_width))
+    }
+
+    fn phase_shift(
+        &self,
+        left_front_phase: &mut f64,
+        right_front_phase: &mut f64,
+        left_rear_phase: &mut f64,
+        right_rear_phase: &mut f64,
+    ) {
+        shift_in_place(left_front_phase, self.left_front_shift);
+        shift_in_place(right_front_phase, self.right_front_shift);
+        shift_in_place(left_rear_phase, self.left_rear_shift);
+        shift_in_place(right_rear_phase, self.right_rear_shift);
+    }
+
+    fn print_debugging_information(&self) {}
+
+    fn amplitude_adjustment(&self) -> f64 {
+        CENTER_AMPLITUDE_ADJUSTMENT
+    }
+
+    // Left and right come from the decoded direction
+    fn steer_right_left(&self) -> bool {
+        true
+    }
+
+    fn encoding(&self) -> [(Complex<f64>, Complex<f64>); 4] {
+        [PI / 4.0, PI / -4.0, 3.0 * PI / 4.0, 3.0 * PI / -4.0].map(|azimuth| self.encode(azimuth))
+    }
+}
+
+// Constant-power pan from a position to the next position
+fn pan(
+    positions: &[EncodedPosition],
+    position_ctr: usize,
+    fraction: f64,
+) -> (Complex<f64>, Complex<f64>) {
+    let start = &positions[position_ctr];
+    let end = &positions[(position_ctr + 1) % positions.len()];
+
+    let start_amplitude = (fraction * HALF_PI).cos();
+    let end_amplitude = (fraction * HALF_PI).sin();
+
+    (
+        (start.left * start_amplitude) + (end.left * end_amplitude),
+        (start.right * start_amplitude) + (end.right * end_amplitude),
+    )
+}
+
+fn nearest_position(positions: &[EncodedPosition], azimuth: f64) -> &EncodedPosition {
+    let distance = |position: &EncodedPosition| {
+        let mut difference = position.azimuth - azimuth;
+        bring_phase_in_range(&mut difference);
+        difference.abs()
+    };
+
+    positions
+        .iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .expect("A matrix needs at least one position")
+}
+
+// Left and right as a point on the Scheiber sphere: Left, right, in phase, out of phase, and quadrature are
+// at right angles
+fn scheiber_point(left: Complex<f64>, right: Complex<f64>) -> [f64; 3] {
+    let power = left.norm_sqr() + right.norm_sqr();
+    if power == 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+
+    let correlation = left * right.conj();
+
+    [
+        (left.norm_sqr() - right.norm_sqr()) / power,
+        2.0 * correlation.re / power,
+        2.0 * correlation.im / power,
+    ]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    (a[0] * b[0]) + (a[1] * b[1]) + (a[2] * b[2])
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        (a[1] * b[2]) - (a[2] * b[1]),
+        (a[2] * b[0]) - (a[0] * b[2]),
+        (a[0] * b[1]) - (a[1] * b[0]),
+    ]
+}
+
+fn normalize(a: [f64; 3]) -> [f64; 3] {
+    let length = dot(a, a).sqrt();
+    if length == 0.0 {
+        a
+    } else {
+        [a[0] / length, a[1] / length, a[2] / length]
+    }
+}
+
 fn shift(phase: f64, shift: f64) -> f64 {
     let mut phase_mut = phase;
     shift_in_place(&mut phase_mut, shift);
@@ -556,3 +1220,86 @@ fn bring_phase_in_range(phase: &mut f64) {
         *phase += TAU;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each encoded direction, and the pans it should steer to: (azimuth, left_to_right, back_to_front)
+    const CENTER: (f64, f64, f64) = (0.0, 0.0, 0.0);
+    const LEFT_FRONT: (f64, f64, f64) = (PI / 4.0, -1.0, 0.0);
+    const RIGHT_FRONT: (f64, f64, f64) = (PI / -4.0, 1.0, 0.0);
+    const LEFT_REAR: (f64, f64, f64) = (3.0 * PI / 4.0, -1.0, 1.0);
+    const RIGHT_REAR: (f64, f64, f64) = (3.0 * PI / -4.0, 1.0, 1.0);
+    const REAR: (f64, f64, f64) = (PI, 0.0, 1.0);
+
+    const TOLERANCE: f64 = 0.01;
+
+    fn assert_steers_encoded(
+        name: &str,
+        matrix: &CoefficientMatrix,
+        directions: &[(f64, f64, f64)],
+    ) {
+        for (azimuth, left_to_right, back_to_front) in directions {
+            let (left, right) = matrix.encode(*azimuth);
+            let (left_amplitude, left_phase) = left.to_polar();
+            let (right_amplitude, right_phase) = right.to_polar();
+
+            let frequency_pans =
+                matrix.steer(left_amplitude, left_phase, right_amplitude, right_phase);
+
+            assert!(
+                (frequency_pans.left_to_right - left_to_right).abs() < TOLERANCE,
+                "{} at {} degrees: left_to_right is {}, expected {}",
+                name,
+                azimuth.to_degrees(),
+                frequency_pans.left_to_right,
+                left_to_right
+            );
+            assert!(
+                (frequency_pans.back_to_front - back_to_front).abs() < TOLERANCE,
+                "{} at {} degrees: back_to_front is {}, expected {}",
+                name,
+                azimuth.to_degrees(),
+                frequency_pans.back_to_front,
+                back_to_front
+            );
+        }
+    }
+
+    #[test]
+    fn ev4_steers_encoded_directions() {
+        assert_steers_encoded(
+            "ev4",
+            &CoefficientMatrix::ev4(),
+            &[CENTER, LEFT_FRONT, RIGHT_FRONT, LEFT_REAR, RIGHT_REAR],
+        );
+    }
+
+    #[test]
+    fn dynaquad_steers_encoded_directions() {
+        assert_steers_encoded(
+            "dynaquad",
+            &CoefficientMatrix::dynaquad(),
+            &[CENTER, LEFT_FRONT, RIGHT_FRONT, LEFT_REAR, RIGHT_REAR],
+        );
+    }
+
+    #[test]
+    fn hafler_steers_encoded_directions() {
+        assert_steers_encoded(
+            "hafler",
+            &CoefficientMatrix::hafler(),
+            &[CENTER, LEFT_FRONT, RIGHT_FRONT, REAR],
+        );
+    }
+
+    #[test]
+    fn dolby_wide_steers_encoded_directions() {
+        assert_steers_encoded(
+            "dolby_wide",
+            &CoefficientMatrix::dolby_wide(),
+            &[CENTER, LEFT_FRONT, RIGHT_FRONT, REAR],
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    f64::consts::PI,
+    fs::remove_file,
+    io::{Error, ErrorKind, Read, Result, Seek},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use wave_stream::{
+    open_wav::OpenWav,
+    read_wav_from_file_path,
+    samples_by_channel::SamplesByChannel,
+    wave_header::{Channels, SampleFormat, WavHeader},
+    wave_reader::{OpenWavReader, StreamOpenWavReader},
+    write_wav_to_file_path,
+};
+
+use crate::{
+    lr_delay::DelayCorrection,
+    noise_floor::NoiseFloor,
+    options::{Multiband, Options},
+    upmixer::{upmix_band, window_size},
+};
+
+// Automatic bands are two octaves wide, and there are at most this many
+const AUTO_BAND_WIDTH: f64 = 4.0;
+const AUTO_MAXIMUM_BANDS: usize = 4;
+
+// Each crossover's transition is this fraction of the lowest crossover frequency
+const TRANSITION: f64 = 0.5;
+
+// The length of a Blackman-windowed sinc, in samples, is this divided by the transition, (as a fraction of the
+// sample rate)
+const BLACKMAN_LENGTH: f64 = 5.5;
+
+// Upmixes in multiple bands: The source is split with crossovers that add back up to the source, each band is
+// upmixed with a window suited to its lowest frequency, and then the upmixed bands are added together
+//
+// The upmix of each band is written next to the written paths, and removed once they are added together
+pub fn upmix<TReader: 'static + Read + Seek>(
+    options: Options,
+    source_wav_reader: OpenWavReader<TReader>,
+    header: WavHeader,
+    written_paths: &[PathBuf],
+) -> Result<()> {
+    let sample_rate = source_wav_reader.sample_rate() as f64;
+    let low_frequencies = band_low_frequencies(
+        options.low_frequency as f64,
+        options.multiband.as_ref(),
+        sample_rate,
+    )?;
+    if low_frequencies.len() == 1 {
+        println!("The lowest frequency is too high to split into bands");
+
+        let mut target_open_wav_writers = Vec::with_capacity(written_paths.len());
+        for written_path in written_paths {
+            target_open_wav_writers.push(write_wav_to_file_path(written_path, header)?);
+        }
+
+        let window_size = window_size(&options, options.low_frequency, &source_wav_reader)?;
+        let noise_floor = NoiseFloor::estimate(&options, window_size)?;
+        let delay_correction = DelayCorrection::new(&options)?;
+
+        return upmix_band(
+            Rc::new(options),
+            window_size,
+            noise_floor,
+            delay_correction,
+            source_wav_reader,
+            target_open_wav_writers,
+        );
+    }
+
+    let result = upmix_bands(
+        options,
+        source_wav_reader,
+        header,
+        written_paths,
+        &low_frequencies,
+    );
+
+    // Bands aren't left next to the target when upmixing fails
+    if result.is_err() {
+        for written_path in written_paths {
+            for band_ctr in 0..low_frequencies.len() {
+                for purpose in ["source", "upmix"] {
+                    let _ = remove_file(band_path(written_path, band_ctr, purpose));
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn upmix_bands<TReader: 'static + Read + Seek>(
+    options: Options,
+    source_wav_reader: OpenWavReader<TReader>,
+    header: WavHeader,
+    written_paths: &[PathBuf],
+    low_frequencies: &[f64],
+) -> Result<()> {
+    // Every band's window is checked before any band is upmixed
+    let mut window_sizes = Vec::with_capacity(low_frequencies.len());
+    for (band_ctr, low_frequency) in low_frequencies.iter().enumerate() {
+        print_band(low_frequencies, band_ctr);
+        window_sizes.push(window_size(
+            &options,
+            *low_frequency as f32,
+            &source_wav_reader,
+        )?);
+    }
+
+    let band_source_paths: Vec<PathBuf> = (0..low_frequencies.len())
+        .map(|band_ctr| band_path(&written_paths[0], band_ctr, "source"))
+        .collect();
+
+    println!();
+    println!("Splitting into {} bands...", low_frequencies.len());
+    split(source_wav_reader, &low_frequencies[1..], &band_source_paths)?;
+
+    // The delay is the same in every band, and the noise floor is measured with the lowest band's window
+    let delay_correction = DelayCorrection::new(&options)?;
+    let noise_floor = NoiseFloor::estimate(&options, window_sizes[0])?;
+
+    let options = Rc::new(options);
+    for (band_ctr, window_size) in window_sizes.into_iter().enumerate() {
+        println!();
+        print_band(low_frequencies, band_ctr);
+
+        let mut target_open_wav_writers = Vec::with_capacity(written_paths.len());
+        for written_path in written_paths {
+            target_open_wav_writers.push(write_wav_to_file_path(
+                &band_path(written_path, band_ctr, "upmix"),
+                header,
+            )?);
+        }
+
+        upmix_band(
+            options.clone(),
+            window_size,
+            noise_floor
+                .as_ref()
+                .map(|noise_floor| noise_floor.resize(window_size)),
+            delay_correction.clone(),
+            read_wav_from_file_path(&band_source_paths[band_ctr])?,
+            target_open_wav_writers,
+        )?;
+
+        remove_file(&band_source_paths[band_ctr])?;
+    }
+
+    println!();
+    println!("Adding the bands together...");
+
+    for written_path in written_paths {
+        let band_upmix_paths: Vec<PathBuf> = (0..low_frequencies.len())
+            .map(|band_ctr| band_path(written_path, band_ctr, "upmix"))
+            .collect();
+
+        add_bands(header, &band_upmix_paths, written_path)?;
+
+        for band_upmix_path in band_upmix_paths {
+            remove_file(band_upmix_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn print_band(low_frequencies: &[f64], band_ctr: usize) {
+    match low_frequencies.get(band_ctr + 1) {
+        Some(high_frequency) => println!(
+            "Band {}: {}hz to {}hz",
+            band_ctr + 1,
+            low_frequencies[band_ctr],
+            high_frequency
+        ),
+        None => println!(
+            "Band {}: {}hz and up",
+            band_ctr + 1,
+            low_frequencies[band_ctr]
+        ),
+    }
+}
+
+// The lowest frequency of each band, starting with the lowest frequency that is steered
+fn band_low_frequencies(
+    low_frequency: f64,
+    multiband: Option<&Multiband>,
+    sample_rate: f64,
+) -> Result<Vec<f64>> {
+    // See upmixer.rs: The lowest frequency needs a window of at least 8 samples
+    let max_low_frequency = sample_rate / 8.0;
+
+    let mut low_frequencies = vec![low_frequency];
+    match multiband {
+        Some(Multiband::Crossovers(crossovers)) => {
+            for crossover in crossovers {
+                if *crossover >= max_low_frequency {
+                    let error = format!(
+                        "Crossover {}hz is too high. Maximum crossover for {} samples / second is {}",
+                        crossover, sample_rate, max_low_frequency
+                    );
+                    return Err(Error::new(ErrorKind::InvalidInput, error));
+                }
+
+                low_frequencies.push(*crossover);
+            }
+        }
+        _ => {
+            let mut crossover = low_frequency * AUTO_BAND_WIDTH;
+            while crossover < max_low_frequency && low_frequencies.len() < AUTO_MAXIMUM_BANDS {
+                low_frequencies.push(crossover);
+                crossover *= AUTO_BAND_WIDTH;
+            }
+        }
+    }
+
+    Ok(low_frequencies)
+}
+
+fn band_path(written_path: &Path, band_ctr: usize, purpose: &str) -> PathBuf {
+    written_path.with_extension(format!("band{}.{}.wav", band_ctr + 1, purpose))
+}
+
+// Splits the source into bands at the crossovers. Each crossover is a linear-phase lowpass, and each band is the
+// difference between the lowpasses above and below it, so the bands add up to the source
+fn split<TReader: 'static + Read + Seek>(
+    source_wav_reader: OpenWavReader<TReader>,
+    crossovers: &[f64],
+    band_paths: &[PathBuf],
+) -> Result<()> {
+    let sample_rate = source_wav_reader.sample_rate();
+    let len_samples = source_wav_reader.len_samples();
+
+    let filters = band_filters(crossovers, sample_rate as f64);
+    let filter_length = filters[0].len();
+    let delay = filter_length / 2;
+
+    // Filters are applied via overlap-save: Each block is transformed with the end of the previous block
+    let transform_length = (filter_length * 2).next_power_of_two();
+    let block_length = transform_length - filter_length + 1;
+
+    let mut planner: FftPlanner<f64> = FftPlanner::new();
+    let fft_forward = planner.plan_fft_forward(transform_length);
+    let fft_inverse = planner.plan_fft_inverse(transform_length);
+
+    // rustfft doesn't normalize, so the scale is included in each filter
+    let scale = 1.0 / transform_length as f64;
+    let filters_transformed: Vec<Vec<Complex<f64>>> = filters
+        .iter()
+        .map(|filter| {
+            let mut filter_transformed = vec![Complex { re: 0.0, im: 0.0 }; transform_length];
+            for (tap, filter_transformed) in filter.iter().zip(filter_transformed.iter_mut()) {
+                filter_transformed.re = tap * scale;
+            }
+
+            fft_forward.process(&mut filter_transformed);
+            filter_transformed
+        })
+        .collect();
+
+    let header = WavHeader {
+        sample_format: SampleFormat::Float,
+        channels: Channels::new().front_left().front_right(),
+        sample_rate,
+    };
+
+    if len_samples > header.max_samples() {
+        let error = format!(
+            "The source is too long to split into bands, {} samples; maximum {} samples",
+            len_samples,
+            header.max_samples()
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    let mut writers = Vec::with_capacity(band_paths.len());
+    for band_path in band_paths {
+        writers.push(write_wav_to_file_path(band_path, header)?.get_random_access_f32_writer()?);
+    }
+
+    let mut source_samples = source_wav_reader.get_stream_f32_reader()?.into_iter();
+
+    let mut left_buffer = vec![Complex { re: 0.0, im: 0.0 }; transform_length];
+    let mut right_buffer = vec![Complex { re: 0.0, im: 0.0 }; transform_length];
+
+    // The filters delay the bands, so the first samples that are filtered are skipped
+    let mut filtered_sample_ctr = 0;
+    while filtered_sample_ctr < len_samples + delay {
+        for sample_ctr in (filter_length - 1)..transform_length {
+            // After the end of the source, it's padded with silence
+            let (left, right) = match source_samples.next() {
+                Some(samples) => {
+                    let samples = samples?;
+                    (
+                        samples.front_left.unwrap_or(0.0) as f64,
+                        samples.front_right.unwrap_or(0.0) as f64,
+                    )
+                }
+                None => (0.0, 0.0),
+            };
+
+            left_buffer[sample_ctr] = Complex { re: left, im: 0.0 };
+            right_buffer[sample_ctr] = Complex { re: right, im: 0.0 };
+        }
+
+        let mut left_transformed = left_buffer.clone();
+        let mut right_transformed = right_buffer.clone();
+        fft_forward.process(&mut left_transformed);
+        fft_forward.process(&mut right_transformed);
+
+        for (filter_transformed, writer) in filters_transformed.iter().zip(writers.iter_mut()) {
+            let mut left_band: Vec<Complex<f64>> = left_transformed
+                .iter()
+                .zip(filter_transformed)
+                .map(|(sample, filter)| sample * filter)
+                .collect();
+            let mut right_band: Vec<Complex<f64>> = right_transformed
+                .iter()
+                .zip(filter_transformed)
+                .map(|(sample, filter)| sample * filter)
+                .collect();
+            fft_inverse.process(&mut left_band);
+            fft_inverse.process(&mut right_band);
+
+            for block_ctr in 0..block_length {
+                let filtered_ctr = filtered_sample_ctr + block_ctr;
+                if filtered_ctr < delay || filtered_ctr >= len_samples + delay {
+                    continue;
+                }
+
+                let band_ctr = filter_length - 1 + block_ctr;
+                writer.write_samples(
+                    filtered_ctr - delay,
+                    SamplesByChannel::new()
+                        .front_left(left_band[band_ctr].re as f32)
+                        .front_right(right_band[band_ctr].re as f32),
+                )?;
+            }
+        }
+
+        // The end of this block is the start of the next block
+        left_buffer.copy_within(block_length.., 0);
+        right_buffer.copy_within(block_length.., 0);
+
+        filtered_sample_ctr += block_length;
+    }
+
+    for mut writer in writers {
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+// The impulse response of each band. All bands have the same length, and add up to a delayed impulse
+fn band_filters(crossovers: &[f64], sample_rate: f64) -> Vec<Vec<f64>> {
+    let transition = crossovers[0] * TRANSITION / sample_rate;
+    let filter_length = ((BLACKMAN_LENGTH / transition).ceil() as usize) | 1;
+    let delay = filter_length / 2;
+
+    let lowpasses: Vec<Vec<f64>> = crossovers
+        .iter()
+        .map(|crossover| lowpass(crossover / sample_rate, filter_length))
+        .collect();
+
+    let mut impulse = vec![0.0; filter_length];
+    impulse[delay] = 1.0;
+
+    let mut filters = Vec::with_capacity(crossovers.len() + 1);
+    let mut below = vec![0.0; filter_length];
+    for above in lowpasses.iter().chain(std::iter::once(&impulse)) {
+        filters.push(above.iter().zip(&below).map(|(a, b)| a - b).collect());
+        below = above.clone();
+    }
+
+    filters
+}
+
+// A Blackman-windowed sinc lowpass, with unity gain at DC. The cutoff is a fraction of the sample rate
+fn lowpass(cutoff: f64, filter_length: usize) -> Vec<f64> {
+    let delay = (filter_length / 2) as f64;
+    let mut taps: Vec<f64> = (0..filter_length)
+        .map(|tap_ctr| {
+            let offset = tap_ctr as f64 - delay;
+            let sinc = if offset == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * PI * cutoff * offset).sin() / (PI * offset)
+            };
+
+            let phase = 2.0 * PI * tap_ctr as f64 / (filter_length - 1) as f64;
+            let blackman = 0.42 - (0.5 * phase.cos()) + (0.08 * (2.0 * phase).cos());
+
+            sinc * blackman
+        })
+        .collect();
+
+    let sum: f64 = taps.iter().sum();
+    for tap in taps.iter_mut() {
+        *tap /= sum;
+    }
+
+    taps
+}
+
+// Adds the upmix of each band into the written path
+fn add_bands(header: WavHeader, band_upmix_paths: &[PathBuf], written_path: &Path) -> Result<()> {
+    let mut band_samples = Vec::with_capacity(band_upmix_paths.len());
+    for band_upmix_path in band_upmix_paths {
+        band_samples.push(
+            read_wav_from_file_path(band_upmix_path)?
+                .get_stream_f32_reader()?
+                .into_iter(),
+        );
+    }
+
+    let mut writer =
+        write_wav_to_file_path(written_path, header)?.get_random_access_f32_writer()?;
+
+    let mut sample_ctr = 0;
+    'add: loop {
+        let mut sum: Option<SamplesByChannel<f32>> = None;
+        for samples in band_samples.iter_mut() {
+            let samples = match samples.next() {
+                Some(samples) => samples?,
+                None => break 'add,
+            };
+
+            sum = Some(match sum {
+                Some(sum) => add(&sum, &samples),
+                None => samples,
+            });
+        }
+
+        if let Some(sum) = sum {
+            writer.write_samples(sample_ctr, sum)?;
+            sample_ctr += 1;
+        }
+    }
+
+    writer.flush()
+}
+
+fn add(a: &SamplesByChannel<f32>, b: &SamplesByChannel<f32>) -> SamplesByChannel<f32> {
+    let add_sample = |a: Option<f32>, b: Option<f32>| a.map(|a| a + b.unwrap_or(0.0));
+
+    SamplesByChannel {
+        front_left: add_sample(a.front_left, b.front_left),
+        front_right: add_sample(a.front_right, b.front_right),
+        front_center: add_sample(a.front_center, b.front_center),
+        low_frequency: add_sample(a.low_frequency, b.low_frequency),
+        back_left: add_sample(a.back_left, b.back_left),
+        back_right: add_sample(a.back_right, b.back_right),
+        front_left_of_center: add_sample(a.front_left_of_center, b.front_left_of_center),
+        front_right_of_center: add_sample(a.front_right_of_center, b.front_right_of_center),
+        back_center: add_sample(a.back_center, b.back_center),
+        side_left: add_sample(a.side_left, b.side_left),
+        side_right: add_sample(a.side_right, b.side_right),
+        top_center: add_sample(a.top_center, b.top_center),
+        top_front_left: add_sample(a.top_front_left, b.top_front_left),
+        top_front_center: add_sample(a.top_front_center, b.top_front_center),
+        top_front_right: add_sample(a.top_front_right, b.top_front_right),
+        top_back_left: add_sample(a.top_back_left, b.top_back_left),
+        top_back_center: add_sample(a.top_back_center, b.top_back_center),
+        top_back_right: add_sample(a.top_back_right, b.top_back_right),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn band_filters_add_up_to_a_delayed_impulse() {
+        let filters = band_filters(&[80.0, 320.0, 1280.0], 44100.0);
+        assert_eq!(filters.len(), 4);
+
+        let filter_length = filters[0].len();
+        let delay = filter_length / 2;
+        for tap_ctr in 0..filter_length {
+            let sum: f64 = filters.iter().map(|filter| filter[tap_ctr]).sum();
+            let expected = if tap_ctr == delay { 1.0 } else { 0.0 };
+            assert!((sum - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn auto_bands_are_two_octaves_wide() {
+        assert_eq!(
+            band_low_frequencies(20.0, Some(&Multiband::Auto), 44100.0).unwrap(),
+            vec![20.0, 80.0, 320.0, 1280.0]
+        );
+
+        // Bands stop below an eighth of the sample rate, (5512.5hz)
+        assert_eq!(
+            band_low_frequencies(1000.0, Some(&Multiband::Auto), 44100.0).unwrap(),
+            vec![1000.0, 4000.0]
+        );
+    }
+
+    #[test]
+    fn crossovers_are_band_low_frequencies() {
+        assert_eq!(
+            band_low_frequencies(
+                20.0,
+                Some(&Multiband::Crossovers(vec![200.0, 2000.0])),
+                44100.0
+            )
+            .unwrap(),
+            vec![20.0, 200.0, 2000.0]
+        );
+
+        assert!(band_low_frequencies(
+            20.0,
+            Some(&Multiband::Crossovers(vec![200.0, 6000.0])),
+            44100.0
+        )
+        .is_err());
+    }
+}
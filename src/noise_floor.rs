@@ -0,0 +1,419 @@
+use std::{
+    collections::VecDeque,
+    f64::consts::SQRT_2,
+    fs::File,
+    io::{BufWriter, Result, Write},
+};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use wave_stream::{open_wav::OpenWav, read_wav_from_file_path, wave_reader::StreamOpenWavReader};
+
+use crate::{
+    input_conditioning::InputConditioner,
+    options::{amplitude_to_db, db_to_amplitude, Options},
+};
+
+// The noise floor is the minimum of the smoothed power of each frequency, see Rainer Martin, "Noise Power Spectral
+// Density Estimation Based on Optimal Smoothing and Minimum Statistics"
+// Minimums are tracked in segments; the floor of a segment is the minimum of it and its neighbors
+const SEGMENT_SECONDS: f64 = 1.5;
+const SMOOTHING_SECONDS: f64 = 0.2;
+
+// The minimum of smoothed noise is lower than its average, about 2db for this much smoothing
+const BIAS: f64 = 1.6;
+
+// Center frequencies of the octave bands that are printed
+const BANDS: [f64; 10] = [
+    31.5, 63.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+// The noise floor of each frequency over time, found by reading the source before upmixing
+//
+// In a multiband upmix, the floor is measured once with the lowest band's window, and resized for the other bands
+pub struct NoiseFloor {
+    window_size: usize,
+    segment_samples: usize,
+
+    // The minimum amplitude to steer front-to-back, for each segment and each frequency
+    thresholds: Vec<Vec<f32>>,
+}
+
+impl NoiseFloor {
+    pub fn estimate(options: &Options, window_size: usize) -> Result<Option<NoiseFloor>> {
+        let threshold = match options.noise_floor {
+            Some(threshold) => threshold,
+            None => return Ok(None),
+        };
+
+        println!("Estimating the noise floor...");
+
+        let source_wav = read_wav_from_file_path(&options.source_wav_path)?;
+        let sample_rate = source_wav.sample_rate() as usize;
+
+        // Reads the source the same way as the reader
+        let headroom = db_to_amplitude(options.headroom.unwrap_or(0.0)) as f64;
+        let mut input_conditioner = InputConditioner::new(&options.input_conditioning, sample_rate);
+        let samples = source_wav
+            .get_stream_f32_reader()?
+            .into_iter()
+            .map(|samples| {
+                let samples = samples?;
+                let (left, right) = input_conditioner.condition(
+                    samples.front_left.expect("front_left missing when reading") as f64,
+                    samples
+                        .front_right
+                        .expect("front_right missing when reading") as f64,
+                );
+                Ok((left * headroom, right * headroom))
+            });
+
+        let (floors, segment_samples) = measure_floors(samples, window_size, sample_rate)?;
+        let noise_floor = NoiseFloor::from_floors(window_size, segment_samples, &floors, threshold);
+
+        // The typical floor, in db relative to a full-scale sine wave
+        let window_midpoint = window_size / 2;
+        let full_scale = headroom * window_size as f64 / 2.0;
+        let median_floors: Vec<f32> = (0..window_midpoint)
+            .map(|bin_ctr| {
+                let mut powers: Vec<f64> = floors.iter().map(|floor| floor[bin_ctr]).collect();
+                powers.sort_by(f64::total_cmp);
+                amplitude_to_db((powers[powers.len() / 2].sqrt() / full_scale) as f32)
+            })
+            .collect();
+
+        let frequency =
+            |bin_ctr: usize| (bin_ctr + 1) as f64 * sample_rate as f64 / window_size as f64;
+
+        println!("Noise floor:");
+        for band in BANDS {
+            let band_floors: Vec<f32> = (0..window_midpoint)
+                .filter(|bin_ctr| {
+                    let frequency = frequency(*bin_ctr);
+                    frequency >= band / SQRT_2 && frequency < band * SQRT_2
+                })
+                .map(|bin_ctr| median_floors[bin_ctr])
+                .collect();
+
+            if !band_floors.is_empty() {
+                let band_floor = band_floors.iter().sum::<f32>() / band_floors.len() as f32;
+                println!("\t{:>7}hz: {:>6.1}db", band, band_floor);
+            }
+        }
+
+        if let Some(noise_floor_csv_path) = &options.noise_floor_csv_path {
+            let mut csv = BufWriter::new(File::create(noise_floor_csv_path)?);
+            writeln!(csv, "frequency,floor_db")?;
+            for (bin_ctr, median_floor) in median_floors.iter().enumerate() {
+                writeln!(csv, "{:.2},{:.2}", frequency(bin_ctr), median_floor)?;
+            }
+            csv.flush()?;
+
+            println!(
+                "Wrote the noise floor to {}",
+                noise_floor_csv_path.display()
+            );
+        }
+
+        Ok(Some(noise_floor))
+    }
+
+    // The threshold is in db, relative to the floor
+    fn from_floors(
+        window_size: usize,
+        segment_samples: usize,
+        floors: &[Vec<f64>],
+        threshold: f64,
+    ) -> NoiseFloor {
+        let threshold = db_to_amplitude(threshold as f32) as f64;
+        NoiseFloor {
+            window_size,
+            segment_samples,
+            thresholds: floors
+                .iter()
+                .map(|floor| {
+                    floor
+                        .iter()
+                        .map(|power| (power.sqrt() * threshold) as f32)
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    // The noise floor for a different window size: Each frequency uses the floor of the nearest measured frequency.
+    // The amplitude of noise in a frequency grows with the square root of the window size
+    pub fn resize(&self, window_size: usize) -> NoiseFloor {
+        let measured_window_midpoint = self.window_size / 2;
+        let frequency_ratio = self.window_size as f64 / window_size as f64;
+        let scale = (window_size as f64 / self.window_size as f64).sqrt() as f32;
+
+        NoiseFloor {
+            window_size,
+            segment_samples: self.segment_samples,
+            thresholds: self
+                .thresholds
+                .iter()
+                .map(|thresholds| {
+                    (1..(window_size / 2 + 1))
+                        .map(|freq_ctr| {
+                            let measured_freq_ctr = ((freq_ctr as f64 * frequency_ratio).round()
+                                as usize)
+                                .clamp(1, measured_window_midpoint);
+                            thresholds[measured_freq_ctr - 1] * scale
+                        })
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    // The minimum amplitude to steer front-to-back, for the window that ends with last_sample_ctr
+    pub fn threshold(&self, last_sample_ctr: usize, freq_ctr: usize) -> f64 {
+        let segment_ctr = (last_sample_ctr.saturating_sub(self.window_size - 1)
+            / self.segment_samples)
+            .min(self.thresholds.len() - 1);
+
+        self.thresholds[segment_ctr][freq_ctr - 1] as f64
+    }
+}
+
+// Measures the floor of each segment, in power, for each frequency. The windows overlap by half. Returns the floors
+// and the number of samples in each segment
+fn measure_floors(
+    samples: impl Iterator<Item = Result<(f64, f64)>>,
+    window_size: usize,
+    sample_rate: usize,
+) -> Result<(Vec<Vec<f64>>, usize)> {
+    let window_midpoint = window_size / 2;
+    let frames_per_segment = ((SEGMENT_SECONDS * sample_rate as f64) / window_midpoint as f64)
+        .round()
+        .max(1.0) as usize;
+    let smoothing = (-(window_midpoint as f64) / (SMOOTHING_SECONDS * sample_rate as f64)).exp();
+
+    let mut planner = FftPlanner::new();
+    let fft_forward = planner.plan_fft_forward(window_size);
+
+    let mut left_buffer = VecDeque::with_capacity(window_size);
+    let mut right_buffer = VecDeque::with_capacity(window_size);
+    let mut scratch = vec![Complex::default(); fft_forward.get_inplace_scratch_len()];
+
+    let mut smoothed = vec![0.0f64; window_midpoint];
+    let mut segment_minimums = vec![f64::INFINITY; window_midpoint];
+    let mut segments = Vec::new();
+    let mut frame_ctr = 0;
+
+    for samples in samples {
+        let (left, right) = samples?;
+        left_buffer.push_back(Complex { re: left, im: 0.0 });
+        right_buffer.push_back(Complex { re: right, im: 0.0 });
+
+        if left_buffer.len() < window_size {
+            continue;
+        }
+
+        let mut left_transformed = left_buffer.make_contiguous().to_vec();
+        let mut right_transformed = right_buffer.make_contiguous().to_vec();
+        fft_forward.process_with_scratch(&mut left_transformed, &mut scratch);
+        fft_forward.process_with_scratch(&mut right_transformed, &mut scratch);
+
+        for freq_ctr in 1..(window_midpoint + 1) {
+            let power = (left_transformed[freq_ctr].norm_sqr()
+                + right_transformed[freq_ctr].norm_sqr())
+                / 2.0;
+
+            let smoothed_power = &mut smoothed[freq_ctr - 1];
+            *smoothed_power = if frame_ctr == 0 {
+                power
+            } else {
+                (smoothing * *smoothed_power) + ((1.0 - smoothing) * power)
+            };
+
+            let segment_minimum = &mut segment_minimums[freq_ctr - 1];
+            *segment_minimum = segment_minimum.min(*smoothed_power);
+        }
+
+        frame_ctr += 1;
+        if frame_ctr % frames_per_segment == 0 {
+            segments.push(segment_minimums);
+            segment_minimums = vec![f64::INFINITY; window_midpoint];
+        }
+
+        left_buffer.drain(..window_midpoint);
+        right_buffer.drain(..window_midpoint);
+    }
+
+    if frame_ctr % frames_per_segment > 0 || segments.is_empty() {
+        segments.push(segment_minimums);
+    }
+
+    // The floor of each segment is the minimum of it and its neighbors
+    let floors = (0..segments.len())
+        .map(|segment_ctr| {
+            let first = segment_ctr.saturating_sub(1);
+            let last = (segment_ctr + 1).min(segments.len() - 1);
+            (0..window_midpoint)
+                .map(|bin_ctr| {
+                    let minimum = segments[first..=last]
+                        .iter()
+                        .map(|segment| segment[bin_ctr])
+                        .fold(f64::INFINITY, f64::min);
+
+                    // When the source is shorter than a window there are no measurements
+                    if minimum.is_finite() {
+                        minimum * BIAS
+                    } else {
+                        0.0
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok((floors, frames_per_segment * window_midpoint))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    const SAMPLE_RATE: usize = 8000;
+    const WINDOW_SIZE: usize = 256;
+
+    // A small linear congruential generator, so the noise is the same every time
+    struct Random(u64);
+
+    impl Random {
+        // Uniform between -0.5 and 0.5: The power is 1 / 12
+        fn next(&mut self) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 11) as f64 / (1u64 << 53) as f64) - 0.5
+        }
+    }
+
+    // Stereo white noise, in segments of (amplitude, seconds)
+    fn noise(segments: &[(f64, f64)]) -> Vec<(f64, f64)> {
+        let mut random = Random(1);
+        segments
+            .iter()
+            .flat_map(|(amplitude, seconds)| {
+                (0..(seconds * SAMPLE_RATE as f64) as usize)
+                    .map(|_| (amplitude * random.next(), amplitude * random.next()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn noise_floor(samples: &[(f64, f64)], window_size: usize) -> NoiseFloor {
+        let (floors, segment_samples) = measure_floors(
+            samples.iter().map(|samples| Ok(*samples)),
+            window_size,
+            SAMPLE_RATE,
+        )
+        .unwrap();
+        NoiseFloor::from_floors(window_size, segment_samples, &floors, 0.0)
+    }
+
+    fn db(amplitude: f64) -> f64 {
+        20.0 * amplitude.log10()
+    }
+
+    // The median, in db, of how far each frequency's threshold is from the expected threshold
+    fn median_error_db(
+        noise_floor: &NoiseFloor,
+        last_sample_ctr: usize,
+        expected: impl Fn(usize) -> f64,
+    ) -> f64 {
+        let mut errors: Vec<f64> = (1..(noise_floor.window_size / 2 + 1))
+            .map(|freq_ctr| {
+                db(noise_floor.threshold(last_sample_ctr, freq_ctr)) - db(expected(freq_ctr))
+            })
+            .collect();
+        errors.sort_by(f64::total_cmp);
+        errors[errors.len() / 2]
+    }
+
+    #[test]
+    fn stationary_noise_floor_is_its_level() {
+        let amplitude = 0.01;
+        let noise_floor = noise_floor(&noise(&[(amplitude, 6.0)]), WINDOW_SIZE);
+
+        // The power of white noise in each frequency is the power of each sample, times the window size
+        let expected = (amplitude * amplitude / 12.0 * WINDOW_SIZE as f64).sqrt();
+
+        for last_sample_ctr in [WINDOW_SIZE - 1, 3 * SAMPLE_RATE, 6 * SAMPLE_RATE - 1] {
+            let error_db = median_error_db(&noise_floor, last_sample_ctr, |_| expected);
+            assert!(
+                error_db.abs() < 2.0,
+                "at sample {}, the floor is {}db from the noise",
+                last_sample_ctr,
+                error_db
+            );
+        }
+    }
+
+    #[test]
+    fn threshold_follows_the_floor_of_each_segment() {
+        let quiet = 0.001;
+        let loud = 0.01;
+        let noise_floor = noise_floor(&noise(&[(quiet, 6.0), (loud, 6.0)]), WINDOW_SIZE);
+
+        let expected = |amplitude: f64| (amplitude * amplitude / 12.0 * WINDOW_SIZE as f64).sqrt();
+
+        // Each segment's floor includes its neighbors, so the segments near the change are quiet
+        for (seconds, amplitude) in [(1.0, quiet), (4.0, quiet), (10.0, loud), (11.5, loud)] {
+            let last_sample_ctr = (seconds * SAMPLE_RATE as f64) as usize;
+            let error_db = median_error_db(&noise_floor, last_sample_ctr, |_| expected(amplitude));
+            assert!(
+                error_db.abs() < 2.0,
+                "at {} seconds, the floor is {}db from the noise",
+                seconds,
+                error_db
+            );
+        }
+    }
+
+    #[test]
+    fn resized_floor_keeps_the_level_of_each_frequency() {
+        // Lowpassed noise, so the floor is different for each frequency
+        let amplitude = 0.01;
+        let mut left = 0.0;
+        let mut right = 0.0;
+        let samples: Vec<(f64, f64)> = noise(&[(amplitude, 6.0)])
+            .iter()
+            .map(|(left_sample, right_sample)| {
+                left = (0.9 * left) + (0.1 * left_sample);
+                right = (0.9 * right) + (0.1 * right_sample);
+                (left, right)
+            })
+            .collect();
+
+        let measured = noise_floor(&samples, WINDOW_SIZE);
+        let resized = measured.resize(4 * WINDOW_SIZE);
+        let last_sample_ctr = 3 * SAMPLE_RATE;
+
+        // Each measured frequency covers 4 resized frequencies, at twice the amplitude
+        for freq_ctr in [1, 10, 100, 128] {
+            assert_eq!(
+                resized.threshold(last_sample_ctr, 4 * freq_ctr),
+                2.0 * measured.threshold(last_sample_ctr, freq_ctr)
+            );
+        }
+
+        // The lowpass's gain at each frequency, applied to the white noise's level
+        let error_db = median_error_db(&resized, last_sample_ctr, |freq_ctr| {
+            let radians = 2.0 * PI * freq_ctr as f64 / (4 * WINDOW_SIZE) as f64;
+            let gain = 0.1 / Complex::new(1.0 - 0.9 * radians.cos(), 0.9 * radians.sin()).norm();
+            gain * (amplitude * amplitude / 12.0 * (4 * WINDOW_SIZE) as f64).sqrt()
+        });
+        assert!(
+            error_db.abs() < 2.0,
+            "the resized floor is {}db from the noise",
+            error_db
+        );
+    }
+}
@@ -4,22 +4,85 @@ use std::path::Path;
 use wave_stream::wave_header::Channels;
 
 use crate::{
-    matrix::{DefaultMatrix, Matrix, SQMatrix, SQMatrixExperimental},
+    binaural::HrtfSource,
+    matrix::{
+        CoefficientMatrix, DefaultMatrix, Matrix, RearCurve, SQMatrix, SQMatrixExperimental,
+        UHJMatrix,
+    },
     panner_and_writer,
 };
 
+// The names of the written channels, in the order of channel_adjustments
+const CHANNEL_NAMES: [&str; 6] = ["L", "R", "C", "LFE", "Ls", "Rs"];
+
+// The standard in-band gain of the LFE channel, in db
+const LFE_IN_BAND_GAIN: f64 = 10.0;
+
 pub struct Options {
     pub source_wav_path: Box<Path>,
     pub target_wav_path: Box<Path>,
     pub num_threads: Option<usize>,
     pub transform_mono: bool,
     pub channels: Channels,
+    // Write first-order Ambisonic B-format (AmbiX) instead of speaker channels
+    pub b_format: bool,
     pub low_frequency: f32,
     pub minimum_steered_amplitude: f32,
+    // Fixes mis-wired captures before steering
+    pub input_conditioning: InputConditioning,
+    // When set, the minimum amplitude to steer is this many db above the noise floor
+    pub noise_floor: Option<f64>,
+    pub noise_floor_csv_path: Option<Box<Path>>,
     pub keep_awake: bool,
     pub loud: bool,
     pub requested_fft_size: Option<usize>,
+    // Steers each band with a window suited to its lowest frequency
+    pub multiband: Option<Multiband>,
     pub headroom: Option<f32>,
+    // Normalizes the upmix to the true peak after it's written
+    pub headroom_auto: bool,
+    // Limits the upmix to the true peak after it's written
+    pub limit: bool,
+    // In dbTP
+    pub true_peak: f64,
+    // Reports the loudness of the source and the upmix
+    pub measure_loudness: bool,
+    // Normalizes the upmix to the integrated loudness after it's written, in LUFS
+    pub normalize_lufs: Option<f64>,
+    // Renders the channels to headphones
+    pub binaural: Option<HrtfSource>,
+    // CD-4 is demodulated instead of steered, the matrix is unused
+    pub cd4: bool,
+    pub lfe_crossover: f64,
+    pub lfe_slope: LfeSlope,
+    // Removes the bass below the crossover from the main channels, so it's only in the LFE
+    pub bass_management: bool,
+    // Left-right spread: Below 1 narrows, above 1 widens
+    pub width: f64,
+    // How each frequency's steering is smoothed over time
+    pub smoothing: Smoothing,
+    // When set, steering isn't averaged across onsets. The rise in the spectrum that is an onset, in db
+    pub transients: Option<f64>,
+    // How much of the uncorrelated sound is steered to the rear
+    pub ambience: f64,
+    // Randomizes the phase of the rear channels, 0 is off, 1 is fully random
+    pub decorrelation: f64,
+    // Haas delay for the rear channels, in milliseconds
+    pub rear_delay: f64,
+    pub center_mode: CenterMode,
+    // Pans each frequency between the speakers at their positions in the room, instead of between the corners of a square
+    pub vbap: bool,
+    // In degrees: 0 is front, positive is left, negative is right. In the same order as channel_adjustments
+    pub speaker_azimuths: [f64; 6],
+    pub pan_law: PanLaw,
+    // Trims, delays, and inverts each written channel: Front left, front right, center, LFE, rear left, rear right
+    pub channel_adjustments: [ChannelAdjustment; 6],
+    // Corrects a delay between left and right before steering
+    pub lr_delay: Option<LrDelay>,
+    // After upmixing, folds the upmix back to stereo and compares it with the source
+    pub verify_downmix: Option<DownmixMethod>,
+    // The run fails if the downmix residual is above this, in db
+    pub downmix_threshold: f64,
 
     // Performs additional adjustments according to the specific chosen matrix
     // SQ, QS, RM, ect
@@ -30,6 +93,83 @@ pub enum ChannelLayout {
     Four,
     Five,
     FiveOne,
+    AmbiX,
+}
+
+pub enum LfeSlope {
+    // Tapers from full at half of the crossover to silent at the crossover
+    Cosine,
+    // Linkwitz-Riley, 24db / octave, -6db at the crossover
+    Lr4,
+}
+
+pub enum Smoothing {
+    // A moving average over this many wavelengths of each frequency, centered on the window
+    Rectangular(f64),
+    // Follows rising amplitudes with the attack, and falling amplitudes with the release, in milliseconds
+    Exponential { attack: f64, release: f64 },
+    // Each window is steered on its own
+    None,
+}
+
+pub enum Multiband {
+    // Bands are two octaves wide, starting at the lowest frequency
+    Auto,
+    // The frequencies, in hz, where each band ends and the next band starts
+    Crossovers(Vec<f64>),
+}
+
+pub enum LrDelay {
+    // The delay is measured before upmixing
+    Auto,
+    // How late the right channel is, in samples. Negative when the left channel is late
+    Samples(f64),
+}
+
+pub enum CenterMode {
+    // Every center-panned frequency is steered to the center
+    Amplitude,
+    // Only voice-like frequencies in the speech band are steered to the center
+    Dialog,
+    // The component that is common to left and right is moved to the center, keeping its phase
+    Projection,
+}
+
+pub enum PanLaw {
+    // A sound between two speakers is lowered 3db
+    ConstantPower,
+    // A sound between two speakers is lowered 4.5db
+    Compromise,
+    // A sound between two speakers is lowered 6db
+    Linear,
+}
+
+#[derive(Clone, Copy)]
+pub struct ChannelAdjustment {
+    // In db
+    pub trim: f64,
+    // In milliseconds
+    pub delay: f64,
+    pub invert: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct InputConditioning {
+    pub invert_left: bool,
+    pub invert_right: bool,
+    pub swap: bool,
+    // In db: Positive moves the image to the right
+    pub balance: f64,
+    // The cutoff of the DC-blocking highpass, in hz
+    pub dc_block: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+pub enum DownmixMethod {
+    // ITU-R BS.775 coefficients
+    Itu,
+    // Undoes the selected matrix's phase shifts
+    Matrix,
 }
 
 pub enum MatrixFormat {
@@ -39,6 +179,13 @@ pub enum MatrixFormat {
     DolbyStereo,
     SQ,
     SQExperimental,
+    Uhj,
+    Ev4,
+    Dynaquad,
+    Hafler,
+    DolbyWide,
+    File(Box<Path>),
+    Cd4,
 }
 
 impl Options {
@@ -68,14 +215,70 @@ impl Options {
         let mut low_frequency = 20.0f32;
 
         let mut minimum_steered_amplitude = 0.01;
+        let mut minimum_specified = false;
+        let mut noise_floor = None;
+        let mut noise_floor_csv_path: Option<Box<Path>> = None;
+
+        let mut input_conditioning = InputConditioning {
+            invert_left: false,
+            invert_right: false,
+            swap: false,
+            balance: 0.0,
+            dc_block: None,
+        };
 
         let mut keep_awake = true;
 
         let mut loud: Option<bool> = None;
 
         let mut fft_size: Option<usize> = None;
+        let mut multiband = None;
 
         let mut headroom = Some(-24f32);
+        let mut headroom_auto = false;
+        let mut limit = false;
+        let mut true_peak = -1.0;
+        let mut measure_loudness = false;
+        let mut normalize_lufs = None;
+
+        let mut binaural = None;
+
+        let mut lfe_crossover = panner_and_writer::DEFAULT_LFE_CROSSOVER;
+        let mut lfe_slope = LfeSlope::Cosine;
+        let mut bass_management = false;
+
+        let mut width = 1.0;
+
+        let mut smoothing = Smoothing::Rectangular(1.0);
+        let mut transients = None;
+
+        let mut ambience = 0.0;
+
+        let mut decorrelation = 0.0;
+        let mut rear_delay = 0.0;
+
+        let mut center_mode = CenterMode::Amplitude;
+
+        let mut lr_delay = None;
+
+        let mut channel_adjustments = [ChannelAdjustment {
+            trim: 0.0,
+            delay: 0.0,
+            invert: false,
+        }; 6];
+        let mut lfe_gain = false;
+
+        // ITU-R BS.775: The front speakers are at 30 degrees, and the surrounds at 110 degrees
+        let mut speaker_azimuths = [30.0, -30.0, 0.0, 0.0, 110.0, -110.0];
+        let mut speakers_specified = false;
+        let mut pan_law = None;
+
+        let mut verify_downmix = None;
+        let mut downmix_threshold = -20.0;
+
+        let mut rear_amount = 1.0;
+        let mut rear_curve = RearCurve::Linear;
+        let mut rear_steering = false;
 
         // Iterate through the options
         // -channels
@@ -94,6 +297,12 @@ impl Options {
                                         return None;
                                     }
 
+                                    // Each window is mirrored around its midpoint, which needs an even size
+                                    if size % 2 != 0 {
+                                        println!("fft size must be even: {}", size);
+                                        return None;
+                                    }
+
                                     fft_size = Some(size)
                                 }
                                 Err(_) => {
@@ -108,6 +317,7 @@ impl Options {
                         }
                     } else if flag.eq("-headroom") {
                         match args_iter.next() {
+                            Some(f_size_string) if f_size_string.eq("auto") => headroom_auto = true,
                             Some(f_size_string) => match f_size_string.parse::<f32>() {
                                 Ok(head) => {
                                     println!("headroom: {}", head);
@@ -128,6 +338,68 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-true-peak") {
+                        match args_iter.next() {
+                            Some(true_peak_string) => match true_peak_string.parse::<f64>() {
+                                Ok(true_peak_value) => {
+                                    if true_peak_value > 0.0 {
+                                        println!("True peak must be <= 0: {}", true_peak_value);
+                                        return None;
+                                    }
+
+                                    true_peak = true_peak_value
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the true peak: {}", true_peak_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("True peak unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-limit") {
+                        limit = true
+                    } else if flag.eq("-loudness") {
+                        measure_loudness = true
+                    } else if flag.eq("-normalize-lufs") {
+                        match args_iter.next() {
+                            Some(lufs_string) => match lufs_string.parse::<f64>() {
+                                Ok(lufs) => {
+                                    if lufs >= 0.0 {
+                                        println!("Loudness must be < 0: {}", lufs);
+                                        return None;
+                                    }
+
+                                    normalize_lufs = Some(lufs)
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the loudness: {}", lufs_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Loudness unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-binaural") {
+                        match args_iter.next() {
+                            Some(hrtf_string) => {
+                                if hrtf_string.eq("builtin") {
+                                    binaural = Some(HrtfSource::BuiltIn)
+                                } else {
+                                    binaural = Some(HrtfSource::Sofa(
+                                        Path::new(hrtf_string.as_str()).into(),
+                                    ))
+                                }
+                            }
+                            None => {
+                                println!("HRTF unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-channels") {
                         match args_iter.next() {
                             Some(channels_string) => {
@@ -137,6 +409,8 @@ impl Options {
                                     channel_layout = ChannelLayout::Five
                                 } else if channels_string.eq("5.1") {
                                     channel_layout = ChannelLayout::FiveOne
+                                } else if channels_string.eq("ambix") {
+                                    channel_layout = ChannelLayout::AmbiX
                                 } else {
                                     println!("Unknown channel configuration: {}", channels_string);
                                     return None;
@@ -152,9 +426,9 @@ impl Options {
                             Some(matrix_format_string) => {
                                 if matrix_format_string.eq("default") {
                                     matrix_format = MatrixFormat::Default
-                                } else if matrix_format_string.eq("qs") {
-                                    matrix_format = MatrixFormat::QS
-                                } else if matrix_format_string.eq("rm") {
+                                } else if matrix_format_string.eq("qs")
+                                    || matrix_format_string.eq("rm")
+                                {
                                     matrix_format = MatrixFormat::QS
                                 } else if matrix_format_string.eq("horseshoe") {
                                     matrix_format = MatrixFormat::HorseShoe
@@ -164,6 +438,20 @@ impl Options {
                                     matrix_format = MatrixFormat::SQ
                                 } else if matrix_format_string.eq("sqexperimental") {
                                     matrix_format = MatrixFormat::SQExperimental
+                                } else if matrix_format_string.eq("uhj") {
+                                    matrix_format = MatrixFormat::Uhj
+                                } else if matrix_format_string.eq("ev4")
+                                    || matrix_format_string.eq("stereo4")
+                                {
+                                    matrix_format = MatrixFormat::Ev4
+                                } else if matrix_format_string.eq("dynaquad") {
+                                    matrix_format = MatrixFormat::Dynaquad
+                                } else if matrix_format_string.eq("hafler") {
+                                    matrix_format = MatrixFormat::Hafler
+                                } else if matrix_format_string.eq("dolbywide") {
+                                    matrix_format = MatrixFormat::DolbyWide
+                                } else if matrix_format_string.eq("cd4") {
+                                    matrix_format = MatrixFormat::Cd4
                                 } else {
                                     println!("Unknown matrix format: {}", matrix_format_string);
                                     return None;
@@ -174,6 +462,18 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-matrix-file") {
+                        match args_iter.next() {
+                            Some(matrix_file_string) => {
+                                matrix_format = MatrixFormat::File(
+                                    Path::new(matrix_file_string.as_str()).into(),
+                                )
+                            }
+                            None => {
+                                println!("Matrix file unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-low") {
                         match args_iter.next() {
                             Some(low_frequency_string) => {
@@ -203,6 +503,34 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-multiband") {
+                        match args_iter.next() {
+                            Some(multiband_string) => {
+                                if multiband_string.eq("auto") {
+                                    multiband = Some(Multiband::Auto)
+                                } else {
+                                    let mut crossovers = Vec::new();
+                                    for crossover_string in multiband_string.split(',') {
+                                        match crossover_string.parse::<f64>() {
+                                            Ok(crossover) => crossovers.push(crossover),
+                                            Err(_) => {
+                                                println!(
+                                                    "Can not parse the crossover: {}",
+                                                    crossover_string
+                                                );
+                                                return None;
+                                            }
+                                        }
+                                    }
+
+                                    multiband = Some(Multiband::Crossovers(crossovers))
+                                }
+                            }
+                            None => {
+                                println!("Crossovers unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-threads") {
                         match args_iter.next() {
                             Some(num_threads_string) => match num_threads_string.parse::<usize>() {
@@ -225,7 +553,8 @@ impl Options {
                             Some(minimum_steered_amplitude_string) => {
                                 match minimum_steered_amplitude_string.parse::<f32>() {
                                     Ok(minimum_steered_amplitude_value) => {
-                                        minimum_steered_amplitude = minimum_steered_amplitude_value
+                                        minimum_steered_amplitude = minimum_steered_amplitude_value;
+                                        minimum_specified = true;
                                     }
                                     Err(_) => {
                                         println!(
@@ -241,6 +570,212 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-noise-floor") {
+                        match args_iter.next() {
+                            Some(noise_floor_string) => match noise_floor_string.parse::<f64>() {
+                                Ok(noise_floor_value) => noise_floor = Some(noise_floor_value),
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the noise floor threshold: {}",
+                                        noise_floor_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Noise floor threshold unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-invert-left") {
+                        input_conditioning.invert_left = true
+                    } else if flag.eq("-invert-right") {
+                        input_conditioning.invert_right = true
+                    } else if flag.eq("-swap") {
+                        input_conditioning.swap = true
+                    } else if flag.eq("-balance") {
+                        match args_iter.next() {
+                            Some(balance_string) => match balance_string.parse::<f64>() {
+                                Ok(balance) => input_conditioning.balance = balance,
+                                Err(_) => {
+                                    println!("Can not parse the balance: {}", balance_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Balance unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-dc-block") {
+                        match args_iter.next() {
+                            Some(dc_block_string) => match dc_block_string.parse::<f64>() {
+                                Ok(frequency) => {
+                                    if frequency <= 0.0 {
+                                        println!(
+                                            "The DC block frequency must be > 0: {}",
+                                            frequency
+                                        );
+                                        return None;
+                                    }
+
+                                    input_conditioning.dc_block = Some(frequency)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the DC block frequency: {}",
+                                        dc_block_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("DC block frequency unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-trim") {
+                        match args_iter.next() {
+                            Some(trims_string) => {
+                                for trim_string in trims_string.split(',') {
+                                    let (channel_ctr, value_string) =
+                                        parse_channel_and_value(trim_string)?;
+                                    match value_string.parse::<f64>() {
+                                        Ok(trim) => channel_adjustments[channel_ctr].trim = trim,
+                                        Err(_) => {
+                                            println!("Can not parse the trim: {}", trim_string);
+                                            return None;
+                                        }
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Trim unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-delay") {
+                        match args_iter.next() {
+                            Some(delays_string) => {
+                                for delay_string in delays_string.split(',') {
+                                    let (channel_ctr, value_string) =
+                                        parse_channel_and_value(delay_string)?;
+                                    match value_string.trim_end_matches("ms").parse::<f64>() {
+                                        Ok(delay) => {
+                                            if delay < 0.0 {
+                                                println!("Delay must be >= 0: {}", delay_string);
+                                                return None;
+                                            }
+
+                                            channel_adjustments[channel_ctr].delay = delay
+                                        }
+                                        Err(_) => {
+                                            println!("Can not parse the delay: {}", delay_string);
+                                            return None;
+                                        }
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Delay unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-invert") {
+                        match args_iter.next() {
+                            Some(channels_string) => {
+                                for channel_string in channels_string.split(',') {
+                                    let channel_ctr = parse_channel(channel_string)?;
+                                    channel_adjustments[channel_ctr].invert = true;
+                                }
+                            }
+                            None => {
+                                println!("Channels to invert unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-speakers") {
+                        match args_iter.next() {
+                            Some(speakers_string) => {
+                                for speaker_string in speakers_string.split(',') {
+                                    let (channel_ctr, value_string) =
+                                        parse_channel_and_value(speaker_string)?;
+                                    if channel_ctr == 3 {
+                                        println!("The LFE doesn't have a position");
+                                        return None;
+                                    }
+
+                                    match value_string.parse::<f64>() {
+                                        Ok(azimuth) => speaker_azimuths[channel_ctr] = azimuth,
+                                        Err(_) => {
+                                            println!(
+                                                "Can not parse the speaker position: {}",
+                                                speaker_string
+                                            );
+                                            return None;
+                                        }
+                                    }
+                                }
+
+                                speakers_specified = true;
+                            }
+                            None => {
+                                println!("Speaker positions unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-pan-law") {
+                        match args_iter.next() {
+                            Some(pan_law_string) => {
+                                if pan_law_string.eq("constant-power") {
+                                    pan_law = Some(PanLaw::ConstantPower)
+                                } else if pan_law_string.eq("-4.5") {
+                                    pan_law = Some(PanLaw::Compromise)
+                                } else if pan_law_string.eq("linear") {
+                                    pan_law = Some(PanLaw::Linear)
+                                } else {
+                                    println!("Unknown pan law: {}", pan_law_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Pan law unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-gain") {
+                        lfe_gain = true
+                    } else if flag.eq("-lr-delay") {
+                        match args_iter.next() {
+                            Some(lr_delay_string) if lr_delay_string.eq("auto") => {
+                                lr_delay = Some(LrDelay::Auto)
+                            }
+                            Some(lr_delay_string) => match lr_delay_string.parse::<f64>() {
+                                Ok(samples) => lr_delay = Some(LrDelay::Samples(samples)),
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the left-right delay: {}",
+                                        lr_delay_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Left-right delay unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-noise-floor-csv") {
+                        match args_iter.next() {
+                            Some(csv_path_string) => {
+                                noise_floor_csv_path =
+                                    Some(Path::new(csv_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("Noise floor csv file unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-keepawake") {
                         match args_iter.next() {
                             Some(keep_awake_string) => match keep_awake_string.parse::<bool>() {
@@ -258,6 +793,409 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-lfe-crossover") {
+                        match args_iter.next() {
+                            Some(lfe_crossover_string) => {
+                                match lfe_crossover_string.parse::<f64>() {
+                                    Ok(lfe_crossover_value) => {
+                                        if lfe_crossover_value <= 0.0 {
+                                            println!(
+                                                "LFE crossover must be > 0: {}",
+                                                lfe_crossover_value
+                                            );
+                                            return None;
+                                        }
+
+                                        lfe_crossover = lfe_crossover_value
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the LFE crossover: {}",
+                                            lfe_crossover_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("LFE crossover unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-slope") {
+                        match args_iter.next() {
+                            Some(lfe_slope_string) => {
+                                if lfe_slope_string.eq("cosine") {
+                                    lfe_slope = LfeSlope::Cosine
+                                } else if lfe_slope_string.eq("lr4") {
+                                    lfe_slope = LfeSlope::Lr4
+                                } else {
+                                    println!("Unknown LFE slope: {}", lfe_slope_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("LFE slope unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-width") {
+                        match args_iter.next() {
+                            Some(width_string) => match width_string.parse::<f64>() {
+                                Ok(width_value) => {
+                                    if width_value < 0.0 {
+                                        println!("Width must be >= 0: {}", width_value);
+                                        return None;
+                                    }
+
+                                    width = width_value
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the width: {}", width_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Width unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-smoothing") {
+                        match args_iter.next() {
+                            Some(smoothing_string) => {
+                                if smoothing_string.eq("none") {
+                                    smoothing = Smoothing::None
+                                } else if smoothing_string.eq("rectangular") {
+                                    // Rectangular is followed by the number of wavelengths
+                                    match args_iter.next() {
+                                        Some(wavelengths_string) => {
+                                            match wavelengths_string.parse::<f64>() {
+                                                Ok(wavelengths) => {
+                                                    if wavelengths <= 0.0 {
+                                                        println!(
+                                                            "The rectangular smoothing's wavelengths must be > 0: {}",
+                                                            wavelengths
+                                                        );
+                                                        return None;
+                                                    }
+
+                                                    smoothing = Smoothing::Rectangular(wavelengths)
+                                                }
+                                                Err(_) => {
+                                                    println!(
+                                                        "Can not parse the rectangular smoothing's wavelengths: {}",
+                                                        wavelengths_string
+                                                    );
+                                                    return None;
+                                                }
+                                            }
+                                        }
+                                        None => {
+                                            println!(
+                                                "The rectangular smoothing's wavelengths are unspecified"
+                                            );
+                                            return None;
+                                        }
+                                    }
+                                } else if smoothing_string.eq("exponential") {
+                                    // Exponential is followed by the attack and the release
+                                    let mut times = [0.0; 2];
+                                    for (time, name) in times.iter_mut().zip(["attack", "release"])
+                                    {
+                                        match args_iter.next() {
+                                            Some(time_string) => match time_string.parse::<f64>() {
+                                                Ok(time_value) => {
+                                                    if time_value < 0.0 {
+                                                        println!(
+                                                                "The exponential smoothing's {} must be >= 0: {}",
+                                                                name, time_value
+                                                            );
+                                                        return None;
+                                                    }
+
+                                                    *time = time_value
+                                                }
+                                                Err(_) => {
+                                                    println!(
+                                                            "Can not parse the exponential smoothing's {}: {}",
+                                                            name, time_string
+                                                        );
+                                                    return None;
+                                                }
+                                            },
+                                            None => {
+                                                println!(
+                                                    "The exponential smoothing's {} is unspecified",
+                                                    name
+                                                );
+                                                return None;
+                                            }
+                                        }
+                                    }
+
+                                    smoothing = Smoothing::Exponential {
+                                        attack: times[0],
+                                        release: times[1],
+                                    }
+                                } else {
+                                    println!("Unknown smoothing: {}", smoothing_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Smoothing unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-transients") {
+                        match args_iter.next() {
+                            Some(transients_string) => match transients_string.parse::<f64>() {
+                                Ok(transients_value) => {
+                                    if transients_value <= 0.0 {
+                                        println!(
+                                            "The transient threshold must be > 0: {}",
+                                            transients_value
+                                        );
+                                        return None;
+                                    }
+
+                                    transients = Some(transients_value)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the transient threshold: {}",
+                                        transients_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Transient threshold unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-rear-amount") {
+                        match args_iter.next() {
+                            Some(rear_amount_string) => match rear_amount_string.parse::<f64>() {
+                                Ok(rear_amount_value) => {
+                                    if rear_amount_value < 0.0 {
+                                        println!("Rear amount must be >= 0: {}", rear_amount_value);
+                                        return None;
+                                    }
+
+                                    rear_amount = rear_amount_value;
+                                    rear_steering = true;
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the rear amount: {}",
+                                        rear_amount_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Rear amount unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-rear-curve") {
+                        match args_iter.next() {
+                            Some(rear_curve_string) => {
+                                if rear_curve_string.eq("linear") {
+                                    rear_curve = RearCurve::Linear
+                                } else if rear_curve_string.eq("power")
+                                    || rear_curve_string.eq("sigmoid")
+                                {
+                                    // Power is followed by the exponent, sigmoid is followed by the threshold
+                                    let parameter = match args_iter.next() {
+                                        Some(parameter_string) => {
+                                            match parameter_string.parse::<f64>() {
+                                                Ok(parameter) => parameter,
+                                                Err(_) => {
+                                                    println!(
+                                                        "Can not parse the {} rear curve's value: {}",
+                                                        rear_curve_string, parameter_string
+                                                    );
+                                                    return None;
+                                                }
+                                            }
+                                        }
+                                        None => {
+                                            println!(
+                                                "The {} rear curve's value is unspecified",
+                                                rear_curve_string
+                                            );
+                                            return None;
+                                        }
+                                    };
+
+                                    if rear_curve_string.eq("power") {
+                                        if parameter <= 0.0 {
+                                            println!(
+                                                "The power rear curve's exponent must be > 0: {}",
+                                                parameter
+                                            );
+                                            return None;
+                                        }
+
+                                        rear_curve = RearCurve::Power(parameter)
+                                    } else {
+                                        if !(0.0..=1.0).contains(&parameter) {
+                                            println!(
+                                                "The sigmoid rear curve's threshold must be between 0 and 1: {}",
+                                                parameter
+                                            );
+                                            return None;
+                                        }
+
+                                        rear_curve = RearCurve::Sigmoid(parameter)
+                                    }
+                                } else {
+                                    println!("Unknown rear curve: {}", rear_curve_string);
+                                    return None;
+                                }
+
+                                rear_steering = true;
+                            }
+                            None => {
+                                println!("Rear curve unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-ambience") {
+                        match args_iter.next() {
+                            Some(ambience_string) => match ambience_string.parse::<f64>() {
+                                Ok(ambience_value) => {
+                                    if !(0.0..=1.0).contains(&ambience_value) {
+                                        println!(
+                                            "Ambience must be between 0 and 1: {}",
+                                            ambience_value
+                                        );
+                                        return None;
+                                    }
+
+                                    ambience = ambience_value
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the ambience: {}", ambience_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Ambience unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-decorrelate") {
+                        match args_iter.next() {
+                            Some(decorrelation_string) => {
+                                match decorrelation_string.parse::<f64>() {
+                                    Ok(decorrelation_value) => {
+                                        if !(0.0..=1.0).contains(&decorrelation_value) {
+                                            println!(
+                                                "Decorrelation must be between 0 and 1: {}",
+                                                decorrelation_value
+                                            );
+                                            return None;
+                                        }
+
+                                        decorrelation = decorrelation_value
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the decorrelation: {}",
+                                            decorrelation_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Decorrelation unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-rear-delay") {
+                        match args_iter.next() {
+                            Some(rear_delay_string) => match rear_delay_string.parse::<f64>() {
+                                Ok(rear_delay_value) => {
+                                    if rear_delay_value < 0.0 {
+                                        println!("Rear delay must be >= 0: {}", rear_delay_value);
+                                        return None;
+                                    }
+
+                                    rear_delay = rear_delay_value
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the rear delay: {}", rear_delay_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Rear delay unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center") {
+                        match args_iter.next() {
+                            Some(center_mode_string) => {
+                                if center_mode_string.eq("amplitude") {
+                                    center_mode = CenterMode::Amplitude
+                                } else if center_mode_string.eq("dialog") {
+                                    center_mode = CenterMode::Dialog
+                                } else if center_mode_string.eq("projection") {
+                                    center_mode = CenterMode::Projection
+                                } else {
+                                    println!("Unknown center mode: {}", center_mode_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Center mode unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-verify-downmix") {
+                        match args_iter.next() {
+                            Some(downmix_method_string) => {
+                                if downmix_method_string.eq("itu") {
+                                    verify_downmix = Some(DownmixMethod::Itu)
+                                } else if downmix_method_string.eq("matrix") {
+                                    verify_downmix = Some(DownmixMethod::Matrix)
+                                } else {
+                                    println!("Unknown downmix: {}", downmix_method_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Downmix unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-downmix-threshold") {
+                        match args_iter.next() {
+                            Some(downmix_threshold_string) => {
+                                match downmix_threshold_string.parse::<f64>() {
+                                    Ok(downmix_threshold_value) => {
+                                        downmix_threshold = downmix_threshold_value
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the downmix threshold: {}",
+                                            downmix_threshold_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Downmix threshold unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-bass-management") {
+                        bass_management = true;
                     } else if flag.eq("-loud") {
                         loud = Some(true);
                     } else if flag.eq("-quiet") {
@@ -271,6 +1209,7 @@ impl Options {
                     // No more flags left, interpret the options and return them
                     let transform_mono: bool;
                     let channels: Channels;
+                    let mut b_format = false;
 
                     match channel_layout {
                         ChannelLayout::Four => {
@@ -300,24 +1239,238 @@ impl Options {
                                 .back_left()
                                 .back_right();
                         }
+                        ChannelLayout::AmbiX => {
+                            // W, Y, Z, X
+                            transform_mono = false;
+                            b_format = true;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .front_center()
+                                .low_frequency();
+                        }
+                    }
+
+                    let present = [
+                        channels.front_left,
+                        channels.front_right,
+                        channels.front_center,
+                        channels.low_frequency,
+                        channels.back_left,
+                        channels.back_right,
+                    ];
+                    for (channel_ctr, channel_adjustment) in channel_adjustments.iter().enumerate()
+                    {
+                        let adjusted = channel_adjustment.trim != 0.0
+                            || channel_adjustment.delay != 0.0
+                            || channel_adjustment.invert;
+                        if adjusted
+                            && (b_format
+                                || binaural.is_some()
+                                || matches!(matrix_format, MatrixFormat::Cd4))
+                        {
+                            println!(
+                                "-trim, -delay, and -invert only work when upmixing to speakers"
+                            );
+                            return None;
+                        }
+
+                        if adjusted && !present[channel_ctr] {
+                            println!(
+                                "-trim, -delay, or -invert is used on {}, which isn't in the upmix",
+                                CHANNEL_NAMES[channel_ctr]
+                            );
+                            return None;
+                        }
+                    }
+
+                    let vbap = speakers_specified || pan_law.is_some();
+                    if vbap && (b_format || matches!(matrix_format, MatrixFormat::Cd4)) {
+                        println!("-speakers and -pan-law only work when upmixing to speakers");
+                        return None;
+                    }
+
+                    if speakers_specified && binaural.is_some() {
+                        println!("-speakers can not be used with -binaural");
+                        return None;
+                    }
+
+                    if vbap {
+                        let [left, right, center, _, left_rear, right_rear] = speaker_azimuths;
+                        let left_in_order = 0.0 < left && left < left_rear && left_rear < 180.0;
+                        let right_in_order =
+                            -180.0 < right_rear && right_rear < right && right < 0.0;
+                        if !(left_in_order && right_in_order) {
+                            println!("The speakers must be in order around the room: L and Ls between 0 and 180 degrees, and R and Rs between 0 and -180 degrees");
+                            return None;
+                        }
+
+                        if left - right >= 180.0 || left_rear - right_rear <= 180.0 {
+                            println!("The front and rear speakers must each be less than 180 degrees apart");
+                            return None;
+                        }
+
+                        if channels.front_center && !(right < center && center < left) {
+                            println!("The center speaker must be between L and R");
+                            return None;
+                        }
+                    }
+
+                    if lfe_gain {
+                        if !channels.low_frequency {
+                            println!("-lfe-gain requires an LFE channel");
+                            return None;
+                        }
+
+                        channel_adjustments[3].trim += LFE_IN_BAND_GAIN;
+                    }
+
+                    if rear_steering
+                        && !matches!(
+                            matrix_format,
+                            MatrixFormat::Default
+                                | MatrixFormat::QS
+                                | MatrixFormat::HorseShoe
+                                | MatrixFormat::DolbyStereo
+                        )
+                    {
+                        println!("-rear-amount and -rear-curve only work with the default, qs, rm, horseshoe, and dolby matrixes");
+                        return None;
+                    }
+
+                    let cd4 = matches!(matrix_format, MatrixFormat::Cd4);
+                    if cd4 && !matches!(channel_layout, ChannelLayout::Four) {
+                        println!("-matrix cd4 requires -channels 4");
+                        return None;
+                    }
+
+                    if cd4 && binaural.is_some() {
+                        println!("-binaural can not be used with -matrix cd4");
+                        return None;
                     }
 
                     let matrix: Box<dyn Matrix> = match matrix_format {
-                        MatrixFormat::Default => Box::new(DefaultMatrix::new()),
-                        MatrixFormat::QS => Box::new(DefaultMatrix::qs()),
-                        MatrixFormat::HorseShoe => Box::new(DefaultMatrix::horseshoe()),
-                        MatrixFormat::DolbyStereo => Box::new(DefaultMatrix::dolby_stereo()),
+                        MatrixFormat::Default => {
+                            Box::new(DefaultMatrix::new().rear_steering(rear_amount, rear_curve))
+                        }
+                        MatrixFormat::QS => {
+                            Box::new(DefaultMatrix::qs().rear_steering(rear_amount, rear_curve))
+                        }
+                        MatrixFormat::HorseShoe => Box::new(
+                            DefaultMatrix::horseshoe().rear_steering(rear_amount, rear_curve),
+                        ),
+                        MatrixFormat::DolbyStereo => Box::new(
+                            DefaultMatrix::dolby_stereo().rear_steering(rear_amount, rear_curve),
+                        ),
                         MatrixFormat::SQ => Box::new(SQMatrix::sq()),
                         MatrixFormat::SQExperimental => Box::new(SQMatrixExperimental::sq()),
+                        MatrixFormat::Uhj => Box::new(UHJMatrix::uhj()),
+                        MatrixFormat::Ev4 => Box::new(CoefficientMatrix::ev4()),
+                        MatrixFormat::Dynaquad => Box::new(CoefficientMatrix::dynaquad()),
+                        MatrixFormat::Hafler => Box::new(CoefficientMatrix::hafler()),
+                        MatrixFormat::DolbyWide => Box::new(CoefficientMatrix::dolby_wide()),
+                        MatrixFormat::File(path) => match CoefficientMatrix::open(&path) {
+                            Ok(matrix) => Box::new(matrix),
+                            Err(error) => {
+                                println!("Can not read {}: {}", path.display(), error);
+                                return None;
+                            }
+                        },
+                        MatrixFormat::Cd4 => Box::new(DefaultMatrix::new()),
                     };
 
-                    if (low_frequency as f64) > panner_and_writer::LFE_START
+                    if b_format && binaural.is_some() {
+                        println!("-binaural can not be used with -channels ambix");
+                        return None;
+                    }
+
+                    if (low_frequency as f64) > lfe_crossover
                         && channels.low_frequency
+                        && !b_format
+                        && binaural.is_none()
                     {
                         println!(
-                            "LFE channel not supported when the lowest frequency to steer ({}hz) is greater than {}hz",
+                            "LFE channel not supported when the lowest frequency to steer ({}hz) is greater than the LFE crossover ({}hz)",
                             low_frequency,
-                            panner_and_writer::LFE_START);
+                            lfe_crossover);
+                        return None;
+                    }
+
+                    if bass_management
+                        && (!channels.low_frequency || b_format || binaural.is_some())
+                    {
+                        println!("-bass-management requires an LFE channel");
+                        return None;
+                    }
+
+                    if !matches!(center_mode, CenterMode::Amplitude)
+                        && (!channels.front_center || b_format)
+                    {
+                        println!("-center requires a center channel");
+                        return None;
+                    }
+
+                    if cd4
+                        && (input_conditioning.invert_left
+                            || input_conditioning.invert_right
+                            || input_conditioning.swap
+                            || input_conditioning.balance != 0.0
+                            || input_conditioning.dc_block.is_some())
+                    {
+                        println!("-invert-left, -invert-right, -swap, -balance, and -dc-block can not be used with -matrix cd4");
+                        return None;
+                    }
+
+                    if let Some(Multiband::Crossovers(crossovers)) = &multiband {
+                        let mut band_low_frequency = low_frequency as f64;
+                        for crossover in crossovers {
+                            if *crossover <= band_low_frequency {
+                                println!("Crossovers must be in order, and above the lowest frequency ({}hz): {}", low_frequency, crossover);
+                                return None;
+                            }
+
+                            band_low_frequency = *crossover;
+                        }
+                    }
+
+                    if multiband.is_some() && (cd4 || fft_size.is_some()) {
+                        println!("-multiband can not be used with -fft_size or -matrix cd4");
+                        return None;
+                    }
+
+                    // Each band's window gives the rear channels different filters, which don't add back up
+                    if multiband.is_some() && (decorrelation > 0.0 || rear_delay > 0.0) {
+                        println!("-multiband can not be used with -decorrelate or -rear-delay");
+                        return None;
+                    }
+
+                    if verify_downmix.is_some() && (b_format || binaural.is_some() || cd4) {
+                        println!("-verify-downmix only works when upmixing to speakers");
+                        return None;
+                    }
+
+                    if noise_floor.is_some() && minimum_specified {
+                        println!("-minimum and -noise-floor can not be used together");
+                        return None;
+                    }
+
+                    if noise_floor_csv_path.is_some() && noise_floor.is_none() {
+                        println!("-noise-floor-csv requires -noise-floor");
+                        return None;
+                    }
+
+                    if limit && b_format {
+                        println!("-limit can not be used with -channels ambix");
+                        return None;
+                    }
+
+                    if (measure_loudness || normalize_lufs.is_some()) && b_format {
+                        println!("Loudness can not be measured with -channels ambix");
+                        return None;
+                    }
+
+                    if headroom_auto && normalize_lufs.is_some() {
+                        println!("-headroom auto and -normalize-lufs can not be used together");
                         return None;
                     }
 
@@ -338,19 +1491,92 @@ impl Options {
                         num_threads,
                         transform_mono,
                         channels,
+                        b_format,
                         matrix,
                         low_frequency,
                         minimum_steered_amplitude,
+                        input_conditioning,
+                        noise_floor,
+                        noise_floor_csv_path,
                         keep_awake,
                         loud,
                         requested_fft_size: fft_size,
+                        multiband,
                         headroom,
+                        headroom_auto,
+                        limit,
+                        true_peak,
+                        measure_loudness,
+                        normalize_lufs,
+                        binaural,
+                        cd4,
+                        lfe_crossover,
+                        lfe_slope,
+                        bass_management,
+                        width,
+                        smoothing,
+                        transients,
+                        ambience,
+                        decorrelation,
+                        rear_delay,
+                        center_mode,
+                        vbap,
+                        speaker_azimuths,
+                        pan_law: pan_law.unwrap_or(PanLaw::ConstantPower),
+                        channel_adjustments,
+                        lr_delay,
+                        verify_downmix,
+                        downmix_threshold,
                     });
                 }
             }
         }
     }
+
+    // The channels in the written wav file
+    pub fn target_channels(&self) -> Channels {
+        if self.binaural.is_some() {
+            Channels::new().front_left().front_right()
+        } else {
+            self.channels
+        }
+    }
 }
+
+// Parses a channel name, such as "Ls"
+fn parse_channel(channel_string: &str) -> Option<usize> {
+    match CHANNEL_NAMES
+        .iter()
+        .position(|channel_name| channel_name.eq_ignore_ascii_case(channel_string))
+    {
+        Some(channel_ctr) => Some(channel_ctr),
+        None => {
+            println!(
+                "Unknown channel: {}. Channels are {}",
+                channel_string,
+                CHANNEL_NAMES.join(", ")
+            );
+            None
+        }
+    }
+}
+
+// Parses a channel and its value, such as "Ls=-2"
+fn parse_channel_and_value(channel_and_value_string: &str) -> Option<(usize, &str)> {
+    match channel_and_value_string.split_once('=') {
+        Some((channel_string, value_string)) => {
+            Some((parse_channel(channel_string)?, value_string))
+        }
+        None => {
+            println!(
+                "Expected a channel and a value, like C=-3: {}",
+                channel_and_value_string
+            );
+            None
+        }
+    }
+}
+
 pub fn amplitude_to_db(amplitude: f32) -> f32 {
     return 20.0 * amplitude.log10();
 }
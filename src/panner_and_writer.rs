@@ -1,22 +1,27 @@
 use std::{
-    collections::VecDeque,
-    f64::consts::PI,
+    collections::{HashMap, VecDeque},
+    f64::consts::{PI, SQRT_2},
     io::Result,
     sync::{Arc, Mutex},
 };
 
-pub const LFE_START: f64 = 40.0;
-const LFE_FULL: f64 = 20.0;
+pub const DEFAULT_LFE_CROSSOVER: f64 = 40.0;
 const HALF_PI: f64 = PI / 2.0;
+const QUARTER_PI: f64 = PI / 4.0;
 
 use rustfft::{num_complex::Complex, Fft};
 use wave_stream::{samples_by_channel::SamplesByChannel, wave_writer::RandomAccessWavWriter};
 
 use crate::{
+    ambisonics::{self, BFormatWindow},
+    binaural::BinauralRenderer,
+    decorrelation::RearFilters,
+    dialog::DialogDetector,
     matrix,
-    options::{db_to_amplitude, Options},
+    options::{db_to_amplitude, CenterMode, LfeSlope, Options},
     structs::{ThreadState, TransformedWindowAndPans},
     upmixer::Upmixer,
+    vbap::Vbap,
 };
 
 pub struct PannerAndWriter {
@@ -30,13 +35,35 @@ pub struct PannerAndWriter {
 
     lfe_levels: Option<Vec<f64>>,
 
+    // When bass management is enabled, the main channels are filtered so that the bass is only in the LFE
+    main_levels: Option<Vec<f64>>,
+
     max_samples_in_file: usize,
+
+    // Renders to headphones instead of writing each channel
+    binaural_renderer: Option<BinauralRenderer>,
+
+    // Decorrelates and delays the rear channels
+    rear_filters: Option<RearFilters>,
+
+    // Limits the center channel to dialog
+    dialog_detector: Option<DialogDetector>,
+
+    // Pans between the speakers at their positions in the room
+    vbap: Option<Vbap>,
+
+    // Each written channel's gain and delay, in samples: Front left, front right, center, LFE, rear left, rear right
+    channel_gains: [f64; 6],
+    channel_delays: Option<[usize; 6]>,
 }
 
 // Wraps types used during writing so they can be within a mutex
 struct WriterState {
     pub target_random_access_wav_writers: Vec<RandomAccessWavWriter<f32>>,
     pub total_samples_written: usize,
+
+    // When channels are delayed, samples are held until every channel is written
+    pub delayed_frames: HashMap<usize, [Option<f64>; 6]>,
 }
 
 impl PannerAndWriter {
@@ -47,6 +74,7 @@ impl PannerAndWriter {
         target_random_access_wav_writers: Vec<RandomAccessWavWriter<f32>>,
         fft_inverse: Arc<dyn Fft<f64>>,
         max_samples_in_file: usize,
+        binaural_renderer: Option<BinauralRenderer>,
     ) -> PannerAndWriter {
         let lfe_levels = if options.channels.low_frequency {
             let mut lfe_levels = vec![0.0f64; window_size];
@@ -67,14 +95,7 @@ impl PannerAndWriter {
                 let wavelength = window_size_f64 / transform_index_f64;
                 let frequency = sample_rate_f64 / wavelength;
 
-                let level = if frequency < LFE_FULL {
-                    1.0
-                } else if frequency < LFE_START {
-                    let frequency_fraction = (frequency - LFE_FULL) / LFE_FULL;
-                    (frequency_fraction * HALF_PI).cos()
-                } else {
-                    0.0
-                };
+                let level = lfe_level(options, frequency);
 
                 lfe_levels[transform_index] = level;
                 lfe_levels[window_size - transform_index] = level;
@@ -85,15 +106,47 @@ impl PannerAndWriter {
             None
         };
 
+        // Undoes the headroom that the reader applied
+        let headroom_gain = db_to_amplitude(0f32 - options.headroom.unwrap_or(0.0)) as f64;
+        let channel_gains = options.channel_adjustments.map(|channel_adjustment| {
+            let gain = headroom_gain * db_to_amplitude(channel_adjustment.trim as f32) as f64;
+            if channel_adjustment.invert {
+                -gain
+            } else {
+                gain
+            }
+        });
+
+        let main_levels = match (&lfe_levels, options.bass_management) {
+            (Some(lfe_levels), true) => Some(lfe_levels.iter().map(|level| 1.0 - level).collect()),
+            _ => None,
+        };
+
         PannerAndWriter {
             transformed_window_and_averaged_pans_queue: Mutex::new(VecDeque::new()),
             writer_state: Mutex::new(WriterState {
                 target_random_access_wav_writers,
                 total_samples_written: 0,
+                delayed_frames: HashMap::new(),
             }),
             fft_inverse,
             lfe_levels,
+            main_levels,
             max_samples_in_file,
+            binaural_renderer,
+            rear_filters: RearFilters::new(options, window_size, sample_rate),
+            dialog_detector: match options.center_mode {
+                CenterMode::Dialog => Some(DialogDetector::new(window_size, sample_rate)),
+                _ => None,
+            },
+            vbap: if options.vbap {
+                Some(Vbap::new(options))
+            } else {
+                None
+            },
+            channel_gains,
+            channel_delays: Some(channel_delay_samples(options, sample_rate))
+                .filter(|channel_delays| channel_delays.iter().any(|delay| *delay > 0)),
         }
     }
 
@@ -134,6 +187,11 @@ impl PannerAndWriter {
                 }
             };
 
+            if thread_state.upmixer.options.b_format {
+                self.transform_and_write_b_format(thread_state, transformed_window_and_pans)?;
+                continue 'transform_and_write;
+            }
+
             // The front channels are based on the original transforms
             let mut left_front = transformed_window_and_pans
                 .left_transformed
@@ -158,6 +216,19 @@ impl PannerAndWriter {
                 None
             };
 
+            let center_levels = match (&self.dialog_detector, &center) {
+                (Some(dialog_detector), Some(center)) => {
+                    Some(dialog_detector.center_levels(center))
+                }
+                _ => None,
+            };
+
+            // The projected center is extracted from the front channels after they are steered
+            let projected_center = match thread_state.upmixer.options.center_mode {
+                CenterMode::Projection => center.take(),
+                _ => None,
+            };
+
             // Ultra-lows are not shitfted
             left_rear[0] = Complex { re: 0f64, im: 0f64 };
             right_rear[0] = Complex { re: 0f64, im: 0f64 };
@@ -177,10 +248,7 @@ impl PannerAndWriter {
                 let left_to_right = frequency_pans.left_to_right;
                 let back_to_front = frequency_pans.back_to_front;
 
-                // Widening is currently disabled because it results in poor audio quality, and favors too
-                // much steering to the rear
-                //thread_state.upmixer.options.matrix.widen(&mut back_to_front, &mut left_to_right);
-
+                // Width was already applied to left_to_right after averaging
                 let front_to_back = 1f64 - back_to_front;
 
                 // Figure out the amplitudes for front and rear
@@ -189,8 +257,58 @@ impl PannerAndWriter {
                 let mut right_front_amplitude: f64;
                 let right_rear_amplitude: f64;
 
-                // sq requires oddbal adjustment of right-left panning
-                if thread_state.upmixer.options.matrix.steer_right_left() {
+                if let Some(vbap) = &self.vbap {
+                    let amplitude = if thread_state.upmixer.options.matrix.steer_right_left() {
+                        if thread_state.upmixer.options.loud {
+                            frequency_pans.amplitude
+                        } else {
+                            frequency_pans.amplitude
+                                * thread_state.upmixer.options.matrix.amplitude_adjustment()
+                        }
+                    } else if thread_state.upmixer.options.loud {
+                        (left_amplitude.powi(2) + right_amplitude.powi(2)).sqrt()
+                            / thread_state.upmixer.options.matrix.amplitude_adjustment()
+                    } else {
+                        (left_amplitude.powi(2) + right_amplitude.powi(2)).sqrt()
+                    };
+
+                    let gains = vbap.gains(frequency_pans.azimuth());
+                    left_front_amplitude = amplitude * gains[0];
+                    right_front_amplitude = amplitude * gains[1];
+                    left_rear_amplitude = amplitude * gains[4];
+                    right_rear_amplitude = amplitude * gains[5];
+
+                    center = match center {
+                        Some(mut center) => {
+                            // Sound that isn't dialog is returned to the front left and right
+                            let center_amplitude = match &center_levels {
+                                Some(center_levels) => {
+                                    let returned_amplitude = amplitude
+                                        * gains[2]
+                                        * (1.0 - center_levels[freq_ctr])
+                                        * matrix::CENTER_AMPLITUDE_ADJUSTMENT;
+                                    left_front_amplitude += returned_amplitude;
+                                    right_front_amplitude += returned_amplitude;
+
+                                    amplitude * gains[2] * center_levels[freq_ctr]
+                                }
+                                None => amplitude * gains[2],
+                            };
+
+                            let (_, phase) = center[freq_ctr].to_polar();
+                            let c = Complex::from_polar(center_amplitude, phase);
+
+                            center[freq_ctr] = c;
+                            if freq_ctr < thread_state.upmixer.window_midpoint {
+                                center[thread_state.upmixer.window_size - freq_ctr] = c.conj();
+                            }
+
+                            Some(center)
+                        }
+                        None => None,
+                    };
+                } else if thread_state.upmixer.options.matrix.steer_right_left() {
+                    // sq requires oddbal adjustment of right-left panning
                     // 0.0 is left, 1.0 is right
                     let left_to_right_no_center = (left_to_right / 2.0) + 0.5;
 
@@ -254,6 +372,20 @@ impl PannerAndWriter {
                                 }
                             }
 
+                            // Sound that isn't dialog is returned to the front left and right
+                            let center_amplitude = match &center_levels {
+                                Some(center_levels) => {
+                                    let returned_amplitude = center_amplitude
+                                        * (1.0 - center_levels[freq_ctr])
+                                        * matrix::CENTER_AMPLITUDE_ADJUSTMENT;
+                                    left_front_amplitude += returned_amplitude;
+                                    right_front_amplitude += returned_amplitude;
+
+                                    center_amplitude * center_levels[freq_ctr]
+                                }
+                                None => center_amplitude,
+                            };
+
                             let (_, phase) = center[freq_ctr].to_polar();
                             let c = Complex::from_polar(center_amplitude, phase);
 
@@ -295,6 +427,13 @@ impl PannerAndWriter {
                     let left_amplitude = left_amplitude / amplitude_adjustment;
                     let right_amplitude = right_amplitude / amplitude_adjustment;
 
+                    // These matrixes keep the source's left and right amplitudes, so width is applied to them
+                    let (left_amplitude, right_amplitude) = widen_amplitudes(
+                        left_amplitude,
+                        right_amplitude,
+                        thread_state.upmixer.options.width,
+                    );
+
                     // Figure out the amplitudes for front and rear
                     left_front_amplitude = left_amplitude * front_to_back;
                     right_front_amplitude = right_amplitude * front_to_back;
@@ -309,6 +448,13 @@ impl PannerAndWriter {
                                 * (left_front_amplitude + right_front_amplitude)
                                 * matrix::CENTER_AMPLITUDE_ADJUSTMENT
                                 * 0.5;
+
+                            // Sound that isn't dialog stays in the front left and right
+                            let center_amplitude = match &center_levels {
+                                Some(center_levels) => center_amplitude * center_levels[freq_ctr],
+                                None => center_amplitude,
+                            };
+
                             let c = Complex::from_polar(center_amplitude, phase);
 
                             center[freq_ctr] = c;
@@ -367,6 +513,62 @@ impl PannerAndWriter {
                 }
             }
 
+            if let Some(mut projected_center) = projected_center {
+                project_center(
+                    &mut left_front,
+                    &mut right_front,
+                    &mut projected_center,
+                    thread_state.upmixer.window_midpoint,
+                );
+                center = Some(projected_center);
+            }
+
+            if let Some(rear_filters) = &self.rear_filters {
+                rear_filters.apply(&mut left_rear, &mut right_rear);
+            }
+
+            if let Some(binaural_renderer) = &self.binaural_renderer {
+                let (mut left_ear, mut right_ear) = binaural_renderer.render(
+                    &left_front,
+                    &right_front,
+                    &left_rear,
+                    &right_rear,
+                    &center,
+                );
+
+                self.fft_inverse
+                    .process_with_scratch(&mut left_ear, &mut thread_state.scratch_inverse);
+                self.fft_inverse
+                    .process_with_scratch(&mut right_ear, &mut thread_state.scratch_inverse);
+
+                for (sample_ctr, sample_in_transform) in samples_in_window(
+                    &thread_state.upmixer,
+                    transformed_window_and_pans.last_sample_ctr,
+                ) {
+                    self.write_binaural_samples_in_window(
+                        &thread_state.upmixer,
+                        sample_ctr,
+                        sample_in_transform,
+                        &left_ear,
+                        &right_ear,
+                    )?;
+                }
+
+                thread_state.upmixer.logger.log_status(thread_state)?;
+                continue 'transform_and_write;
+            }
+
+            if let Some(main_levels) = &self.main_levels {
+                apply_levels(&mut left_front, main_levels);
+                apply_levels(&mut right_front, main_levels);
+                apply_levels(&mut left_rear, main_levels);
+                apply_levels(&mut right_rear, main_levels);
+
+                if let Some(center) = &mut center {
+                    apply_levels(center, main_levels);
+                }
+            }
+
             self.fft_inverse
                 .process_with_scratch(&mut left_front, &mut thread_state.scratch_inverse);
             self.fft_inverse
@@ -410,51 +612,14 @@ impl PannerAndWriter {
                 None => None,
             };
 
-            let sample_ctr =
-                transformed_window_and_pans.last_sample_ctr - thread_state.upmixer.window_midpoint;
-
-            if sample_ctr == thread_state.upmixer.window_midpoint {
-                // Special case for the beginning of the file
-                for sample_ctr in 0..sample_ctr {
-                    self.write_samples_in_window(
-                        &thread_state.upmixer,
-                        sample_ctr,
-                        sample_ctr as usize,
-                        &left_front,
-                        &right_front,
-                        &left_rear,
-                        &right_rear,
-                        &lfe,
-                        &center,
-                    )?;
-                }
-            } else if transformed_window_and_pans.last_sample_ctr
-                == thread_state.upmixer.total_samples_to_write - 1
-            {
-                // Special case for the end of the file
-                let first_sample_in_transform = thread_state.upmixer.total_samples_to_write
-                    - thread_state.upmixer.window_size
-                    - 1;
-                for sample_in_transform in
-                    (thread_state.upmixer.window_midpoint - 2)..thread_state.upmixer.window_size
-                {
-                    self.write_samples_in_window(
-                        &thread_state.upmixer,
-                        first_sample_in_transform + sample_in_transform,
-                        sample_in_transform as usize,
-                        &left_front,
-                        &right_front,
-                        &left_rear,
-                        &right_rear,
-                        &lfe,
-                        &center,
-                    )?;
-                }
-            } else {
+            for (sample_ctr, sample_in_transform) in samples_in_window(
+                &thread_state.upmixer,
+                transformed_window_and_pans.last_sample_ctr,
+            ) {
                 self.write_samples_in_window(
                     &thread_state.upmixer,
                     sample_ctr,
-                    thread_state.upmixer.window_midpoint,
+                    sample_in_transform,
                     &left_front,
                     &right_front,
                     &left_rear,
@@ -470,6 +635,41 @@ impl PannerAndWriter {
         Ok(())
     }
 
+    fn transform_and_write_b_format(
+        self: &PannerAndWriter,
+        thread_state: &mut ThreadState,
+        transformed_window_and_pans: TransformedWindowAndPans,
+    ) -> Result<()> {
+        let last_sample_ctr = transformed_window_and_pans.last_sample_ctr;
+        let mut b_format_window =
+            ambisonics::to_b_format(&thread_state.upmixer, transformed_window_and_pans);
+
+        for transformed in [
+            &mut b_format_window.w,
+            &mut b_format_window.x,
+            &mut b_format_window.y,
+            &mut b_format_window.z,
+        ] {
+            self.fft_inverse
+                .process_with_scratch(transformed, &mut thread_state.scratch_inverse);
+        }
+
+        for (sample_ctr, sample_in_transform) in
+            samples_in_window(&thread_state.upmixer, last_sample_ctr)
+        {
+            self.write_b_format_samples_in_window(
+                &thread_state.upmixer,
+                sample_ctr,
+                sample_in_transform,
+                &b_format_window,
+            )?;
+        }
+
+        thread_state.upmixer.logger.log_status(thread_state)?;
+
+        Ok(())
+    }
+
     fn write_samples_in_window(
         self: &PannerAndWriter,
         upmixer: &Upmixer,
@@ -482,47 +682,172 @@ impl PannerAndWriter {
         lfe: &Option<Vec<Complex<f64>>>,
         center: &Option<Vec<Complex<f64>>>,
     ) -> Result<()> {
-        let gain = db_to_amplitude(0f32 - upmixer.options.headroom.unwrap_or(0.0));
+        let sample = |channel_ctr: usize, window: &Vec<Complex<f64>>| {
+            upmixer.scale * window[sample_in_transform].re * self.channel_gains[channel_ctr]
+        };
 
+        let mut samples_by_channel = SamplesByChannel::new()
+            .front_left(sample(0, left_front))
+            .front_right(sample(1, right_front))
+            .back_left(sample(4, left_rear))
+            .back_right(sample(5, right_rear));
+
+        match lfe {
+            Some(lfe) => {
+                samples_by_channel = samples_by_channel.low_frequency(sample(3, lfe));
+            }
+            None => {}
+        }
+
+        match center {
+            Some(center) => {
+                samples_by_channel = samples_by_channel.front_center(sample(2, center));
+            }
+            None => {}
+        }
+
+        match &self.channel_delays {
+            Some(channel_delays) => {
+                self.write_delayed_samples(upmixer, channel_delays, sample_ctr, samples_by_channel)
+            }
+            None => self.write_samples(sample_ctr, samples_by_channel),
+        }
+    }
+
+    // Each channel is written to its own delayed position. Because windows are written out of order, a frame is
+    // only written once all of its channels are ready
+    fn write_delayed_samples(
+        self: &PannerAndWriter,
+        upmixer: &Upmixer,
+        channel_delays: &[usize; 6],
+        sample_ctr: usize,
+        samples_by_channel: SamplesByChannel<f64>,
+    ) -> Result<()> {
         let mut writer_state = self
             .writer_state
             .lock()
             .expect("Cannot aquire lock because a thread panicked");
 
-        let left_front_sample = left_front[sample_in_transform].re;
-        let right_front_sample = right_front[sample_in_transform].re;
-        let left_rear_sample = left_rear[sample_in_transform].re;
-        let right_rear_sample = right_rear[sample_in_transform].re;
+        let samples = [
+            samples_by_channel.front_left,
+            samples_by_channel.front_right,
+            samples_by_channel.front_center,
+            samples_by_channel.low_frequency,
+            samples_by_channel.back_left,
+            samples_by_channel.back_right,
+        ];
+
+        for (channel_ctr, sample) in samples.iter().enumerate() {
+            let sample = match sample {
+                Some(sample) => *sample,
+                None => continue,
+            };
 
-        let lfe_sample = match lfe {
-            Some(lfe) => Some(lfe[sample_in_transform].re),
-            None => None,
-        };
+            let delayed_sample_ctr = sample_ctr + channel_delays[channel_ctr];
+            if delayed_sample_ctr >= upmixer.total_samples_to_write {
+                continue;
+            }
 
-        let center_sample = match center {
-            Some(center) => Some(center[sample_in_transform].re),
-            None => None,
-        };
+            // Delayed channels are silent at the start of the file
+            let frame = writer_state
+                .delayed_frames
+                .entry(delayed_sample_ctr)
+                .or_insert_with(|| {
+                    let mut frame = [None; 6];
+                    for (frame_channel_ctr, frame_sample) in frame.iter_mut().enumerate() {
+                        if samples[frame_channel_ctr].is_some()
+                            && delayed_sample_ctr < channel_delays[frame_channel_ctr]
+                        {
+                            *frame_sample = Some(0.0);
+                        }
+                    }
 
-        let mut samples_by_channel = SamplesByChannel::new()
-            .front_left(upmixer.scale * left_front_sample * gain as f64)
-            .front_right(upmixer.scale * right_front_sample * gain as f64)
-            .back_left(upmixer.scale * left_rear_sample * gain as f64)
-            .back_right(upmixer.scale * right_rear_sample * gain as f64);
-
-        match lfe_sample {
-            Some(lfe_sample) => {
-                samples_by_channel = samples_by_channel.low_frequency(upmixer.scale * lfe_sample);
+                    frame
+                });
+            frame[channel_ctr] = Some(sample);
+
+            let complete = frame
+                .iter()
+                .zip(samples.iter())
+                .all(|(frame_sample, sample)| frame_sample.is_some() || sample.is_none());
+            if complete {
+                let frame = writer_state
+                    .delayed_frames
+                    .remove(&delayed_sample_ctr)
+                    .expect("Delayed frame missing");
+
+                let out_file_index = delayed_sample_ctr / self.max_samples_in_file;
+                let sample_ctr_in_file =
+                    delayed_sample_ctr - (self.max_samples_in_file * out_file_index);
+
+                writer_state.target_random_access_wav_writers[out_file_index].write_samples(
+                    sample_ctr_in_file,
+                    f64_to_f32(SamplesByChannel {
+                        front_left: frame[0],
+                        front_right: frame[1],
+                        front_center: frame[2],
+                        low_frequency: frame[3],
+                        back_left: frame[4],
+                        back_right: frame[5],
+                        ..SamplesByChannel::new()
+                    }),
+                )?;
             }
-            None => {}
         }
 
-        match center_sample {
-            Some(center_sample) => {
-                samples_by_channel = samples_by_channel.front_center(upmixer.scale * center_sample);
-            }
-            None => {}
-        }
+        writer_state.total_samples_written += 1;
+
+        Ok(())
+    }
+
+    // AmbiX files are written in ACN order: W, Y, Z, X
+    // (wave_stream requires speaker positions, so the first four speaker positions are used)
+    fn write_b_format_samples_in_window(
+        self: &PannerAndWriter,
+        upmixer: &Upmixer,
+        sample_ctr: usize,
+        sample_in_transform: usize,
+        b_format_window: &BFormatWindow,
+    ) -> Result<()> {
+        let gain = db_to_amplitude(0f32 - upmixer.options.headroom.unwrap_or(0.0)) as f64;
+        let scale = upmixer.scale * gain;
+
+        let samples_by_channel = SamplesByChannel::new()
+            .front_left(scale * b_format_window.w[sample_in_transform].re)
+            .front_right(scale * b_format_window.y[sample_in_transform].re)
+            .front_center(scale * b_format_window.z[sample_in_transform].re)
+            .low_frequency(scale * b_format_window.x[sample_in_transform].re);
+
+        self.write_samples(sample_ctr, samples_by_channel)
+    }
+
+    fn write_binaural_samples_in_window(
+        self: &PannerAndWriter,
+        upmixer: &Upmixer,
+        sample_ctr: usize,
+        sample_in_transform: usize,
+        left_ear: &[Complex<f64>],
+        right_ear: &[Complex<f64>],
+    ) -> Result<()> {
+        let gain = db_to_amplitude(0f32 - upmixer.options.headroom.unwrap_or(0.0)) as f64;
+        let scale = upmixer.scale * gain;
+
+        let samples_by_channel = SamplesByChannel::new()
+            .front_left(scale * left_ear[sample_in_transform].re)
+            .front_right(scale * right_ear[sample_in_transform].re);
+
+        self.write_samples(sample_ctr, samples_by_channel)
+    }
+
+    fn write_samples(
+        self: &PannerAndWriter,
+        sample_ctr: usize,
+        samples_by_channel: SamplesByChannel<f64>,
+    ) -> Result<()> {
+        let mut writer_state = self
+            .writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked");
 
         let out_file_index = sample_ctr / self.max_samples_in_file;
         let sample_ctr_in_file = sample_ctr - (self.max_samples_in_file * out_file_index);
@@ -536,6 +861,112 @@ impl PannerAndWriter {
     }
 }
 
+// Each channel's delay, in samples: Front left, front right, center, LFE, rear left, rear right
+fn channel_delay_samples(options: &Options, sample_rate: usize) -> [usize; 6] {
+    options.channel_adjustments.map(|channel_adjustment| {
+        (channel_adjustment.delay * sample_rate as f64 / 1000.0).round() as usize
+    })
+}
+
+// Spreads the amplitudes between left and right, keeping the total power the same
+fn widen_amplitudes(left_amplitude: f64, right_amplitude: f64, width: f64) -> (f64, f64) {
+    if width == 1.0 {
+        return (left_amplitude, right_amplitude);
+    }
+
+    // 0 is left, a quarter turn is right
+    let angle = right_amplitude.atan2(left_amplitude);
+    let left_to_right = ((angle / QUARTER_PI) - 1.0) * width;
+    let angle = (left_to_right.clamp(-1.0, 1.0) + 1.0) * QUARTER_PI;
+
+    let amplitude = (left_amplitude.powi(2) + right_amplitude.powi(2)).sqrt();
+    (amplitude * angle.cos(), amplitude * angle.sin())
+}
+
+// How much of a frequency goes to the LFE
+fn lfe_level(options: &Options, frequency: f64) -> f64 {
+    match options.lfe_slope {
+        LfeSlope::Cosine => {
+            let lfe_full = options.lfe_crossover / 2.0;
+
+            if frequency < lfe_full {
+                1.0
+            } else if frequency < options.lfe_crossover {
+                let frequency_fraction = (frequency - lfe_full) / lfe_full;
+                (frequency_fraction * HALF_PI).cos()
+            } else {
+                0.0
+            }
+        }
+        // The magnitude of a Linkwitz-Riley lowpass. Because the levels are applied without shifting phase,
+        // the LFE and the main channels sum back to the original
+        LfeSlope::Lr4 => 1.0 / (1.0 + (frequency / options.lfe_crossover).powi(4)),
+    }
+}
+
+fn apply_levels(transform: &mut [Complex<f64>], levels: &[f64]) {
+    for (value, level) in transform.iter_mut().zip(levels) {
+        *value *= level;
+    }
+}
+
+// Moves the component that is common to left and right into the center, keeping the phases. Because the center is
+// subtracted as a complex value, downmixing (left + .707 * center, right + .707 * center) gives the original left and
+// right
+fn project_center(
+    left: &mut [Complex<f64>],
+    right: &mut [Complex<f64>],
+    center: &mut [Complex<f64>],
+    window_midpoint: usize,
+) {
+    let window_size = left.len();
+
+    for freq_ctr in 0..(window_midpoint + 1) {
+        let power = left[freq_ctr].norm_sqr() + right[freq_ctr].norm_sqr();
+        let common = if power > 0.0 {
+            // 1 when left and right are identical, 0 when they are isolated, uncorrelated, or out of phase
+            let similarity =
+                (2.0 * (left[freq_ctr] * right[freq_ctr].conj()).re / power).clamp(0.0, 1.0);
+            (left[freq_ctr] + right[freq_ctr]) * (similarity / 2.0)
+        } else {
+            Complex { re: 0.0, im: 0.0 }
+        };
+
+        left[freq_ctr] -= common;
+        right[freq_ctr] -= common;
+        center[freq_ctr] = common * SQRT_2;
+
+        if freq_ctr > 0 && freq_ctr < window_midpoint {
+            left[window_size - freq_ctr] = left[freq_ctr].conj();
+            right[window_size - freq_ctr] = right[freq_ctr].conj();
+            center[window_size - freq_ctr] = center[freq_ctr].conj();
+        }
+    }
+}
+
+// The samples to write from a window, as (sample_ctr, sample_in_transform). The window ends at last_sample_ctr, so
+// its midpoint is sample last_sample_ctr + 1 - window_midpoint. The first window also writes the samples before its
+// midpoint, and the last window also writes the samples after its midpoint, so each sample is written once
+fn samples_in_window(upmixer: &Upmixer, last_sample_ctr: usize) -> Vec<(usize, usize)> {
+    let first_sample_ctr = last_sample_ctr + 1 - upmixer.window_size;
+
+    let first_sample_in_transform = if first_sample_ctr == 0 {
+        0
+    } else {
+        upmixer.window_midpoint
+    };
+
+    let end_sample_in_transform = if last_sample_ctr == upmixer.total_samples_to_write - 1 {
+        upmixer.window_size
+    } else {
+        upmixer.window_midpoint + 1
+    };
+
+    (first_sample_in_transform..end_sample_in_transform)
+        .map(|sample_in_transform| (first_sample_ctr + sample_in_transform, sample_in_transform))
+        .collect()
+}
+
 pub fn f64_to_f32(samples: SamplesByChannel<f64>) -> SamplesByChannel<f32> {
     SamplesByChannel {
         front_left_of_center: None,
@@ -574,3 +1005,118 @@ impl Drop for PannerAndWriter {
             });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW_SIZE: usize = 32;
+    const WINDOW_MIDPOINT: usize = WINDOW_SIZE / 2;
+
+    // Deterministic pseudo-random numbers from -1 to 1
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 11) as f64 / (1u64 << 52) as f64) - 1.0
+        }
+
+        fn next_complex(&mut self) -> Complex<f64> {
+            Complex {
+                re: self.next(),
+                im: self.next(),
+            }
+        }
+    }
+
+    // Left and right as they come out of a Fourier transform of real samples
+    fn transform(random: &mut Random) -> (Vec<Complex<f64>>, Vec<Complex<f64>>) {
+        let mut left = vec![Complex { re: 0.0, im: 0.0 }; WINDOW_SIZE];
+        let mut right = vec![Complex { re: 0.0, im: 0.0 }; WINDOW_SIZE];
+
+        for freq_ctr in 0..(WINDOW_MIDPOINT + 1) {
+            let left_bin = random.next_complex();
+            let (left_bin, right_bin) = match freq_ctr % 5 {
+                // In phase
+                0 => (left_bin, left_bin),
+                // Out of phase
+                1 => (left_bin, -left_bin),
+                // Hard left
+                2 => (left_bin, Complex { re: 0.0, im: 0.0 }),
+                // Partly correlated
+                3 => (left_bin, (left_bin * 0.5) + (random.next_complex() * 0.5)),
+                // Uncorrelated
+                _ => (left_bin, random.next_complex()),
+            };
+
+            left[freq_ctr] = left_bin;
+            right[freq_ctr] = right_bin;
+        }
+
+        // The lowest and highest frequencies are real
+        for bins in [&mut left, &mut right] {
+            bins[0].im = 0.0;
+            bins[WINDOW_MIDPOINT].im = 0.0;
+            for freq_ctr in 1..WINDOW_MIDPOINT {
+                bins[WINDOW_SIZE - freq_ctr] = bins[freq_ctr].conj();
+            }
+        }
+
+        (left, right)
+    }
+
+    #[test]
+    fn project_center_adds_back_to_left_and_right() {
+        let mut random = Random(1);
+
+        for _ in 0..100 {
+            let (original_left, original_right) = transform(&mut random);
+            let mut left = original_left.clone();
+            let mut right = original_right.clone();
+            let mut center = vec![Complex { re: 0.0, im: 0.0 }; WINDOW_SIZE];
+
+            project_center(&mut left, &mut right, &mut center, WINDOW_MIDPOINT);
+
+            for freq_ctr in 0..WINDOW_SIZE {
+                let center_in_each = center[freq_ctr] / SQRT_2;
+                assert!((left[freq_ctr] + center_in_each - original_left[freq_ctr]).norm() < 1e-12);
+                assert!(
+                    (right[freq_ctr] + center_in_each - original_right[freq_ctr]).norm() < 1e-12
+                );
+            }
+
+            for freq_ctr in 1..WINDOW_MIDPOINT {
+                let mirrored = WINDOW_SIZE - freq_ctr;
+                assert_eq!(left[mirrored], left[freq_ctr].conj());
+                assert_eq!(right[mirrored], right[freq_ctr].conj());
+                assert_eq!(center[mirrored], center[freq_ctr].conj());
+            }
+        }
+    }
+
+    #[test]
+    fn project_center_takes_only_what_left_and_right_share() {
+        let mut random = Random(2);
+        let bin = random.next_complex();
+        let zero = Complex { re: 0.0, im: 0.0 };
+
+        // In phase, out of phase, and hard left
+        for (left_bin, right_bin, center_bin) in [
+            (bin, bin, bin * SQRT_2),
+            (bin, -bin, zero),
+            (bin, zero, zero),
+        ] {
+            let mut left = vec![left_bin; WINDOW_SIZE];
+            let mut right = vec![right_bin; WINDOW_SIZE];
+            let mut center = vec![zero; WINDOW_SIZE];
+
+            project_center(&mut left, &mut right, &mut center, WINDOW_MIDPOINT);
+
+            assert!((center[1] - center_bin).norm() < 1e-12);
+        }
+    }
+}
@@ -3,7 +3,11 @@ use std::{
     sync::Mutex,
 };
 
-use crate::structs::{FrequencyPans, ThreadState, TransformedWindowAndPans};
+use crate::{
+    options::{Options, Smoothing},
+    structs::{FrequencyPans, ThreadState, TransformedWindowAndPans},
+    transients::TransientDetector,
+};
 
 pub struct PanningAverager {
     // Temporary location for transformed windows and pans so that they can be finished out-of-order
@@ -18,50 +22,74 @@ struct EnqueueAndAverageState {
     pub average_last_sample_ctr_lower_bounds: Vec<usize>,
     pub average_last_sample_ctr_upper_bounds: Vec<usize>,
     pub pan_fraction_per_frequencies: Vec<f64>,
+    // For exponential smoothing: How far the averages move towards each new pan, per sample
+    pub attack_fraction: f64,
+    pub release_fraction: f64,
+    // For exponential smoothing: Follows the amplitude of each frequency to choose between attack and release
+    pub amplitude_averages: Vec<f64>,
     // Indexes of samples to average
     pub next_last_sample_ctr_to_enqueue: usize,
     // A queue of transformed windows and all of the panned locations of each frequency, before averaging
     pub transformed_window_and_pans_queue: VecDeque<TransformedWindowAndPans>,
     // The current average pans
     pub pan_averages: Vec<FrequencyPans>,
+    // When set, averages don't smear across onsets
+    pub transient_detector: Option<TransientDetector>,
+    // Indexes, in the queue, of the windows that start with an onset. An onset is written when its index is 0
+    pub onset_indexes: VecDeque<usize>,
+    // The last written pans, which are held while the pans include an onset that isn't written yet
+    pub held_pans: Vec<FrequencyPans>,
 }
 
 impl PanningAverager {
-    pub fn new(window_size: usize) -> PanningAverager {
+    pub fn new(options: &Options, window_size: usize, sample_rate: usize) -> PanningAverager {
         let window_midpoint = window_size / 2;
 
+        // Rectangular smoothing averages a multiple of each wavelength
+        let wavelengths = match options.smoothing {
+            Smoothing::Rectangular(wavelengths) => wavelengths,
+            _ => 1.0,
+        };
+
         // Calculate ranges for averaging each sub frequency
         let mut average_last_sample_ctr_lower_bounds = Vec::with_capacity(window_midpoint - 1);
         let mut average_last_sample_ctr_upper_bounds = Vec::with_capacity(window_midpoint - 1);
         let mut pan_fraction_per_frequencys = Vec::with_capacity(window_midpoint - 1);
         for sub_freq_ctr in 0..window_midpoint {
-            // Out of 8
-            // 1, 2, 3, 4
-            let transform_index = sub_freq_ctr + 1;
-            // 8, 4, 2, 1
-            let wavelength = window_size / transform_index;
-
-            let extra_samples = window_size - wavelength;
-
-            let average_last_sample_ctr_lower_bound = extra_samples / 2;
-            let average_last_sample_ctr_upper_bound =
-                average_last_sample_ctr_lower_bound + wavelength - 1;
-            let pan_fraction_per_frequency = 1.0f64 / (wavelength as f64);
+            let (
+                average_last_sample_ctr_lower_bound,
+                average_last_sample_ctr_upper_bound,
+                pan_fraction_per_frequency,
+            ) = rectangular_bounds(window_size, sub_freq_ctr, wavelengths);
 
             average_last_sample_ctr_lower_bounds.push(average_last_sample_ctr_lower_bound);
             average_last_sample_ctr_upper_bounds.push(average_last_sample_ctr_upper_bound);
             pan_fraction_per_frequencys.push(pan_fraction_per_frequency);
         }
 
+        let (attack_fraction, release_fraction) = match options.smoothing {
+            Smoothing::Exponential { attack, release } => (
+                exponential_fraction(attack, sample_rate),
+                exponential_fraction(release, sample_rate),
+            ),
+            _ => (1.0, 1.0),
+        };
+
         PanningAverager {
             transformed_window_and_pans_by_sample: Mutex::new(HashMap::new()),
             enqueue_and_average_state: Mutex::new(EnqueueAndAverageState {
                 average_last_sample_ctr_lower_bounds,
                 average_last_sample_ctr_upper_bounds,
                 pan_fraction_per_frequencies: pan_fraction_per_frequencys,
+                attack_fraction,
+                release_fraction,
+                amplitude_averages: Vec::with_capacity(window_midpoint),
                 next_last_sample_ctr_to_enqueue: window_size - 1,
                 transformed_window_and_pans_queue: VecDeque::new(),
                 pan_averages: Vec::with_capacity(window_size - 1),
+                transient_detector: TransientDetector::new(options, window_size, sample_rate),
+                onset_indexes: VecDeque::new(),
+                held_pans: Vec::with_capacity(window_midpoint),
             }),
         }
     }
@@ -104,14 +132,15 @@ impl PanningAverager {
                 {
                     Some(mut last_transformed_window_and_pans) => {
                         // Special case: First transform
-                        // Pre-seed multiple copies of the first transform for averaging
+                        // Pre-seed multiple copies of the first transform for averaging. The first transform is
+                        // then at the midpoint of the queue, so it's the first one written
                         if enqueue_and_average_state.next_last_sample_ctr_to_enqueue
                             == thread_state.upmixer.window_size - 1
                         {
                             while enqueue_and_average_state
                                 .transformed_window_and_pans_queue
                                 .len()
-                                < thread_state.upmixer.window_midpoint - 1
+                                < thread_state.upmixer.window_midpoint
                             {
                                 enqueue_and_average_state
                                     .transformed_window_and_pans_queue
@@ -160,39 +189,80 @@ impl PanningAverager {
                             .transformed_window_and_pans_queue
                             .push_back(last_transformed_window_and_pans);
 
+                        let enqueue_and_average_state_ref = &mut *enqueue_and_average_state;
+                        if let Some(transient_detector) =
+                            &mut enqueue_and_average_state_ref.transient_detector
+                        {
+                            let onset_index = enqueue_and_average_state_ref
+                                .transformed_window_and_pans_queue
+                                .len()
+                                - 1;
+                            if transient_detector.detect(
+                                &enqueue_and_average_state_ref.transformed_window_and_pans_queue
+                                    [onset_index],
+                            ) {
+                                enqueue_and_average_state_ref
+                                    .onset_indexes
+                                    .push_back(onset_index);
+                            }
+                        }
+
                         // Special case: Pre-seed averages
                         if enqueue_and_average_state.next_last_sample_ctr_to_enqueue
                             == thread_state.upmixer.window_size
                                 + thread_state.upmixer.window_midpoint
+                                - 1
                         {
                             for freq_ctr in 0..thread_state.upmixer.window_midpoint {
-                                let mut average_left_to_right = 0.0;
-                                let mut average_back_to_front = 0.0;
-                                for sample_ctr in enqueue_and_average_state
-                                    .average_last_sample_ctr_lower_bounds[freq_ctr]
-                                    ..(enqueue_and_average_state
-                                        .average_last_sample_ctr_upper_bounds[freq_ctr]
-                                        - 1)
-                                {
-                                    let fraction_per_frequency = enqueue_and_average_state
-                                        .pan_fraction_per_frequencies[freq_ctr];
-
-                                    let frequency_pans = &enqueue_and_average_state
-                                        .transformed_window_and_pans_queue[sample_ctr]
-                                        .frequency_pans[freq_ctr];
-
-                                    average_left_to_right +=
-                                        frequency_pans.left_to_right * fraction_per_frequency;
-                                    average_back_to_front +=
-                                        frequency_pans.back_to_front * fraction_per_frequency;
+                                match thread_state.upmixer.options.smoothing {
+                                    Smoothing::Rectangular(_) => {
+                                        // The newest pan is added before the first average is used
+                                        let mut average_left_to_right = 0.0;
+                                        let mut average_back_to_front = 0.0;
+                                        for sample_ctr in enqueue_and_average_state
+                                            .average_last_sample_ctr_lower_bounds[freq_ctr]
+                                            ..enqueue_and_average_state
+                                                .average_last_sample_ctr_upper_bounds[freq_ctr]
+                                        {
+                                            let fraction_per_frequency = enqueue_and_average_state
+                                                .pan_fraction_per_frequencies[freq_ctr];
+
+                                            let frequency_pans = &enqueue_and_average_state
+                                                .transformed_window_and_pans_queue[sample_ctr]
+                                                .frequency_pans[freq_ctr];
+
+                                            average_left_to_right += frequency_pans.left_to_right
+                                                * fraction_per_frequency;
+                                            average_back_to_front += frequency_pans.back_to_front
+                                                * fraction_per_frequency;
+                                        }
+
+                                        enqueue_and_average_state.pan_averages.push(
+                                            FrequencyPans {
+                                                amplitude: 0.0, // unused
+                                                left_to_right: average_left_to_right,
+                                                back_to_front: average_back_to_front,
+                                            },
+                                        );
+                                    }
+                                    Smoothing::Exponential { .. } | Smoothing::None => {
+                                        // Start from the first written window's pans
+                                        let frequency_pans = enqueue_and_average_state
+                                            .transformed_window_and_pans_queue
+                                            [thread_state.upmixer.window_midpoint]
+                                            .frequency_pans[freq_ctr]
+                                            .clone();
+
+                                        enqueue_and_average_state
+                                            .amplitude_averages
+                                            .push(frequency_pans.amplitude);
+                                        enqueue_and_average_state.pan_averages.push(frequency_pans);
+                                    }
                                 }
-
-                                enqueue_and_average_state.pan_averages.push(FrequencyPans {
-                                    amplitude: 0.0, // unused
-                                    left_to_right: average_left_to_right,
-                                    back_to_front: average_back_to_front,
-                                });
                             }
+
+                            enqueue_and_average_state.held_pans =
+                                enqueue_and_average_state.pan_averages.clone();
                         }
 
                         enqueue_and_average_state.next_last_sample_ctr_to_enqueue += 1;
@@ -213,29 +283,83 @@ impl PanningAverager {
             .len()
             >= thread_state.upmixer.window_size
         {
-            // Add newly-added pans (in the queue) to the averages
-            for freq_ctr in 0..thread_state.upmixer.window_midpoint {
-                let sample_ctr =
-                    enqueue_and_average_state.average_last_sample_ctr_upper_bounds[freq_ctr];
-
-                let pan_fraction_per_frequency =
-                    enqueue_and_average_state.pan_fraction_per_frequencies[freq_ctr];
-                let frequency_pan = enqueue_and_average_state.transformed_window_and_pans_queue
-                    [sample_ctr]
-                    .frequency_pans[freq_ctr]
-                    .clone();
-
-                let frequency_pan_average = &mut enqueue_and_average_state.pan_averages[freq_ctr];
-
-                let adjust_left_to_right = frequency_pan.left_to_right * pan_fraction_per_frequency;
-                frequency_pan_average.left_to_right += adjust_left_to_right;
-
-                let adjust_back_to_front = frequency_pan.back_to_front * pan_fraction_per_frequency;
-                frequency_pan_average.back_to_front += adjust_back_to_front;
+            // An onset that is about to be written resets exponential averages
+            let onset_written = enqueue_and_average_state.onset_indexes.front() == Some(&0);
+
+            match thread_state.upmixer.options.smoothing {
+                Smoothing::Rectangular(_) => {
+                    // Add newly-added pans (in the queue) to the averages
+                    for freq_ctr in 0..thread_state.upmixer.window_midpoint {
+                        let sample_ctr = enqueue_and_average_state
+                            .average_last_sample_ctr_upper_bounds[freq_ctr];
+
+                        let pan_fraction_per_frequency =
+                            enqueue_and_average_state.pan_fraction_per_frequencies[freq_ctr];
+                        let frequency_pan = enqueue_and_average_state
+                            .transformed_window_and_pans_queue[sample_ctr]
+                            .frequency_pans[freq_ctr]
+                            .clone();
+
+                        let frequency_pan_average =
+                            &mut enqueue_and_average_state.pan_averages[freq_ctr];
+                        *frequency_pan_average = rectangular_average(
+                            frequency_pan_average,
+                            &frequency_pan,
+                            pan_fraction_per_frequency,
+                        );
+                    }
+                }
+                Smoothing::Exponential { .. } => {
+                    // Move the averages towards the pans that are about to be written
+                    let enqueue_and_average_state = &mut *enqueue_and_average_state;
+                    let frequency_pans = &enqueue_and_average_state
+                        .transformed_window_and_pans_queue[thread_state.upmixer.window_midpoint]
+                        .frequency_pans;
+
+                    let (attack_fraction, release_fraction) = if onset_written {
+                        (1.0, 1.0)
+                    } else {
+                        (
+                            enqueue_and_average_state.attack_fraction,
+                            enqueue_and_average_state.release_fraction,
+                        )
+                    };
+
+                    for ((frequency_pan_average, amplitude_average), frequency_pan) in
+                        enqueue_and_average_state
+                            .pan_averages
+                            .iter_mut()
+                            .zip(enqueue_and_average_state.amplitude_averages.iter_mut())
+                            .zip(frequency_pans.iter())
+                    {
+                        (*frequency_pan_average, *amplitude_average) = exponential_average(
+                            frequency_pan_average,
+                            *amplitude_average,
+                            frequency_pan,
+                            attack_fraction,
+                            release_fraction,
+                        );
+                    }
+                }
+                Smoothing::None => {
+                    enqueue_and_average_state.pan_averages = enqueue_and_average_state
+                        .transformed_window_and_pans_queue[thread_state.upmixer.window_midpoint]
+                        .frequency_pans
+                        .iter()
+                        .map(no_average)
+                        .collect();
+                }
             }
 
             // enqueue the averaged transformed window and pans
-            let mut frequency_pans = enqueue_and_average_state.pan_averages.clone();
+            let mut frequency_pans = if enqueue_and_average_state.transient_detector.is_some() {
+                enqueue_and_average_state.hold_pans_before_onsets(
+                    &thread_state.upmixer.options.smoothing,
+                    thread_state.upmixer.window_midpoint,
+                )
+            } else {
+                enqueue_and_average_state.pan_averages.clone()
+            };
             let transformed_window_and_pans = enqueue_and_average_state
                 .transformed_window_and_pans_queue
                 .get_mut(thread_state.upmixer.window_midpoint)
@@ -252,6 +376,9 @@ impl PanningAverager {
             for freq_ctr in 0..frequency_pans.len() {
                 frequency_pans[freq_ctr].amplitude =
                     transformed_window_and_pans.frequency_pans[freq_ctr].amplitude;
+
+                // Width is applied after averaging so that it doesn't change the averages
+                frequency_pans[freq_ctr].widen(thread_state.upmixer.options.width);
             }
 
             thread_state
@@ -270,35 +397,285 @@ impl PanningAverager {
                 enqueue_and_average_state
                     .transformed_window_and_pans_queue
                     .clear();
+                enqueue_and_average_state.onset_indexes.clear();
 
                 return;
             }
 
             // Remove the unneeded pans
-            for freq_ctr in 0..thread_state.upmixer.window_midpoint {
-                let sample_ctr =
-                    enqueue_and_average_state.average_last_sample_ctr_lower_bounds[freq_ctr];
-
-                let pan_fraction_per_frequency =
-                    enqueue_and_average_state.pan_fraction_per_frequencies[freq_ctr];
-                let frequency_pan = enqueue_and_average_state.transformed_window_and_pans_queue
-                    [sample_ctr]
-                    .frequency_pans[freq_ctr]
-                    .clone();
-
-                let frequency_pan_average = &mut enqueue_and_average_state.pan_averages[freq_ctr];
-
-                let adjust_left_to_right = frequency_pan.left_to_right * pan_fraction_per_frequency;
-                frequency_pan_average.left_to_right -= adjust_left_to_right;
-
-                let adjust_back_to_front = frequency_pan.back_to_front * pan_fraction_per_frequency;
-                frequency_pan_average.back_to_front -= adjust_back_to_front;
+            if let Smoothing::Rectangular(_) = thread_state.upmixer.options.smoothing {
+                for freq_ctr in 0..thread_state.upmixer.window_midpoint {
+                    let sample_ctr =
+                        enqueue_and_average_state.average_last_sample_ctr_lower_bounds[freq_ctr];
+
+                    let pan_fraction_per_frequency =
+                        enqueue_and_average_state.pan_fraction_per_frequencies[freq_ctr];
+                    let frequency_pan = enqueue_and_average_state.transformed_window_and_pans_queue
+                        [sample_ctr]
+                        .frequency_pans[freq_ctr]
+                        .clone();
+
+                    let frequency_pan_average =
+                        &mut enqueue_and_average_state.pan_averages[freq_ctr];
+                    *frequency_pan_average = rectangular_average(
+                        frequency_pan_average,
+                        &frequency_pan,
+                        -pan_fraction_per_frequency,
+                    );
+                }
             }
 
             // dequeue
             enqueue_and_average_state
                 .transformed_window_and_pans_queue
                 .pop_front();
+
+            // Onsets move towards the front of the queue, and are forgotten once they are written
+            if onset_written {
+                enqueue_and_average_state.onset_indexes.pop_front();
+            }
+
+            for onset_index in enqueue_and_average_state.onset_indexes.iter_mut() {
+                *onset_index -= 1;
+            }
+        }
+    }
+}
+
+impl EnqueueAndAverageState {
+    // Each written window is the middle sample of its transform, so the windows after it already include sound that
+    // isn't written yet. Until an onset is written, each frequency keeps the pans from before the onset, so the onset
+    // isn't steered early (pre-echo.) After the onset is written, the averages only include windows with the onset
+    fn hold_pans_before_onsets(
+        &mut self,
+        smoothing: &Smoothing,
+        window_midpoint: usize,
+    ) -> Vec<FrequencyPans> {
+        let next_onset_index = self
+            .onset_indexes
+            .iter()
+            .find(|onset_index| **onset_index > 0)
+            .copied()
+            .unwrap_or(usize::MAX);
+
+        for freq_ctr in 0..window_midpoint {
+            // The last window that contributes to the frequency's pans
+            let last_averaged_index = match smoothing {
+                Smoothing::Rectangular(_) => {
+                    self.average_last_sample_ctr_upper_bounds[freq_ctr].max(window_midpoint)
+                }
+                Smoothing::Exponential { .. } | Smoothing::None => window_midpoint,
+            };
+
+            if next_onset_index > last_averaged_index {
+                self.held_pans[freq_ctr] = self.pan_averages[freq_ctr].clone();
+            }
         }
+
+        self.held_pans.clone()
+    }
+}
+
+// The first and last index, in the queue, of the pans that rectangular smoothing averages for a frequency, and
+// the fraction that each pan contributes to the average
+fn rectangular_bounds(
+    window_size: usize,
+    sub_freq_ctr: usize,
+    wavelengths: f64,
+) -> (usize, usize, f64) {
+    // Out of 8
+    // 1, 2, 3, 4
+    let transform_index = sub_freq_ctr + 1;
+    // 8, 4, 2, 1
+    let wavelength = window_size / transform_index;
+
+    // The average can not be longer than the queue
+    let average_length = ((wavelength as f64) * wavelengths)
+        .round()
+        .clamp(1.0, window_size as f64) as usize;
+
+    let extra_samples = window_size - average_length;
+
+    let lower_bound = extra_samples / 2;
+    let upper_bound = lower_bound + average_length - 1;
+
+    (lower_bound, upper_bound, 1.0f64 / (average_length as f64))
+}
+
+// Rectangular smoothing: Adds a pan that enters the average, or, with a negative fraction, removes a pan that
+// leaves it
+fn rectangular_average(
+    frequency_pan_average: &FrequencyPans,
+    frequency_pan: &FrequencyPans,
+    pan_fraction_per_frequency: f64,
+) -> FrequencyPans {
+    FrequencyPans {
+        amplitude: frequency_pan_average.amplitude,
+        left_to_right: frequency_pan_average.left_to_right
+            + (frequency_pan.left_to_right * pan_fraction_per_frequency),
+        back_to_front: frequency_pan_average.back_to_front
+            + (frequency_pan.back_to_front * pan_fraction_per_frequency),
+    }
+}
+
+// Exponential smoothing: Moves the average towards a pan, by the attack fraction when the frequency gets louder,
+// and by the release fraction when it gets quieter. Returns the new average and the new amplitude average
+fn exponential_average(
+    frequency_pan_average: &FrequencyPans,
+    amplitude_average: f64,
+    frequency_pan: &FrequencyPans,
+    attack_fraction: f64,
+    release_fraction: f64,
+) -> (FrequencyPans, f64) {
+    let fraction = if frequency_pan.amplitude > amplitude_average {
+        attack_fraction
+    } else {
+        release_fraction
+    };
+
+    (
+        FrequencyPans {
+            amplitude: frequency_pan_average.amplitude,
+            left_to_right: frequency_pan_average.left_to_right
+                + ((frequency_pan.left_to_right - frequency_pan_average.left_to_right) * fraction),
+            back_to_front: frequency_pan_average.back_to_front
+                + ((frequency_pan.back_to_front - frequency_pan_average.back_to_front) * fraction),
+        },
+        amplitude_average + ((frequency_pan.amplitude - amplitude_average) * fraction),
+    )
+}
+
+// No smoothing: Each window is steered by its own pans
+fn no_average(frequency_pan: &FrequencyPans) -> FrequencyPans {
+    frequency_pan.clone()
+}
+
+// How far an exponential average moves towards each new value, per sample, for a time constant in milliseconds
+fn exponential_fraction(time_constant: f64, sample_rate: usize) -> f64 {
+    let samples = time_constant * (sample_rate as f64) / 1000.0;
+    if samples <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / samples).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frequency_pans(amplitude: f64, left_to_right: f64, back_to_front: f64) -> FrequencyPans {
+        FrequencyPans {
+            amplitude,
+            left_to_right,
+            back_to_front,
+        }
+    }
+
+    #[test]
+    fn rectangular_average_converges_after_wavelengths() {
+        let window_size = 64;
+        // A wavelength of 16 samples
+        let sub_freq_ctr = 3;
+        let wavelengths = 2.0;
+
+        let (lower_bound, upper_bound, pan_fraction_per_frequency) =
+            rectangular_bounds(window_size, sub_freq_ctr, wavelengths);
+        assert_eq!(upper_bound - lower_bound + 1, 32);
+
+        // Pans step from the center to the right
+        let step_ctr = 100;
+        let pan = |sample_ctr: usize| {
+            let left_to_right = if sample_ctr >= step_ctr { 1.0 } else { 0.0 };
+            frequency_pans(1.0, left_to_right, 0.0)
+        };
+
+        let mut average = frequency_pans(0.0, 0.0, 0.0);
+        for sample_ctr in lower_bound..upper_bound {
+            average = rectangular_average(&average, &pan(sample_ctr), pan_fraction_per_frequency);
+        }
+
+        let mut averages = Vec::new();
+        for sample_ctr in 0..(step_ctr * 2) {
+            average = rectangular_average(
+                &average,
+                &pan(sample_ctr + upper_bound),
+                pan_fraction_per_frequency,
+            );
+            averages.push(average.left_to_right);
+            average = rectangular_average(
+                &average,
+                &pan(sample_ctr + lower_bound),
+                -pan_fraction_per_frequency,
+            );
+        }
+
+        let first_moved = averages
+            .iter()
+            .position(|average| *average > 1e-12)
+            .unwrap();
+        let first_converged = averages
+            .iter()
+            .position(|average| (average - 1.0).abs() < 1e-12)
+            .unwrap();
+
+        assert_eq!(first_converged - first_moved + 1, 32);
+        assert!(averages[first_converged..]
+            .iter()
+            .all(|average| (average - 1.0).abs() < 1e-12));
+    }
+
+    #[test]
+    fn exponential_average_follows_time_constants() {
+        let sample_rate = 48000;
+        // 48 and 96 samples
+        let attack_fraction = exponential_fraction(1.0, sample_rate);
+        let release_fraction = exponential_fraction(2.0, sample_rate);
+
+        // A frequency that gets louder moves by the attack time constant
+        let mut average = frequency_pans(0.0, 0.0, 0.0);
+        let mut amplitude_average = 0.0;
+        for _ in 0..48 {
+            (average, amplitude_average) = exponential_average(
+                &average,
+                amplitude_average,
+                &frequency_pans(1.0, 1.0, 1.0),
+                attack_fraction,
+                release_fraction,
+            );
+        }
+
+        let expected = 1.0 - (-1.0f64).exp();
+        assert!((average.left_to_right - expected).abs() < 1e-9);
+        assert!((average.back_to_front - expected).abs() < 1e-9);
+        assert!((amplitude_average - expected).abs() < 1e-9);
+
+        // A frequency that gets quieter moves by the release time constant
+        let mut average = frequency_pans(0.0, 1.0, 1.0);
+        let mut amplitude_average = 1.0;
+        for _ in 0..96 {
+            (average, amplitude_average) = exponential_average(
+                &average,
+                amplitude_average,
+                &frequency_pans(0.0, 0.0, 0.0),
+                attack_fraction,
+                release_fraction,
+            );
+        }
+
+        let expected = (-1.0f64).exp();
+        assert!((average.left_to_right - expected).abs() < 1e-9);
+        assert!((average.back_to_front - expected).abs() < 1e-9);
+        assert!((amplitude_average - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn no_average_passes_pans_through() {
+        let frequency_pan = frequency_pans(0.5, -0.25, 0.75);
+        let averaged = no_average(&frequency_pan);
+
+        assert_eq!(averaged.amplitude, frequency_pan.amplitude);
+        assert_eq!(averaged.left_to_right, frequency_pan.left_to_right);
+        assert_eq!(averaged.back_to_front, frequency_pan.back_to_front);
     }
 }
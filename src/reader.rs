@@ -8,6 +8,10 @@ use rustfft::{num_complex::Complex, Fft};
 use wave_stream::wave_reader::{StreamWavReader, StreamWavReaderIterator};
 
 use crate::{
+    ambience,
+    input_conditioning::InputConditioner,
+    lr_delay::DelayCorrection,
+    noise_floor::NoiseFloor,
     options::{db_to_amplitude, Options},
     structs::{ThreadState, TransformedWindowAndPans},
     vecdeque_ext::VecDequeExt,
@@ -16,6 +20,12 @@ use crate::{
 pub struct Reader {
     open_wav_reader_and_buffer: Mutex<OpenWavReaderAndBuffer>,
     fft_forward: Arc<dyn Fft<f64>>,
+
+    // When set, the minimum amplitude to steer is relative to the noise floor
+    noise_floor: Option<NoiseFloor>,
+
+    // When set, the right channel is shifted to line up with the left
+    delay_correction: Option<DelayCorrection>,
 }
 
 // Allows wrapping information about reading the wav into a single mutex
@@ -25,6 +35,7 @@ struct OpenWavReaderAndBuffer {
     left_buffer: VecDeque<Complex<f64>>,
     right_buffer: VecDeque<Complex<f64>>,
     mono_buffer: VecDeque<Complex<f64>>,
+    input_conditioner: InputConditioner,
 }
 
 impl Reader {
@@ -33,13 +44,20 @@ impl Reader {
         stream_wav_reader: StreamWavReader<f32>,
         window_size: usize,
         fft_forward: Arc<dyn Fft<f64>>,
+        noise_floor: Option<NoiseFloor>,
+        delay_correction: Option<DelayCorrection>,
     ) -> Result<Reader> {
+        let input_conditioner = InputConditioner::new(
+            &options.input_conditioning,
+            stream_wav_reader.info().sample_rate() as usize,
+        );
         let mut open_wav_reader_and_buffer = OpenWavReaderAndBuffer {
             stream_wav_reader_iterator: stream_wav_reader.into_iter(),
             total_samples_read: window_size - 1,
             left_buffer: VecDeque::with_capacity(window_size),
             right_buffer: VecDeque::with_capacity(window_size),
             mono_buffer: VecDeque::with_capacity(window_size),
+            input_conditioner,
         };
 
         for _sample_to_read in 0..(window_size - 1) {
@@ -49,6 +67,8 @@ impl Reader {
         Ok(Reader {
             open_wav_reader_and_buffer: Mutex::new(open_wav_reader_and_buffer),
             fft_forward,
+            noise_floor,
+            delay_correction,
         })
     }
 
@@ -114,38 +134,31 @@ impl Reader {
             mono_transformed = Some(mono_transformed_value);
         }
 
+        if let Some(delay_correction) = &self.delay_correction {
+            delay_correction.correct(last_sample_ctr, &mut right_transformed);
+        }
+
         let mut frequency_pans = Vec::with_capacity(thread_state.upmixer.window_midpoint);
         for freq_ctr in 1..(thread_state.upmixer.window_midpoint + 1) {
             // Phase ranges from -PI to +PI
             let (left_amplitude, mut left_phase) = left_transformed[freq_ctr].to_polar();
             let (right_amplitude, mut right_phase) = right_transformed[freq_ctr].to_polar();
 
-            if left_amplitude
-                < thread_state
+            let minimum_steered_amplitude = match &self.noise_floor {
+                Some(noise_floor) => noise_floor.threshold(last_sample_ctr, freq_ctr),
+                None => thread_state
                     .upmixer
                     .options
                     .minimum_steered_amplitude
-                    .into()
-                && right_amplitude
-                    >= thread_state
-                        .upmixer
-                        .options
-                        .minimum_steered_amplitude
-                        .into()
+                    .into(),
+            };
+
+            if left_amplitude < minimum_steered_amplitude
+                && right_amplitude >= minimum_steered_amplitude
             {
                 left_phase = right_phase;
-            } else if left_amplitude
-                >= thread_state
-                    .upmixer
-                    .options
-                    .minimum_steered_amplitude
-                    .into()
-                && right_amplitude
-                    < thread_state
-                        .upmixer
-                        .options
-                        .minimum_steered_amplitude
-                        .into()
+            } else if left_amplitude >= minimum_steered_amplitude
+                && right_amplitude < minimum_steered_amplitude
             {
                 right_phase = left_phase
             }
@@ -166,6 +179,15 @@ impl Reader {
             frequency_pans.push(steer_result);
         }
 
+        if thread_state.upmixer.options.ambience > 0.0 {
+            ambience::steer_ambience(
+                thread_state.upmixer.options.ambience,
+                &left_transformed,
+                &right_transformed,
+                &mut frequency_pans,
+            );
+        }
+
         let transformed_window_and_pans = TransformedWindowAndPans {
             last_sample_ctr,
             left_transformed: Some(left_transformed),
@@ -183,6 +205,14 @@ impl Reader {
             .expect("Cannot aquire lock because a thread panicked")
             .total_samples_read
     }
+
+    pub fn warn_about_polarity(&self) {
+        self.open_wav_reader_and_buffer
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .input_conditioner
+            .warn_about_polarity();
+    }
 }
 
 impl OpenWavReaderAndBuffer {
@@ -192,13 +222,14 @@ impl OpenWavReaderAndBuffer {
             Some(samples_result) => {
                 let samples = samples_result?;
 
-                let front_left: f64 =
-                    samples.front_left.expect("front_left missing when reading") as f64 * headroom;
-                let front_right = samples
-                    .front_right
-                    .expect("front_right missing when reading")
-                    as f64
-                    * headroom;
+                let (front_left, front_right) = self.input_conditioner.condition(
+                    samples.front_left.expect("front_left missing when reading") as f64,
+                    samples
+                        .front_right
+                        .expect("front_right missing when reading") as f64,
+                );
+                let front_left = front_left * headroom;
+                let front_right = front_right * headroom;
 
                 self.left_buffer.push_back(Complex {
                     re: front_left,
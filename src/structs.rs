@@ -34,3 +34,29 @@ pub struct FrequencyPans {
     // Front to back panning: 0 is front, 1 is back
     pub back_to_front: f64,
 }
+
+impl FrequencyPans {
+    // Converts a direction to pans. The azimuth is in radians: 0 is front, positive is left, negative is right
+    // Directions are placed on the edge of a square, with the quad speakers in the corners
+    pub fn from_azimuth(amplitude: f64, azimuth: f64) -> FrequencyPans {
+        let front = azimuth.cos();
+        let left = azimuth.sin();
+        let largest = front.abs().max(left.abs());
+
+        FrequencyPans {
+            amplitude,
+            left_to_right: -left / largest,
+            back_to_front: (1.0 - (front / largest)) / 2.0,
+        }
+    }
+
+    // Spreads left-right panning: Below 1 narrows, above 1 widens
+    pub fn widen(&mut self, width: f64) {
+        self.left_to_right = (self.left_to_right * width).clamp(-1.0, 1.0);
+    }
+
+    // The direction of the pans, in radians: 0 is front, positive is left, negative is right
+    pub fn azimuth(&self) -> f64 {
+        (-self.left_to_right).atan2(1.0 - (2.0 * self.back_to_front))
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+
+use crate::{options::Options, structs::TransformedWindowAndPans};
+
+// Spectral flux compares each window with the window this many milliseconds earlier
+const FLUX_LAG: f64 = 10.0;
+
+// After an onset, another onset isn't detected for this many milliseconds
+const REFRACTORY_TIME: f64 = 50.0;
+
+// Detects onsets (drums, plucks) with spectral flux: How much louder the frequencies in the newest window are
+// than in a slightly older window
+//
+// The spectrum is |L| + |R| of each frequency, from the transforms. (The amplitude in the pans depends on the
+// matrix, and on where the sound is steered.)
+pub struct TransientDetector {
+    // In samples
+    lag: usize,
+    refractory_samples: usize,
+    // The rise in the spectrum, as a ratio, that is an onset
+    threshold: f64,
+    // The flux is relative to the spectrum, but not below this
+    minimum_spectrum: f64,
+    // The spectrum of the newest window, and of each window before it, back to the lag. (Older windows in the
+    // queue may already be written, so their transforms are gone)
+    spectra: VecDeque<Vec<f64>>,
+    samples_since_onset: usize,
+    above_threshold: bool,
+}
+
+impl TransientDetector {
+    pub fn new(
+        options: &Options,
+        window_size: usize,
+        sample_rate: usize,
+    ) -> Option<TransientDetector> {
+        let threshold = options.transients?;
+
+        let samples_per_millisecond = sample_rate as f64 / 1000.0;
+        let lag = ((FLUX_LAG * samples_per_millisecond) as usize).max(1);
+
+        Some(TransientDetector {
+            lag,
+            refractory_samples: (REFRACTORY_TIME * samples_per_millisecond) as usize,
+            threshold: 10f64.powf(threshold / 20.0),
+            // Each frequency at the minimum steered amplitude, in left and right
+            minimum_spectrum: options.minimum_steered_amplitude as f64 * window_size as f64,
+            spectra: VecDeque::with_capacity(lag + 1),
+            samples_since_onset: usize::MAX,
+            above_threshold: false,
+        })
+    }
+
+    // Called after each window is added to the queue. Returns true if the newest window starts an onset
+    pub fn detect(&mut self, newest: &TransformedWindowAndPans) -> bool {
+        self.samples_since_onset = self.samples_since_onset.saturating_add(1);
+
+        let spectrum = match (&newest.left_transformed, &newest.right_transformed) {
+            (Some(left_transformed), Some(right_transformed)) => {
+                let window_midpoint = left_transformed.len() / 2;
+                (1..(window_midpoint + 1))
+                    .map(|freq_ctr| {
+                        left_transformed[freq_ctr].norm() + right_transformed[freq_ctr].norm()
+                    })
+                    .collect()
+            }
+            // Windows that are copied at the end of the file have no transforms, and repeat the window before them
+            _ => match self.spectra.back() {
+                Some(spectrum) => spectrum.clone(),
+                None => return false,
+            },
+        };
+
+        // Like the queue, the first window is copied to fill the lag
+        while self.spectra.len() < self.lag {
+            self.spectra.push_back(spectrum.clone());
+        }
+
+        self.spectra.push_back(spectrum);
+        if self.spectra.len() > self.lag + 1 {
+            self.spectra.pop_front();
+        }
+
+        let newest = &self.spectra[self.lag];
+        let older = &self.spectra[0];
+
+        // Only rises count, so that a sound that ends isn't an onset
+        let mut older_spectrum = 0.0;
+        let mut rise = 0.0;
+        for (newest_amplitude, older_amplitude) in newest.iter().zip(older.iter()) {
+            older_spectrum += older_amplitude;
+            rise += (newest_amplitude - older_amplitude).max(0.0);
+        }
+
+        let flux = (older_spectrum + rise) / older_spectrum.max(self.minimum_spectrum);
+
+        // An onset is when the flux crosses the threshold
+        let above_threshold = flux >= self.threshold;
+        let onset = above_threshold
+            && !self.above_threshold
+            && self.samples_since_onset >= self.refractory_samples;
+
+        self.above_threshold = above_threshold;
+        if onset {
+            self.samples_since_onset = 0;
+        }
+
+        onset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustfft::{num_complex::Complex, FftPlanner};
+
+    use super::*;
+
+    const WINDOW_SIZE: usize = 16;
+
+    // Transforms each window of the samples, and returns the last sample of each window that starts an onset
+    fn detect_onsets(transient_detector: &mut TransientDetector, samples: &[f64]) -> Vec<usize> {
+        let mut planner = FftPlanner::new();
+        let fft_forward = planner.plan_fft_forward(WINDOW_SIZE);
+
+        let mut onsets = Vec::new();
+        for last_sample_ctr in (WINDOW_SIZE - 1)..samples.len() {
+            let mut transformed: Vec<Complex<f64>> = samples
+                [(last_sample_ctr + 1 - WINDOW_SIZE)..(last_sample_ctr + 1)]
+                .iter()
+                .map(|sample| Complex {
+                    re: *sample,
+                    im: 0.0,
+                })
+                .collect();
+            fft_forward.process(&mut transformed);
+
+            let transformed_window_and_pans = TransformedWindowAndPans {
+                last_sample_ctr,
+                left_transformed: Some(transformed.clone()),
+                right_transformed: Some(transformed),
+                mono_transformed: None,
+                frequency_pans: Vec::new(),
+            };
+
+            if transient_detector.detect(&transformed_window_and_pans) {
+                onsets.push(last_sample_ctr);
+            }
+        }
+
+        onsets
+    }
+
+    fn transient_detector() -> TransientDetector {
+        TransientDetector {
+            lag: 4,
+            refractory_samples: 50,
+            // 6db
+            threshold: 2.0,
+            minimum_spectrum: 0.001,
+            spectra: VecDeque::new(),
+            samples_since_onset: usize::MAX,
+            above_threshold: false,
+        }
+    }
+
+    #[test]
+    fn impulse_after_silence_is_one_onset() {
+        let mut samples = vec![0.0; 200];
+        samples[100] = 1.0;
+
+        let onsets = detect_onsets(&mut transient_detector(), &samples);
+        assert_eq!(onsets, vec![100]);
+    }
+
+    #[test]
+    fn refractory_period_suppresses_onsets() {
+        let mut samples = vec![0.0; 300];
+        samples[100] = 1.0;
+        // Within the refractory period
+        samples[130] = 1.0;
+        // After the refractory period
+        samples[200] = 1.0;
+
+        let onsets = detect_onsets(&mut transient_detector(), &samples);
+        assert_eq!(onsets, vec![100, 200]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::{stdout, Error, ErrorKind, Read, Result, Seek, Write};
+use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -10,16 +11,23 @@ use wave_stream::open_wav::OpenWav;
 use wave_stream::wave_reader::{OpenWavReader, StreamOpenWavReader};
 use wave_stream::wave_writer::OpenWavWriter;
 
+use crate::binaural::BinauralRenderer;
+use crate::cd4;
+use crate::decorrelation::rear_delay_samples;
 use crate::logger::Logger;
+use crate::lr_delay::DelayCorrection;
+use crate::noise_floor::NoiseFloor;
 use crate::options::Options;
 use crate::panner_and_writer::PannerAndWriter;
 use crate::panning_averager::PanningAverager;
 use crate::reader::Reader;
 use crate::structs::ThreadState;
-use crate::window_sizes::get_ideal_window_size;
+use crate::window_sizes::{
+    get_ideal_window_size, get_ideal_window_size_below, is_ideal_window_size,
+};
 
 pub struct Upmixer {
-    pub options: Options,
+    pub options: Rc<Options>,
     pub window_size: usize,
     pub window_midpoint: usize,
     pub total_samples_to_write: usize,
@@ -49,28 +57,75 @@ pub fn upmix<TReader: 'static + Read + Seek>(
     source_wav_reader: OpenWavReader<TReader>,
     target_open_wav_writers: Vec<OpenWavWriter>,
 ) -> Result<()> {
+    if options.cd4 {
+        return cd4::demodulate(source_wav_reader, target_open_wav_writers);
+    }
+
+    let low_frequency = options.low_frequency;
+    let window_size = window_size(&options, low_frequency, &source_wav_reader)?;
+    let noise_floor = NoiseFloor::estimate(&options, window_size)?;
+    let delay_correction = DelayCorrection::new(&options)?;
+
+    upmix_band(
+        Rc::new(options),
+        window_size,
+        noise_floor,
+        delay_correction,
+        source_wav_reader,
+        target_open_wav_writers,
+    )
+}
+
+// The window size to steer the frequencies from low_frequency and up
+pub fn window_size<TReader: Read + Seek>(
+    options: &Options,
+    low_frequency: f32,
+    source_wav_reader: &OpenWavReader<TReader>,
+) -> Result<usize> {
     let max_low_frequency = (source_wav_reader.sample_rate() / 8) as f32;
-    if options.low_frequency >= max_low_frequency {
+    if low_frequency >= max_low_frequency {
         let error = format!(
             "Lowest steered frequency {}hz is too high. Maximum lowest frequency for {} samples / second is {}",
-            options.low_frequency,
+            low_frequency,
             source_wav_reader.sample_rate(),
             max_low_frequency);
         return Err(Error::new(ErrorKind::InvalidInput, error));
     }
 
     let min_window_size =
-        ((source_wav_reader.sample_rate() as f32) / options.low_frequency).ceil() as usize;
+        ((source_wav_reader.sample_rate() as f32) / low_frequency).ceil() as usize;
     let mut window_size = match options.requested_fft_size {
-        Some(size) => get_ideal_window_size(size)?,
-        None => get_ideal_window_size(min_window_size)?,
+        Some(size) => {
+            // The requested size is used as-is, even if it's slow
+            if !is_ideal_window_size(size) {
+                let mut alternatives = Vec::with_capacity(2);
+                if let Some(smaller) = get_ideal_window_size_below(size) {
+                    alternatives.push(smaller.to_string());
+                }
+                alternatives.push(get_ideal_window_size(size).to_string());
+
+                println!(
+                    "Warning: -fft_size {} is not an optimized window size, Fourier transforms will be slower. The nearest optimized window sizes are {}",
+                    size,
+                    alternatives.join(" and "));
+            }
+
+            size
+        }
+        None => get_ideal_window_size(min_window_size),
     };
 
     println!(
-        "Lowest frequency: {}hz. With input at {} samples / second, using an optimized window size of {} samples",
-        options.low_frequency,
+        "Lowest frequency: {}hz. With input at {} samples / second, using {} of {} samples",
+        low_frequency,
         source_wav_reader.sample_rate(),
-        window_size);
+        if is_ideal_window_size(window_size) {
+            "an optimized window size"
+        } else {
+            "a window size"
+        },
+        window_size
+    );
 
     if source_wav_reader.len_samples() < window_size {
         window_size = min_window_size;
@@ -85,6 +140,30 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         return Err(Error::new(ErrorKind::InvalidInput, error));
     }
 
+    if rear_delay_samples(options, source_wav_reader.sample_rate() as usize)
+        >= (window_size / 2) as f64
+    {
+        let error = format!(
+            "The rear delay of {}ms is longer than half of the window, {} samples. Consider lowering the lowest frequency via -low",
+            options.rear_delay,
+            window_size / 2
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    Ok(window_size)
+}
+
+// Upmixes with a window size from window_size(). When upmixing in multiple bands, the options are shared by each
+// band, and the noise floor and delay are measured once
+pub fn upmix_band<TReader: 'static + Read + Seek>(
+    options: Rc<Options>,
+    window_size: usize,
+    noise_floor: Option<NoiseFloor>,
+    delay_correction: Option<DelayCorrection>,
+    source_wav_reader: OpenWavReader<TReader>,
+    target_open_wav_writers: Vec<OpenWavWriter>,
+) -> Result<()> {
     let source_wav_reader = source_wav_reader.get_stream_f32_reader()?;
     let mut target_random_access_wav_writers = Vec::with_capacity(target_open_wav_writers.len());
     for target_open_wav_writer in target_open_wav_writers {
@@ -108,7 +187,24 @@ pub fn upmix<TReader: 'static + Read + Seek>(
     let fft_forward = planner.plan_fft_forward(window_size);
     let fft_inverse = planner.plan_fft_inverse(window_size);
 
-    let reader = Reader::open(&options, source_wav_reader, window_size, fft_forward)?;
+    let binaural_renderer = match &options.binaural {
+        Some(hrtf_source) => Some(BinauralRenderer::new(
+            &options,
+            hrtf_source,
+            window_size,
+            sample_rate,
+        )?),
+        None => None,
+    };
+
+    let reader = Reader::open(
+        &options,
+        source_wav_reader,
+        window_size,
+        fft_forward,
+        noise_floor,
+        delay_correction,
+    )?;
     let panner_and_writer = PannerAndWriter::new(
         &options,
         window_size,
@@ -116,8 +212,11 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         target_random_access_wav_writers,
         fft_inverse,
         max_samples_in_file,
+        binaural_renderer,
     );
 
+    let panning_averager = PanningAverager::new(&options, window_size, sample_rate);
+
     let mut stdout = stdout();
     stdout.write(format!("Starting...").as_bytes())?;
     stdout.flush()?;
@@ -130,7 +229,7 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         scale,
         logger: Logger::new(Duration::from_secs_f64(1.0 / 10.0), total_samples_to_write),
         reader,
-        panning_averager: PanningAverager::new(window_size),
+        panning_averager,
         panner_and_writer,
         num_running_threads: AtomicUsize::new(1),
     });
@@ -140,6 +239,8 @@ pub fn upmix<TReader: 'static + Read + Seek>(
 
     upmixer.logger.finish_logging()?;
 
+    upmixer.reader.warn_about_polarity();
+
     // In general, this should be a no-op
     // This is to help with debugging
     upmixer.options.matrix.print_debugging_information();
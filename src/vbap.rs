@@ -0,0 +1,226 @@
+use std::f64::consts::{FRAC_PI_4, PI};
+
+use crate::options::{CenterMode, Options, PanLaw};
+
+// Matrixes steer to the corners of a square: The front speakers are at 45 degrees, and the rear speakers at 135 degrees
+const NOMINAL_FRONT: f64 = FRAC_PI_4;
+const NOMINAL_REAR: f64 = 3.0 * FRAC_PI_4;
+
+// Renders each frequency's direction to the speakers with vector-base amplitude panning, (VBAP,) see Ville Pulkki,
+// "Virtual Sound Source Positioning Using Vector Base Amplitude Panning"
+pub struct Vbap {
+    // The channel of each speaker, (front left, front right, center, LFE, rear left, rear right,) and its azimuth in
+    // radians, sorted by azimuth
+    speakers: Vec<(usize, f64)>,
+
+    // Maps the direction that the matrix steers to onto the speakers: Each nominal azimuth, and its azimuth in the room
+    warp: Vec<(f64, f64)>,
+
+    // The gains of the two speakers are normalized so that the sum of each gain to this power is 1
+    pan_law_exponent: f64,
+}
+
+impl Vbap {
+    pub fn new(options: &Options) -> Vbap {
+        // The projected center is extracted after panning, so the center speaker isn't panned to
+        let center =
+            options.channels.front_center && !matches!(options.center_mode, CenterMode::Projection);
+
+        Vbap::from_speakers(options.speaker_azimuths, center, &options.pan_law)
+    }
+
+    // The speaker azimuths are in degrees, in the same order as the channels
+    fn from_speakers(speaker_azimuths: [f64; 6], center: bool, pan_law: &PanLaw) -> Vbap {
+        let azimuths = speaker_azimuths.map(f64::to_radians);
+
+        let mut speakers = vec![
+            (0, azimuths[0]),
+            (1, azimuths[1]),
+            (4, azimuths[4]),
+            (5, azimuths[5]),
+        ];
+        if center {
+            speakers.push((2, azimuths[2]));
+        }
+        speakers.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        let center_azimuth = if center { azimuths[2] } else { 0.0 };
+        let warp = vec![
+            (-PI, -PI),
+            (-NOMINAL_REAR, azimuths[5]),
+            (-NOMINAL_FRONT, azimuths[1]),
+            (0.0, center_azimuth),
+            (NOMINAL_FRONT, azimuths[0]),
+            (NOMINAL_REAR, azimuths[4]),
+            (PI, PI),
+        ];
+
+        let pan_law_exponent = match pan_law {
+            PanLaw::ConstantPower => 2.0,
+            // A centered sound is lowered 4.5db: 2 * (10 ^ (-4.5 / 20)) ^ exponent = 1
+            PanLaw::Compromise => 2f64.ln() / (4.5 * 10f64.ln() / 20.0),
+            PanLaw::Linear => 1.0,
+        };
+
+        Vbap {
+            speakers,
+            warp,
+            pan_law_exponent,
+        }
+    }
+
+    // The gain of each channel for a direction. The azimuth is in radians: 0 is front, positive is left, negative is
+    // right
+    pub fn gains(&self, azimuth: f64) -> [f64; 6] {
+        let azimuth = self.warp(azimuth);
+
+        let mut gains = [0.0; 6];
+        for speaker_ctr in 0..self.speakers.len() {
+            let (first_channel, first_azimuth) = self.speakers[speaker_ctr];
+            let (second_channel, mut second_azimuth) =
+                self.speakers[(speaker_ctr + 1) % self.speakers.len()];
+
+            // The last pair wraps around the rear
+            let mut azimuth = azimuth;
+            if second_azimuth <= first_azimuth {
+                second_azimuth += 2.0 * PI;
+                if azimuth < first_azimuth {
+                    azimuth += 2.0 * PI;
+                }
+            }
+
+            if azimuth < first_azimuth || azimuth > second_azimuth {
+                continue;
+            }
+
+            // Solves for the gains whose speaker vectors add up to the direction
+            let span = (second_azimuth - first_azimuth).sin();
+            let first_gain = (second_azimuth - azimuth).sin() / span;
+            let second_gain = (azimuth - first_azimuth).sin() / span;
+
+            let norm = (first_gain.powf(self.pan_law_exponent)
+                + second_gain.powf(self.pan_law_exponent))
+            .powf(1.0 / self.pan_law_exponent);
+
+            gains[first_channel] = first_gain / norm;
+            gains[second_channel] = second_gain / norm;
+            return gains;
+        }
+
+        gains
+    }
+
+    // Moves a direction from the square that the matrix steers to, to where the speakers are
+    fn warp(&self, azimuth: f64) -> f64 {
+        for anchors in self.warp.windows(2) {
+            let (nominal_start, start) = anchors[0];
+            let (nominal_end, end) = anchors[1];
+            if azimuth <= nominal_end {
+                let fraction = (azimuth - nominal_start) / (nominal_end - nominal_start);
+                return start + (fraction.clamp(0.0, 1.0) * (end - start));
+            }
+        }
+
+        azimuth
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Front left, front right, center, LFE, rear left, rear right, in degrees
+    const ITU_SPEAKERS: [f64; 6] = [30.0, -30.0, 0.0, 0.0, 110.0, -110.0];
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn assert_gains(gains: [f64; 6], expected: [f64; 6]) {
+        for channel_ctr in 0..6 {
+            assert!(
+                (gains[channel_ctr] - expected[channel_ctr]).abs() < TOLERANCE,
+                "gains are {:?}, expected {:?}",
+                gains,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn speaker_directions_only_play_in_that_speaker() {
+        let vbap = Vbap::from_speakers(ITU_SPEAKERS, true, &PanLaw::ConstantPower);
+
+        // The corners of the square that the matrix steers to are warped to the speakers
+        assert_gains(vbap.gains(0.0), [0.0, 0.0, 1.0, 0.0, 0.0, 0.0]);
+        assert_gains(vbap.gains(NOMINAL_FRONT), [1.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_gains(vbap.gains(-NOMINAL_FRONT), [0.0, 1.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_gains(vbap.gains(NOMINAL_REAR), [0.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        assert_gains(vbap.gains(-NOMINAL_REAR), [0.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn pan_laws_normalize_each_pair() {
+        for (pan_law, centered_db) in [
+            (PanLaw::ConstantPower, -3.0),
+            (PanLaw::Compromise, -4.5),
+            (PanLaw::Linear, -6.0),
+        ] {
+            let vbap = Vbap::from_speakers(ITU_SPEAKERS, true, &pan_law);
+
+            for azimuth_ctr in -16..16 {
+                let gains = vbap.gains(azimuth_ctr as f64 * PI / 16.0);
+                let sum: f64 = gains
+                    .iter()
+                    .map(|gain| gain.powf(vbap.pan_law_exponent))
+                    .sum();
+                assert!(
+                    (sum - 1.0).abs() < TOLERANCE,
+                    "gains are {:?}, the sum of the gains to the power of {} is {}",
+                    gains,
+                    vbap.pan_law_exponent,
+                    sum
+                );
+            }
+
+            // Halfway between the center and front left
+            let gains = vbap.gains(NOMINAL_FRONT / 2.0);
+            let gain_db = 20.0 * gains[0].log10();
+            assert!(
+                (gains[0] - gains[2]).abs() < TOLERANCE && (gain_db - centered_db).abs() < 0.05,
+                "gains are {:?}, expected {}db in the center and front left",
+                gains,
+                centered_db
+            );
+        }
+    }
+
+    #[test]
+    fn rear_wraps_between_rear_speakers() {
+        let vbap = Vbap::from_speakers(ITU_SPEAKERS, true, &PanLaw::ConstantPower);
+
+        let gain = 0.5f64.sqrt();
+        assert_gains(vbap.gains(PI), [0.0, 0.0, 0.0, 0.0, gain, gain]);
+        assert_gains(vbap.gains(-PI), [0.0, 0.0, 0.0, 0.0, gain, gain]);
+
+        // Just to the right of the rear
+        let gains = vbap.gains(-PI + 0.1);
+        assert!(
+            gains[5] > gains[4] && gains[4] > 0.0,
+            "gains are {:?}, expected more in the rear right than the rear left",
+            gains
+        );
+        assert_eq!([gains[0], gains[1], gains[2], gains[3]], [0.0; 4]);
+    }
+
+    #[test]
+    fn projected_center_is_not_panned_to() {
+        let vbap = Vbap::from_speakers(ITU_SPEAKERS, false, &PanLaw::ConstantPower);
+
+        for azimuth_ctr in -16..16 {
+            assert_eq!(vbap.gains(azimuth_ctr as f64 * PI / 16.0)[2], 0.0);
+        }
+
+        // The front is between front left and front right
+        let gain = 0.5f64.sqrt();
+        assert_gains(vbap.gains(0.0), [gain, gain, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
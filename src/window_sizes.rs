@@ -1,64 +1,94 @@
-use std::io::{Error, ErrorKind, Result};
-
-// All of the optimial window sizes
+// Optimal window sizes are 2^n * 3^m, (with n and m at least 1,) which have the fastest Fourier transforms
 // See https://docs.rs/rustfft/latest/rustfft/#avx-performance-tips
-static WINDOW_SIZES: [usize; 61] = [
-    6, 12, 18, 24, 36, 48, 54, 72, 96, 108, 144, 162, 192, 216, 288, 324, 384, 432, 486, 576, 648,
-    768, 864, 972, 1152, 1296, 1458, 1536, 1728, 1944, 2304, 2592, 2916, 3072, 3456, 3888, 4374,
-    4608, 5184, 5832, 6144, 6912, 7776, 8748, 9216, 10368, 11664, 12288, 13122, 13824, 15552,
-    17496, 18432, 20736, 23328, 24576, 26244, 27648, 31104, 34992, 36864,
-];
-
-/*
-
-C# program to generate window sizes
-
-using System;
-using System.Collections.Generic;
-
-namespace FourierSizes
-{
-    class Program
-    {
-        const int HIGHEST_SAMPLING_RATE = 192000;
-        const int LOWEST_FREQUENCY = 10;
-        const int SMALLEST_WINDOW = HIGHEST_SAMPLING_RATE / LOWEST_FREQUENCY;
-        const int LARGEST_WINDOW = SMALLEST_WINDOW * 2;
-
-        static void Main(string[] args)
-        {
-            var windowSizes = new List<double>();
-            for (var n = 1; n <= SMALLEST_WINDOW; n++)
-                for (var m = 1; m <= SMALLEST_WINDOW; m++)
-                {
-                    var windowSize = Math.Pow(2, n) * Math.Pow(3, m);
-                    if (windowSize <= LARGEST_WINDOW)
-                    {
-                        windowSizes.Add(windowSize);
-                        Console.WriteLine($"2^{n} * 3^{m}: {windowSize}");
-                    }
-                }
-
-            Console.WriteLine();
-
-            windowSizes.Sort();
-            var windowSizesCommaSeparated = string.Join(",", windowSizes);
-
-            Console.WriteLine($"static WINDOW_SIZES: [u32; {windowSizes.Count}] = [{windowSizesCommaSeparated}];");
+//
+// Sizes are calculated instead of listed, so there is no largest size. (Very low frequencies at high sample
+// rates, like 10hz at 384khz, need very large windows.)
+
+// The smallest optimal window size, 2 * 3
+const SMALLEST_WINDOW_SIZE: usize = 6;
+
+// The smallest optimal window size that is at least min_window_size
+pub fn get_ideal_window_size(min_window_size: usize) -> usize {
+    let mut ideal_window_size = usize::MAX;
+
+    let mut power_of_three = 3usize;
+    while power_of_three < ideal_window_size {
+        // The smallest power of two, times the power of three, that is at least min_window_size
+        let mut window_size = power_of_three * 2;
+        while window_size < min_window_size {
+            window_size = match window_size.checked_mul(2) {
+                Some(window_size) => window_size,
+                None => break,
+            };
         }
+
+        if window_size >= min_window_size {
+            ideal_window_size = ideal_window_size.min(window_size);
+        }
+
+        power_of_three = match power_of_three.checked_mul(3) {
+            Some(power_of_three) => power_of_three,
+            None => break,
+        };
     }
+
+    ideal_window_size
 }
 
+// The largest optimal window size that is at most max_window_size
+pub fn get_ideal_window_size_below(max_window_size: usize) -> Option<usize> {
+    if max_window_size < SMALLEST_WINDOW_SIZE {
+        return None;
+    }
 
-*/
+    let mut ideal_window_size = SMALLEST_WINDOW_SIZE;
 
-pub fn get_ideal_window_size(min_window_size: usize) -> Result<usize> {
-    for window_size in WINDOW_SIZES {
-        if window_size >= min_window_size {
-            return Ok(window_size);
+    let mut power_of_three = 3usize;
+    while power_of_three * 2 <= max_window_size {
+        // The largest power of two, times the power of three, that is at most max_window_size
+        let mut window_size = power_of_three * 2;
+        while window_size <= max_window_size / 2 {
+            window_size *= 2;
         }
+
+        ideal_window_size = ideal_window_size.max(window_size);
+        power_of_three *= 3;
     }
 
-    let error = format!("Can not find an ideal window size for {}", min_window_size);
-    return Err(Error::new(ErrorKind::NotFound, error));
+    Some(ideal_window_size)
+}
+
+pub fn is_ideal_window_size(window_size: usize) -> bool {
+    window_size >= SMALLEST_WINDOW_SIZE && get_ideal_window_size(window_size) == window_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ideal_window_sizes() {
+        // 2^12 * 3^2
+        assert_eq!(get_ideal_window_size(36864), 36864);
+        assert_eq!(get_ideal_window_size_below(36864), Some(36864));
+        assert!(is_ideal_window_size(36864));
+
+        // 2 * 3^9
+        assert_eq!(get_ideal_window_size(36865), 39366);
+        assert_eq!(get_ideal_window_size_below(36865), Some(36864));
+        assert!(!is_ideal_window_size(36865));
+
+        // 10hz at 384khz
+        assert_eq!(get_ideal_window_size(384000 / 10), 39366);
+        assert_eq!(get_ideal_window_size_below(384000 / 10), Some(36864));
+        assert!(!is_ideal_window_size(384000 / 10));
+
+        assert_eq!(get_ideal_window_size(6), 6);
+        assert_eq!(get_ideal_window_size_below(6), Some(6));
+        assert!(is_ideal_window_size(6));
+
+        assert_eq!(get_ideal_window_size(5), 6);
+        assert_eq!(get_ideal_window_size_below(5), None);
+        assert!(!is_ideal_window_size(5));
+    }
 }